@@ -11,7 +11,7 @@ use futures::{Future, Stream};
 use futures::future::{self};
 use tokio_core::reactor::{Core};
 
-use tokio_solicit::client::H2Client;
+use tokio_solicit::client::{H2Client, H2Error};
 
 // Shows the usage of `H2Client` when establishing an HTTP/2 connection over cleartext TCP.
 // Also demonstrates how to stream the body of the response (i.e. get response body chunks as soon
@@ -24,7 +24,8 @@ fn cleartext_example() {
 
     let future_client = H2Client::cleartext_connect("localhost", &addr, &handle);
 
-    let future_response = future_client.and_then(|mut client| {
+    // Connecting fails with an `io::Error`, while requests fail with an `H2Error`.
+    let future_response = future_client.map_err(H2Error::from).and_then(|mut client| {
         println!("Connection established.");
 
         // For the first request, we simply want the full body, without streaming individual body
@@ -39,7 +40,7 @@ fn cleartext_example() {
                 println!("receiving a new chunk of size {}", chunk.body.len());
 
                 vec.extend(chunk.body.into_iter());
-                future::ok::<_, io::Error>(vec)
+                future::ok::<_, H2Error>(vec)
             })
         });
 
@@ -77,7 +78,8 @@ fn alpn_example() {
             .next()
             .expect("no matching ip addresses");
 
-    let future_response = H2Client::connect("google.com", &addr, &handle).and_then(|mut client| {
+    let future_client = H2Client::connect("google.com", &addr, &handle);
+    let future_response = future_client.map_err(H2Error::from).and_then(|mut client| {
         // Ask for the homepage...
         client.get(b"/").into_full_body_response()
     });
@@ -120,13 +122,13 @@ fn do_streaming_request() {
     let addr = "127.0.0.1:8080".parse().expect("valid IP address");
     let future_client = H2Client::cleartext_connect("localhost", &addr, &handle);
 
-    let future_response = future_client.and_then(|mut client| {
+    let future_response = future_client.map_err(H2Error::from).and_then(|mut client| {
         let (post, tx) = client.streaming_request(b"POST", b"/post", iter::empty());
         tx
             .send(Ok(HttpRequestBody::new(b"HELLO ".to_vec())))
             .and_then(|tx| tx.send(Ok(HttpRequestBody::new(b" WORLD".to_vec()))))
             .and_then(|tx| tx.send(Ok(HttpRequestBody::new(b"!".to_vec()))))
-            .map_err(|_err| io::Error::from(io::ErrorKind::BrokenPipe))
+            .map_err(|_err| H2Error::Io(io::Error::from(io::ErrorKind::BrokenPipe)))
             .and_then(|_tx| post.into_full_body_response())
     });
 
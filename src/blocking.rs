@@ -0,0 +1,86 @@
+//! A synchronous convenience wrapper around the futures-based `H2Client`.
+//!
+//! The `blocking::Client` owns its own `tokio_core` event loop (`Core`) and simply runs it until
+//! each request completes. This makes it a good fit for scripts and tests that don't need to run
+//! multiple requests concurrently; anything more involved should use `H2Client` directly.
+//!
+//! Establishing the connection fails with an `io::Error`, while requests fail with an `H2Error`,
+//! just like with the async client.
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio_core::reactor::Core;
+
+use solicit::http::StaticHeader;
+
+use client::{H2Client, H2Stats, H2Error, HttpResponse};
+
+/// An HTTP/2 client that blocks the current thread for each request.
+pub struct Client {
+    /// The event loop that drives the connection. It only runs while a call is in progress.
+    core: Core,
+    /// The async client that all the requests are delegated to.
+    client: H2Client,
+}
+
+impl Client {
+    /// Connects to the given socket over TLS (negotiating HTTP/2 via ALPN), blocking until the
+    /// connection is established.
+    pub fn connect(authority: &str, socket_addr: &SocketAddr) -> io::Result<Client> {
+        let mut core = Core::new()?;
+        let handle = core.handle();
+        let client = core.run(H2Client::connect(authority, socket_addr, &handle))?;
+
+        Ok(Client::new(core, client))
+    }
+
+    /// Connects to the given socket over cleartext TCP, blocking until the connection is
+    /// established.
+    pub fn cleartext_connect(authority: &str, socket_addr: &SocketAddr) -> io::Result<Client> {
+        let mut core = Core::new()?;
+        let handle = core.handle();
+        let client = core.run(H2Client::cleartext_connect(authority, socket_addr, &handle))?;
+
+        Ok(Client::new(core, client))
+    }
+
+    fn new(core: Core, client: H2Client) -> Client {
+        Client {
+            core: core,
+            client: client,
+        }
+    }
+
+    /// Issues a GET request and blocks until the full response has been received.
+    pub fn get(&mut self, path: &[u8]) -> Result<HttpResponse, H2Error> {
+        let response = self.client.get(path).into_full_body_response();
+        self.core.run(response)
+    }
+
+    /// Issues a POST request carrying the given body and blocks until the full response has been
+    /// received.
+    pub fn post(&mut self, path: &[u8], body: Vec<u8>) -> Result<HttpResponse, H2Error> {
+        let response = self.client.post(path, body).into_full_body_response();
+        self.core.run(response)
+    }
+
+    /// Performs a request with the given method, headers and body, blocking until the full
+    /// response has been received.
+    pub fn request<I>(&mut self,
+                      method: &[u8],
+                      path: &[u8],
+                      user_headers: I,
+                      body: Option<Vec<u8>>)
+                      -> Result<HttpResponse, H2Error>
+                      where I: IntoIterator<Item=StaticHeader> {
+        let response = self.client.request(method, path, user_headers, body)
+            .into_full_body_response();
+        self.core.run(response)
+    }
+
+    /// Returns a snapshot of the statistics of the underlying connection.
+    pub fn stats(&self) -> H2Stats {
+        self.client.stats()
+    }
+}
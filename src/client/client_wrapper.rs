@@ -6,31 +6,56 @@ use super::{
     HttpRequestBody,
     HttpResponseHeaders,
     HttpResponseBody,
-    HttpResponse
+    HttpResponse,
+    ResponseEvent,
+    CappedBodyError,
+    H2Error,
+    Http2Error,
+    InvalidHeadersError,
+    ConnectionOptions,
+    ClosedReason,
 };
-use client::connectors::{TlsConnector, CleartextConnector, H2ConnectorParams};
-use client::tokio_layer::{H2ClientTokioProto};
+use client::connectors::{TlsConnector, CleartextConnector, UpgradeConnector, H2ConnectorParams};
+use client::tokio_layer::{H2ClientTokioProto, initial_settings_payload};
+use client::tls::{make_connector_from, make_connector_with_alpn, TlsInfo};
+use client::stats::{H2Stats, StreamWindows};
+use client::headers::{validate_header_name, authority_matches};
+use client::grpc::{GrpcStatus, GrpcMessages, GrpcDecoder, GRPC_STATUS_UNKNOWN};
+use client::codec::{BodyDecoder, FramedBody};
+use client::sse::SseDecoder;
+#[cfg(feature = "compression")]
+use client::compression::{self, Encoding, CompressedBody};
+use client::shared::{SharedRequest, SharedConnection, InflightLimit, InflightPermit};
 
-use std::io::{self};
+use std::io::{self, Read, Write};
+use std::thread;
 use std::net::SocketAddr;
+use std::time::Duration;
 use std::iter::{self, IntoIterator};
+use std::cmp;
+use std::mem;
+use std::vec;
+use std::sync::Arc;
 
-use futures::{Async, Future, Poll};
-use futures::future::{self, BoxFuture};
+use futures::{Async, AsyncSink, Future, Poll, StartSend};
+use futures::future;
 use futures::stream::{Stream};
-use futures::sync::mpsc;
+use futures::sink::Sink;
+use futures::sync::{mpsc, oneshot};
 
-use tokio_core::reactor::{Handle};
+use openssl::ssl::{SslConnectorBuilder, SslMethod};
+
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_core::io::{Io};
-use tokio_core::net::TcpStream;
+use tokio_core::net::{TcpStream, TcpStreamNew};
 use tokio_service::{Service};
-use tokio_proto::{Connect, TcpClient};
+use tokio_proto::BindClient;
 use tokio_proto::streaming::{Message, Body};
 use tokio_proto::streaming::multiplex::{StreamingMultiplex};
 use tokio_proto::util::client_proxy::ClientProxy;
 
 
-use solicit::http::{Header, StaticHeader};
+use solicit::http::{Header, StaticHeader, ErrorCode, StreamId};
 
 /// A type alias for the request body stream.
 type RequestBodyStream = Body<HttpRequestBody, io::Error>;
@@ -46,63 +71,739 @@ type TokioClient =
 /// A `futures::Stream` impl that represents the body of the response. The `Future` returned
 /// by various `H2Client` methods returns an instance of this type, along with the response
 /// headers.
+///
+/// The stream yields one `HttpResponseBody` for each DATA frame, in the order they were received.
 pub struct ResponseBodyStream {
     /// The type simply hides away the Tokio `Body`, which will be returned by Tokio client
     /// Service.
     inner: Body<HttpResponseBody, io::Error>,
+    /// The slot that the request takes up, if the client limits the number of outstanding
+    /// requests. It's freed up once the body is done.
+    permit: Option<InflightPermit>,
+    /// The deadline of the request, if it has one, which the body has to arrive by too.
+    deadline: Option<Deadline>,
+    /// Cancels the request if the body is dropped without ever being polled, if armed (see
+    /// `H2RequestHandle::headers`).
+    abort: Option<AbortOnDrop>,
+    /// The number of body bytes yielded so far.
+    received: u64,
+    /// The length of the body, if it's known upfront.
+    content_length: Option<u64>,
 }
 
 impl ResponseBodyStream {
-    fn new(inner: Body<HttpResponseBody, io::Error>) -> ResponseBodyStream {
+    fn new(inner: Body<HttpResponseBody, io::Error>,
+           permit: Option<InflightPermit>,
+           content_length: Option<u64>)
+           -> ResponseBodyStream {
         ResponseBodyStream {
             inner: inner,
+            permit: permit,
+            deadline: None,
+            abort: None,
+            received: 0,
+            content_length: content_length,
         }
     }
+
+    /// Returns the number of body bytes that the stream has yielded so far, e.g. for showing the
+    /// progress of a download.
+    pub fn bytes_received(&self) -> u64 {
+        self.received
+    }
+
+    /// Returns the length of the whole body, if it's known upfront (as declared by the
+    /// `content-length` header of the response), which allows computing how much of it is
+    /// still to come.
+    pub fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    /// Turns the body into a `Stream` of the gRPC messages that it carries, regardless of how
+    /// they're split up into DATA frames.
+    pub fn grpc_messages(self) -> GrpcMessages<ResponseBodyStream> {
+        self.framed(GrpcDecoder::new())
+    }
+
+    /// Turns the body into a `Stream` of the items that the given decoder splits it into,
+    /// regardless of how they're split up into DATA frames.
+    pub fn framed<D>(self, decoder: D) -> FramedBody<ResponseBodyStream, D> where D: BodyDecoder {
+        FramedBody::new(self, decoder)
+    }
+
+    /// Turns a `text/event-stream` body into a `Stream` of the Server-Sent Events that it
+    /// carries. The last event ID, for resuming the stream, is available through the decoder.
+    pub fn sse_events(self) -> FramedBody<ResponseBodyStream, SseDecoder> {
+        self.framed(SseDecoder::new())
+    }
 }
 
 impl Stream for ResponseBodyStream {
     type Item = HttpResponseBody;
-    type Error = io::Error;
+    type Error = H2Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.inner.poll()
+        // Once the body is being read, dropping it is no longer a sign that nobody wants it.
+        if let Some(ref mut abort) = self.abort {
+            abort.disarm();
+        }
+        let res = self.inner.poll().map_err(H2Error::from);
+        if let Ok(Async::NotReady) = res {
+            if let Some(ref mut deadline) = self.deadline {
+                deadline.poll_expired()?;
+            }
+        }
+        match res {
+            Ok(Async::Ready(Some(ref chunk))) => self.received += chunk.body.len() as u64,
+            Ok(Async::NotReady) => {},
+            // The request is no longer outstanding, whether it completed or failed.
+            _ => { self.permit.take(); },
+        }
+        res
+    }
+}
+
+/// A `futures::Stream` of the body chunks of a response that guards against misbehaving servers:
+/// it fails if the body grows larger than a limit, or if the server stalls for too long between
+/// two chunks. Produced by `H2RequestHandle::into_response_with_body_limit_and_timeout`.
+///
+/// Either way, the request's stream is reset, so that the server stops sending the body.
+pub struct GuardedBodyStream {
+    inner: ResponseBodyStream,
+    /// The state of the request, which allows resetting its stream.
+    shared: SharedRequest,
+    /// The most bytes that the body may have.
+    max_bytes: usize,
+    /// The number of bytes received so far.
+    received: usize,
+    /// The longest time that may pass between two chunks.
+    idle_timeout: Duration,
+    /// Fires once the server has been idle for too long. Restarted for each chunk.
+    timer: Timeout,
+    handle: Handle,
+}
+
+impl GuardedBodyStream {
+    /// Gives up on the rest of the body, by resetting the request's stream.
+    fn abort(&mut self, err: H2Error) -> H2Error {
+        debug!("aborting the response body: {}", err);
+        self.shared.request_reset(ErrorCode::Cancel);
+        err
+    }
+}
+
+impl Stream for GuardedBodyStream {
+    type Item = HttpResponseBody;
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.inner.poll()? {
+            Async::Ready(Some(chunk)) => {
+                self.received += chunk.body.len();
+                if self.received > self.max_bytes {
+                    return Err(self.abort(H2Error::BodyLimitExceeded));
+                }
+                self.timer = Timeout::new(self.idle_timeout, &self.handle)?;
+                Ok(Async::Ready(Some(chunk)))
+            },
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => {
+                match self.timer.poll()? {
+                    Async::Ready(()) => Err(self.abort(H2Error::Timeout)),
+                    Async::NotReady => Ok(Async::NotReady),
+                }
+            },
+        }
+    }
+}
+
+/// A `Future` that drives a response and hands it over to a oneshot channel once it arrives.
+/// Gives up on the response (dropping it) as soon as the receiver is gone.
+struct DeliverResponse {
+    response: Box<Future<Item=HttpResponse, Error=H2Error>>,
+    sender: Option<oneshot::Sender<Result<HttpResponse, H2Error>>>,
+}
+
+impl Future for DeliverResponse {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let canceled = self.sender.as_mut().expect("polled after completion").poll_cancel();
+        if let Ok(Async::Ready(())) = canceled {
+            trace!("response receiver dropped");
+            return Ok(Async::Ready(()));
+        }
+        let res = match self.response.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(response)) => Ok(response),
+            Err(err) => Err(err),
+        };
+        let sender = self.sender.take().expect("polled after completion");
+        let _ = sender.send(res);
+        Ok(Async::Ready(()))
+    }
+}
+
+/// A point in time by which a request has to complete (i.e. both its response and the full body
+/// have to arrive), or else it's canceled.
+struct Deadline {
+    timer: Timeout,
+    shared: SharedRequest,
+}
+
+impl Deadline {
+    /// Checks whether the deadline has passed, in which case the request's stream is reset and
+    /// `H2Error::Timeout` returned.
+    fn poll_expired(&mut self) -> Result<(), H2Error> {
+        match self.timer.poll()? {
+            Async::Ready(()) => {
+                debug!("request deadline exceeded; canceling it");
+                self.shared.request_reset(ErrorCode::Cancel);
+                Err(H2Error::Timeout)
+            },
+            Async::NotReady => Ok(()),
+        }
+    }
+}
+
+/// A `Future` of a response that fails if the response doesn't arrive before the deadline. Once
+/// it arrives, the deadline is handed over to its body.
+struct WithDeadline {
+    inner: ResponseFuture,
+    deadline: Option<Deadline>,
+}
+
+impl Future for WithDeadline {
+    type Item = (HttpResponseHeaders, ResponseBodyStream);
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll()? {
+            Async::Ready((headers, mut body)) => {
+                body.deadline = self.deadline.take();
+                Ok(Async::Ready((headers, body)))
+            },
+            Async::NotReady => {
+                self.deadline.as_mut().expect("polled after completion").poll_expired()?;
+                Ok(Async::NotReady)
+            },
+        }
+    }
+}
+
+/// Formats the given timeout as the value of a `grpc-timeout` header: at most 8 digits, followed
+/// by the unit. The finest unit that the timeout fits into is used, rounding up, so that the
+/// server never gives up sooner than the client.
+fn format_grpc_timeout(timeout: Duration) -> Vec<u8> {
+    const MAX_VALUE: u64 = 99_999_999;
+    let secs = timeout.as_secs();
+    let nanos = timeout.subsec_nanos() as u64;
+
+    // The units that are finer than a second, by how many of them make up a second...
+    for &(per_sec, unit) in &[(1_000_000_000, "n"), (1_000_000, "u"), (1_000, "m"), (1, "S")] {
+        let nanos_per_unit = 1_000_000_000 / per_sec;
+        let value = secs.checked_mul(per_sec)
+            .and_then(|value| value.checked_add((nanos + nanos_per_unit - 1) / nanos_per_unit));
+        match value {
+            Some(value) if value <= MAX_VALUE => return format!("{}{}", value, unit).into_bytes(),
+            _ => {},
+        }
+    }
+    // ...and the coarser ones, by how many seconds each of them takes.
+    let secs = secs + if nanos > 0 { 1 } else { 0 };
+    let minutes = (secs + 59) / 60;
+    if minutes <= MAX_VALUE {
+        return format!("{}M", minutes).into_bytes();
+    }
+    format!("{}H", cmp::min((secs + 3599) / 3600, MAX_VALUE)).into_bytes()
+}
+
+/// Cancels a request when dropped, as long as it's armed.
+struct AbortOnDrop {
+    shared: SharedRequest,
+    armed: bool,
+}
+
+impl AbortOnDrop {
+    fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if self.armed {
+            debug!("request dropped before its response arrived; canceling it");
+            self.shared.request_reset(ErrorCode::Cancel);
+        }
+    }
+}
+
+/// A `Future` that resolves once all the requests opened before the given sequence number are
+/// done, or the connection is closed. Produced by `H2Client::flush_inflight`.
+struct FlushInflight {
+    connection: SharedConnection,
+    seq: u64,
+}
+
+impl Future for FlushInflight {
+    type Item = ();
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<(), H2Error> {
+        if self.connection.poll_requests_done(self.seq).is_ready() {
+            return Ok(Async::Ready(()));
+        }
+        match self.connection.poll_closed() {
+            Async::Ready(reason) => {
+                debug!("connection closed while flushing in-flight requests: {:?}", reason);
+                Ok(Async::Ready(()))
+            },
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A `Future` that resolves once the client is allowed to start another request.
+struct AcquireInflightSlot {
+    limit: InflightLimit,
+}
+
+impl Future for AcquireInflightSlot {
+    type Item = InflightPermit;
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(self.limit.poll_acquire())
+    }
+}
+
+/// The future of the response headers and the body stream, which all the `H2Client` request
+/// methods build on.
+type ResponseFuture = Box<Future<Item=(HttpResponseHeaders, ResponseBodyStream), Error=H2Error>>;
+
+/// A `Future` of a response, whose request is re-submitted on a new stream whenever the server
+/// refuses its stream, up to a number of times. Produced by `RequestBuilder::retry_refused`.
+struct RetryRefused {
+    /// The response of the current attempt.
+    response: ResponseFuture,
+    client: TokioClient,
+    connection: SharedConnection,
+    inflight: Option<InflightLimit>,
+    /// The state shared with the transport, which each attempt takes over in turn.
+    shared: SharedRequest,
+    /// The full set of headers that each attempt is sent with.
+    headers: Vec<StaticHeader>,
+    body: Option<Vec<u8>>,
+    /// The number of times that the request may still be re-submitted.
+    attempts_left: u32,
+}
+
+impl Future for RetryRefused {
+    type Item = (HttpResponseHeaders, ResponseBodyStream);
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.response.poll() {
+                // A refused stream is guaranteed not to have been processed by the server.
+                Err(H2Error::StreamReset(ErrorCode::RefusedStream)) if self.attempts_left > 0 => {
+                    debug!("the server refused the stream; retrying ({} attempts left)",
+                           self.attempts_left);
+                    self.attempts_left -= 1;
+                    let headers = HttpRequestHeaders::with_headers(self.headers.clone());
+                    let message = message_with_body(headers, self.body.clone());
+                    self.response = submit_request(&self.client,
+                                                    &self.connection,
+                                                    self.inflight.as_ref(),
+                                                    message,
+                                                    &self.shared);
+                },
+                res => return res,
+            }
+        }
+    }
+}
+
+/// A `futures::Stream` of the interim (1xx) responses that the server sends before the final
+/// response to a request (e.g. `103 Early Hints`). The stream ends once the final response has
+/// been received.
+pub struct InformationalResponses {
+    shared: SharedRequest,
+}
+
+impl Stream for InformationalResponses {
+    type Item = HttpResponseHeaders;
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        Ok(self.shared.poll_informational())
+    }
+}
+
+/// A `Future` of the ID of the HTTP/2 stream that a request gets assigned to. Produced by
+/// `H2RequestHandle::stream_id_assigned`.
+pub struct StreamIdAssigned {
+    shared: SharedRequest,
+}
+
+impl Future for StreamIdAssigned {
+    type Item = Option<StreamId>;
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(self.shared.poll_stream_id())
+    }
+}
+
+/// The state of a `ResponseEventStream`.
+enum EventStreamState {
+    /// Waiting for the response headers.
+    Headers(ResponseFuture),
+    /// Yielding the body chunks.
+    Body(ResponseBodyStream),
+    /// Everything has been yielded.
+    Done,
+}
+
+/// A `futures::Stream` that yields the whole response as a sequence of `ResponseEvent`s: first the
+/// headers, then the body chunks and finally the trailers (if any).
+pub struct ResponseEventStream {
+    state: EventStreamState,
+    /// Gives access to the trailers, once the end of the body is reached.
+    shared: SharedRequest,
+}
+
+impl Stream for ResponseEventStream {
+    type Item = ResponseEvent;
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let (event, next_state) = match self.state {
+            EventStreamState::Headers(ref mut response) => {
+                match response.poll()? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready((headers, body)) => {
+                        (Some(ResponseEvent::Headers(headers)), EventStreamState::Body(body))
+                    },
+                }
+            },
+            EventStreamState::Body(ref mut body) => {
+                match body.poll()? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready(Some(chunk)) => {
+                        return Ok(Async::Ready(Some(ResponseEvent::Body(chunk))));
+                    },
+                    Async::Ready(None) => {
+                        let trailers = self.shared.take_trailers().map(ResponseEvent::Trailers);
+                        (trailers, EventStreamState::Done)
+                    },
+                }
+            },
+            EventStreamState::Done => return Ok(Async::Ready(None)),
+        };
+
+        self.state = next_state;
+        Ok(Async::Ready(event))
+    }
+}
+
+/// A `Future` of just the response headers, which leaves the body with the `H2RequestHandle` that
+/// it was obtained from. Produced by `H2RequestHandle::headers`.
+pub struct ResponseHeaders<'a> {
+    handle: &'a mut H2RequestHandle,
+}
+
+impl<'a> Future for ResponseHeaders<'a> {
+    type Item = HttpResponseHeaders;
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let handle = &mut *self.handle;
+        if handle.headers_taken {
+            return Err(H2Error::ResponseAlreadyTaken);
+        }
+        match handle.inner.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            res => {
+                // The response (or its failure) is in; from here on, it's up to the body whether
+                // the request gets canceled when it's dropped.
+                handle.headers_taken = true;
+                let abort = AbortOnDrop {
+                    shared: handle.shared.clone(),
+                    armed: handle.abort.armed,
+                };
+                handle.abort.disarm();
+                let body_slot = &mut handle.body;
+                res.map(|ready| ready.map(move |(headers, mut body)| {
+                    body.abort = Some(abort);
+                    *body_slot = Some(body);
+                    headers
+                }))
+            },
+        }
     }
 }
 
 /// A `Future` produced by the `H2Client`'s various `request` methods.
 /// (`request`, `get`, `post`, ...)
-pub struct FutureH2Response {
+pub struct H2RequestHandle {
     /// Simply wraps a boxed future
-    inner: BoxFuture<(HttpResponseHeaders, ResponseBodyStream), io::Error>,
+    inner: ResponseFuture,
+    /// The body of the response, once `headers` resolved, until it's taken by `body`.
+    body: Option<ResponseBodyStream>,
+    /// Set once `headers` resolved, after which the handle can no longer be used as a future.
+    headers_taken: bool,
+    /// The state of the request that is shared with the transport.
+    shared: SharedRequest,
+    /// The handle of the event loop that the client runs on, used for setting up timers.
+    handle: Handle,
+    /// Cancels the request if the handle is dropped before the response arrives, if armed.
+    abort: AbortOnDrop,
 }
 
-impl Future for FutureH2Response {
+impl Future for H2RequestHandle {
     type Item = (HttpResponseHeaders, ResponseBodyStream);
-    type Error = io::Error;
+    type Error = H2Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.inner.poll()
+        if self.headers_taken {
+            return Err(H2Error::ResponseAlreadyTaken);
+        }
+        match self.inner.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            res => {
+                // The response (or its failure) is in; from here on, it's up to the body stream.
+                self.abort.disarm();
+                res
+            },
+        }
     }
 }
 
-impl FutureH2Response {
-    /// Creates a new `FutureH2Response` wrapping the given boxed future.
-    fn new(inner: BoxFuture<(HttpResponseHeaders, ResponseBodyStream), io::Error>)
-            -> FutureH2Response {
-        FutureH2Response {
+impl H2RequestHandle {
+    /// Creates a new `H2RequestHandle` wrapping the given boxed future.
+    fn new(inner: ResponseFuture, shared: SharedRequest, handle: Handle) -> H2RequestHandle {
+        H2RequestHandle {
             inner: inner,
+            body: None,
+            headers_taken: false,
+            abort: AbortOnDrop {
+                shared: shared.clone(),
+                armed: false,
+            },
+            shared: shared,
+            handle: handle,
+        }
+    }
+
+    /// Takes the handle apart, for turning it into a different future. If the request is to be
+    /// aborted on drop, the returned future takes over that duty.
+    fn into_parts(self) -> (ResponseFuture, SharedRequest, Handle) {
+        let H2RequestHandle { inner, shared, handle, abort, .. } = self;
+        if !abort.armed {
+            return (inner, shared, handle);
+        }
+        let inner = inner.then(move |res| {
+            let mut abort = abort;
+            abort.disarm();
+            res
+        });
+        (Box::new(inner), shared, handle)
+    }
+
+    /// Makes the request fail with `H2Error::Timeout` if it doesn't complete, body included,
+    /// within the given `timeout`. The request is canceled (see `cancel`) once it times out.
+    fn with_deadline(self, timeout: Duration) -> H2RequestHandle {
+        let timer = match Timeout::new(timeout, &self.handle) {
+            Ok(timer) => timer,
+            Err(err) => {
+                let inner = Box::new(future::err(H2Error::from(err)));
+                return H2RequestHandle { inner: inner, ..self };
+            },
+        };
+        let deadline = Deadline {
+            timer: timer,
+            shared: self.shared.clone(),
+        };
+        let H2RequestHandle { inner, shared, handle, abort, .. } = self;
+        H2RequestHandle {
+            inner: Box::new(WithDeadline { inner: inner, deadline: Some(deadline) }),
+            body: None,
+            headers_taken: false,
+            shared: shared,
+            handle: handle,
+            abort: abort,
+        }
+    }
+
+    /// Cancels the request: its stream is reset, so the server stops working on it, and the
+    /// response fails with `H2Error::Canceled` (as does the response body, if the response was
+    /// already received).
+    ///
+    /// Canceling a request that has already completed has no effect.
+    pub fn cancel(&self) {
+        debug!("canceling the request");
+        self.shared.request_reset(ErrorCode::Cancel);
+    }
+
+    /// Returns a future of just the response headers (and status), which leaves the body with the
+    /// handle, to be taken with `body` once the headers are in. This allows deciding whether to
+    /// read, discard or cancel the body based on the headers.
+    ///
+    /// Once the headers resolved, the handle can no longer be used as a future itself (nor can
+    /// the headers be polled again): polling it fails with `H2Error::ResponseAlreadyTaken`. If the
+    /// request is to be canceled on drop (see `abort_on_drop`), dropping the body without ever
+    /// polling it cancels the request too.
+    pub fn headers(&mut self) -> ResponseHeaders {
+        ResponseHeaders {
+            handle: self,
+        }
+    }
+
+    /// Takes the body of the response, once `headers` resolved. Returns `None` before that, or if
+    /// the body has already been taken.
+    pub fn body(&mut self) -> Option<ResponseBodyStream> {
+        self.body.take()
+    }
+
+    /// Takes the body of the response, like `body`, as a `Stream` of the items that the given
+    /// decoder splits it into.
+    pub fn body_framed<D>(&mut self, decoder: D) -> Option<FramedBody<ResponseBodyStream, D>>
+            where D: BodyDecoder {
+        self.body.take().map(|body| body.framed(decoder))
+    }
+
+    /// Takes the body of the response, like `body`, as a `Stream` of the Server-Sent Events that
+    /// it carries.
+    pub fn sse_events(&mut self) -> Option<FramedBody<ResponseBodyStream, SseDecoder>> {
+        self.body.take().map(ResponseBodyStream::sse_events)
+    }
+
+    /// Sets whether the request should be canceled (see `cancel`) if the handle is dropped before
+    /// the response arrives. By default, a dropped request is left to complete, with its response
+    /// ignored.
+    ///
+    /// Once the response has arrived, dropping the handle has no effect either way: the request
+    /// then lives on for as long as its body stream does.
+    pub fn abort_on_drop(mut self, abort: bool) -> H2RequestHandle {
+        self.abort.armed = abort;
+        self
+    }
+
+    /// Returns the ID of the HTTP/2 stream that the request was assigned to, e.g. for correlating
+    /// it with the server's logs.
+    ///
+    /// The request is only handed off to the connection once the event loop gets to run, so the
+    /// ID is `None` until then (as well as if the request could not be started at all).
+    pub fn stream_id(&self) -> Option<StreamId> {
+        self.shared.stream_id()
+    }
+
+    /// Returns a future of the ID of the HTTP/2 stream that the request gets assigned to, which
+    /// resolves as soon as the connection starts the request, unlike `stream_id`.
+    ///
+    /// Resolves to `None` if the request is done without ever getting a stream, e.g. because it
+    /// could not be started.
+    pub fn stream_id_assigned(&self) -> StreamIdAssigned {
+        StreamIdAssigned {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Returns the full set of headers that the request was sent with, including the ones that
+    /// were added automatically (e.g. the pseudo-headers or `content-length`), which is useful
+    /// for figuring out why the server rejected a request.
+    ///
+    /// Just like the stream ID, the headers are only known once the event loop got to hand the
+    /// request to the connection. They remain available after the request fails, as long as the
+    /// headers were well-formed.
+    pub fn sent_headers(&self) -> Option<Vec<StaticHeader>> {
+        self.shared.sent_headers()
+    }
+
+    /// Turns the request into a background request, which yields to all the other (foreground)
+    /// requests on the connection, e.g. for a bulk download that shouldn't hold up interactive
+    /// requests.
+    ///
+    /// The server is asked to give the stream the lowest possible priority. On top of that, the
+    /// request body is only sent while no foreground request has any body data to send, and the
+    /// stream's flow control window is only given back to the server while no foreground
+    /// response is still being received. A background response can therefore stall for as long
+    /// as foreground requests keep coming in.
+    pub fn background(self) -> H2RequestHandle {
+        self.shared.request_background();
+        self
+    }
+
+    /// Re-prioritizes the request, e.g. for a long-running download that became more (or less)
+    /// urgent while in flight. The weight is clamped to the range allowed by HTTP/2 (1 to 256),
+    /// with requests starting out at 16.
+    ///
+    /// The server is sent a PRIORITY frame with the new weight, and while several requests have
+    /// body data to send, the ones with the highest weight go first. Re-prioritizing a request
+    /// that has already completed has no effect.
+    pub fn set_priority(&self, weight: u16) {
+        let weight = cmp::max(1, cmp::min(weight, 256));
+        debug!("re-prioritizing the request; weight={}", weight);
+        self.shared.request_priority(weight);
+    }
+
+    /// Attaches the given caller-supplied value to the request. The returned future resolves to
+    /// a `TaggedResponse` (or fails with a `TaggedError`) that hands the value back, which allows
+    /// correlating many concurrent requests with their responses.
+    pub fn tag<T>(self, tag: T) -> TaggedH2Response<T> {
+        TaggedH2Response {
+            response: self,
+            tag: Some(tag),
+        }
+    }
+
+    /// Returns a `Stream` of the interim (1xx) responses that the server sends before the final
+    /// response. Unless this is called, any interim responses are silently skipped over.
+    ///
+    /// Only the interim responses received after the call are observed, so it should be called
+    /// right after the request is made.
+    pub fn informational_responses(&self) -> InformationalResponses {
+        self.shared.observe_informational();
+        InformationalResponses {
+            shared: self.shared.clone(),
         }
     }
 
-    /// Consumes the `FutureH2Response` and returns a new `Future` that will resolve once the full
+    /// Consumes the `H2RequestHandle` and returns a single `Stream` of `ResponseEvent`s, which
+    /// yields the headers, each body chunk as it arrives and finally the trailers, if the server
+    /// sent any. Any error (including one in the middle of the body) is yielded as the stream's
+    /// error.
+    pub fn into_event_stream(self) -> ResponseEventStream {
+        let (inner, shared, _handle) = self.into_parts();
+        ResponseEventStream {
+            state: EventStreamState::Headers(inner),
+            shared: shared,
+        }
+    }
+
+    /// Consumes the `H2RequestHandle` and returns a new `Future` that will resolve once the full
     /// body of the response has become available, with both the response headers and all the body
     /// bytes in a `Vec<u8>` (as an `HttpResponse` instance).
-    pub fn into_full_body_response(self) -> BoxFuture<HttpResponse, io::Error> {
-        let body_response = self.and_then(|(headers, body_stream)| {
+    pub fn into_full_body_response(self) -> Box<Future<Item=HttpResponse, Error=H2Error>> {
+        let (inner, _shared, _handle) = self.into_parts();
+        let body_response = inner.and_then(|(headers, body_stream)| {
+            // With the length known upfront, the body is allocated for only once. The server
+            // can't be trusted with how much gets allocated before any of the body arrives though.
+            let expected = headers.content_length().map(|len| len as usize);
+            let capacity = expected.map_or(0, |len| cmp::min(len, MAX_PREALLOCATED_BODY_LEN));
             body_stream
-                .fold(Vec::<u8>::new(), |mut vec, chunk| {
-                    vec.extend(chunk.body.into_iter());
-                    future::ok::<_, io::Error>(vec)
+                .fold(Vec::<u8>::with_capacity(capacity), move |mut vec, chunk| {
+                    if vec.is_empty() && expected.map_or(true, |len| chunk.body.len() >= len) {
+                        // The first chunk carries the whole body (or the length is unknown), so
+                        // its buffer can be taken over as is, without copying.
+                        vec = chunk.body;
+                    } else {
+                        vec.extend_from_slice(&chunk.body);
+                    }
+                    future::ok::<_, H2Error>(vec)
                 })
                 .map(move |body| {
                     HttpResponse {
@@ -112,7 +813,616 @@ impl FutureH2Response {
                 })
         });
 
-        body_response.boxed()
+        Box::new(body_response)
+    }
+
+    /// Consumes the `H2RequestHandle` and returns a new `Future` that resolves to the response
+    /// headers alone, for requests where only the status matters. The body is drained in the
+    /// background, by a task spawned on the client's event loop, with each chunk dropped as soon
+    /// as it arrives (and its share of the flow control window given back), until the server
+    /// finishes the response.
+    ///
+    /// Unlike canceling the request, this lets the server complete it. Any error that the body
+    /// fails with is ignored.
+    pub fn discard_body(self) -> Box<Future<Item=HttpResponseHeaders, Error=H2Error>> {
+        let (inner, _shared, handle) = self.into_parts();
+        let headers = inner.map(move |(headers, body)| {
+            handle.spawn(body.for_each(|_chunk| Ok(())).then(|res| {
+                if let Err(err) = res {
+                    debug!("discarded response body failed: {:?}", err);
+                }
+                Ok(())
+            }));
+            headers
+        });
+
+        Box::new(headers)
+    }
+
+    /// Consumes the `H2RequestHandle` and returns a new `Future` that writes the response body to
+    /// the given blocking `Write` sink (e.g. a file) as it arrives, instead of buffering it in
+    /// memory, and resolves to the number of bytes written. The response headers are dropped.
+    ///
+    /// As blocking writes can't be done on the event loop, the sink is written to on a separate
+    /// worker thread, which is handed the chunks through a bounded channel. Only a few chunks are
+    /// ever buffered: once the worker falls behind, the body is no longer read off the
+    /// connection, so the server is held back by flow control. If writing fails, so does the
+    /// future, with the error that the sink returned.
+    ///
+    /// Every call spawns a new OS thread, and there is no limit on how many of them may be around
+    /// at a time. The thread is detached: if the returned future is dropped before the body is
+    /// done, the thread finishes the write in progress, flushes the sink and exits, leaving the
+    /// sink with whatever part of the body reached it.
+    pub fn copy_body_to<W>(self, writer: W) -> Box<Future<Item=u64, Error=H2Error>>
+            where W: 'static + Write + Send {
+        let (tx, rx) = mpsc::channel(WRITER_CHANNEL_CAPACITY);
+        let (done_tx, done_rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = done_tx.send(write_chunks(writer, rx));
+        });
+
+        let (inner, _shared, _handle) = self.into_parts();
+        let tx = tx.sink_map_err(|_| writer_thread_gone());
+        let copy = inner
+            .and_then(move |(_headers, body)| body.map(|chunk| chunk.body).forward(tx))
+            // Dropping the channel lets the worker know that the body is done.
+            .map(|(_body, _tx)| ())
+            .then(move |forwarded| {
+                done_rx.then(move |written| {
+                    match (forwarded, written) {
+                        // Writing failed, which is what stopped the body from being forwarded.
+                        (_, Ok(Err(err))) => Err(H2Error::Io(err)),
+                        (Err(err), _) => Err(err),
+                        (Ok(()), Ok(Ok(total))) => Ok(total),
+                        (Ok(()), Err(_canceled)) => Err(writer_thread_gone()),
+                    }
+                })
+            });
+
+        Box::new(copy)
+    }
+
+    /// Consumes the `H2RequestHandle` and returns a new `Future` that resolves to the full
+    /// response of a gRPC call, just like `into_full_body_response`, as long as the call
+    /// succeeded. If the response carries a non-OK `grpc-status` (in its trailers or, for a
+    /// trailers-only response, in its headers), or none at all, it fails with `H2Error::Grpc`.
+    pub fn into_grpc_response(self) -> Box<Future<Item=HttpResponse, Error=H2Error>> {
+        let shared = self.shared.clone();
+        let response = self.into_full_body_response().and_then(move |response| {
+            let status = shared.take_trailers()
+                .and_then(|trailers| trailers.grpc_status())
+                .or_else(|| GrpcStatus::from_headers(&response.headers));
+            match status {
+                Some(ref status) if status.is_ok() => Ok(response),
+                Some(status) => Err(H2Error::Grpc(status)),
+                None => {
+                    Err(H2Error::Grpc(GrpcStatus {
+                        code: GRPC_STATUS_UNKNOWN,
+                        message: Some("the response carries no grpc-status".to_owned()),
+                    }))
+                },
+            }
+        });
+
+        Box::new(response)
+    }
+
+    /// Consumes the `H2RequestHandle` and returns a new `Future` that resolves as soon as the
+    /// response headers arrive, along with a `Stream` of the body that fails if the body exceeds
+    /// `max_bytes`, or if more than `idle_timeout` passes without a new chunk arriving (e.g. as
+    /// the server stalls in the middle of the body).
+    ///
+    /// This allows reading large bodies lazily, while still guarding against servers that send
+    /// bodies too large or too slowly.
+    pub fn into_response_with_body_limit_and_timeout(self, max_bytes: usize, idle_timeout: Duration)
+            -> Box<Future<Item=(HttpResponseHeaders, GuardedBodyStream), Error=H2Error>> {
+        let (inner, shared, handle) = self.into_parts();
+        let response = inner.and_then(move |(headers, body)| {
+            let timer = Timeout::new(idle_timeout, &handle)?;
+            let body = GuardedBodyStream {
+                inner: body,
+                shared: shared,
+                max_bytes: max_bytes,
+                received: 0,
+                idle_timeout: idle_timeout,
+                timer: timer,
+                handle: handle,
+            };
+            Ok((headers, body))
+        });
+
+        Box::new(response)
+    }
+
+    /// Consumes the `H2RequestHandle` and returns a new `Future` that will resolve once the full
+    /// body of the response has become available, as long as the body is no larger than
+    /// `max_bytes`.
+    ///
+    /// If the body exceeds the limit, the stream is reset (so that the server stops sending the
+    /// rest of it) and the future resolves to a `CappedBodyError::LimitExceeded` error, which
+    /// carries the headers and the part of the body received before the limit was hit.
+    pub fn into_full_body_response_capped(self, max_bytes: usize)
+            -> Box<Future<Item=HttpResponse, Error=CappedBodyError>> {
+        let (inner, shared, _handle) = self.into_parts();
+        let response = inner.map_err(CappedBodyError::Request);
+        let body_response = response.and_then(move |(headers, body_stream)| {
+            body_stream
+                .map_err(CappedBodyError::Request)
+                .fold(Vec::<u8>::new(), move |mut vec, chunk| {
+                    if vec.len() + chunk.body.len() > max_bytes {
+                        debug!("response body exceeded the limit of {} bytes", max_bytes);
+                        shared.request_reset(ErrorCode::Cancel);
+                        let partial = HttpResponse {
+                            headers: Vec::new(),
+                            body: vec,
+                        };
+                        return Err(CappedBodyError::LimitExceeded(partial));
+                    }
+
+                    vec.extend(chunk.body.into_iter());
+                    Ok(vec)
+                })
+                .then(move |res| {
+                    // Attach the headers to the (possibly partial) response.
+                    match res {
+                        Ok(body) => Ok(HttpResponse {
+                            headers: headers.headers,
+                            body: body,
+                        }),
+                        Err(CappedBodyError::LimitExceeded(partial)) => {
+                            Err(CappedBodyError::LimitExceeded(HttpResponse {
+                                headers: headers.headers,
+                                body: partial.body,
+                            }))
+                        },
+                        Err(err) => Err(err),
+                    }
+                })
+        });
+
+        Box::new(body_response)
+    }
+}
+
+/// A response along with the value that was attached to its request by `H2RequestHandle::tag`.
+pub struct TaggedResponse<T> {
+    pub tag: T,
+    pub headers: HttpResponseHeaders,
+    pub body: ResponseBodyStream,
+}
+
+/// The error of a request along with the value that was attached to it by
+/// `H2RequestHandle::tag`.
+#[derive(Debug)]
+pub struct TaggedError<T> {
+    pub tag: T,
+    pub error: H2Error,
+}
+
+/// A builder of a single request, which allows adding the headers of the request one by one or
+/// in bulk. Obtained from `H2Client::build_request`.
+///
+/// The headers are appended after the pseudo-headers, in the order in which they were added.
+/// The path is sent as-is, so it can also be `*` for an `OPTIONS *` request. Each header name
+/// is checked to be non-empty and lowercase as it is added; if any of them isn't, the request is
+/// never sent and its response fails with `H2Error::InvalidRequest`.
+///
+/// The request is either sent whole, along with its body, by `send`, or started right away by
+/// `start`, with its body streamed out afterwards.
+pub struct RequestBuilder<'a> {
+    client: &'a mut H2Client,
+    method: Vec<u8>,
+    path: Vec<u8>,
+    headers: Vec<StaticHeader>,
+    body: Option<Vec<u8>>,
+    /// The time that the request is given to complete, if it has a deadline.
+    timeout: Option<Duration>,
+    /// The number of times that the request is re-submitted if the server refuses its stream.
+    refused_retries: u32,
+    /// The encoding that the body is compressed with, if any.
+    #[cfg(feature = "compression")]
+    encoding: Option<Encoding>,
+    /// The first problem found with the added headers, if any.
+    error: Option<InvalidHeadersError>,
+}
+
+impl<'a> RequestBuilder<'a> {
+    /// Adds a single header to the request.
+    pub fn header<N, V>(mut self, name: N, value: V) -> RequestBuilder<'a>
+            where N: Into<Vec<u8>>, V: Into<Vec<u8>> {
+        let name = name.into();
+        if self.error.is_none() {
+            if let Err(err) = validate_header_name(&name) {
+                debug!("invalid header name {:?}: {}", String::from_utf8_lossy(&name), err);
+                self.error = Some(err);
+            }
+        }
+        self.headers.push(Header::new(name, value.into()));
+        self
+    }
+
+    /// Adds all the given `(name, value)` pairs as headers of the request, e.g. when forwarding
+    /// the headers of an incoming request.
+    pub fn headers<I, N, V>(self, headers: I) -> RequestBuilder<'a>
+            where I: IntoIterator<Item=(N, V)>, N: Into<Vec<u8>>, V: Into<Vec<u8>> {
+        headers.into_iter().fold(self, |builder, (name, value)| builder.header(name, value))
+    }
+
+    /// Sets the body of the request.
+    pub fn body(mut self, body: Vec<u8>) -> RequestBuilder<'a> {
+        self.body = Some(body);
+        self
+    }
+
+    /// Sends the request with the given `:authority`, instead of the one that the client
+    /// connected to, while reusing the same connection.
+    ///
+    /// This is meant for connection coalescing, i.e. when the server is authoritative for
+    /// multiple hosts (e.g. its certificate covers all of them).
+    pub fn authority<A>(mut self, authority: A) -> RequestBuilder<'a> where A: Into<Vec<u8>> {
+        self.headers.retain(|header| header.name() != b":authority");
+        self.headers.insert(0, Header::new(b":authority", authority.into()));
+        self
+    }
+
+    /// Compresses the body with the given encoding and declares it with a `content-encoding`
+    /// header.
+    ///
+    /// Only the body is compressed, so the server needs to be known to accept the encoding. When
+    /// the request is started (see `start`), the chunks sent through its `RequestBodySender` are
+    /// fed through a streaming encoder on their way to the connection, so the compressed chunks
+    /// don't line up with the ones that were sent. Once the body is finished, the encoder is
+    /// flushed and the end of the compressed stream (e.g. the gzip trailer) is sent, before the
+    /// request ends. If compressing fails, the request fails too (resetting its stream, if it
+    /// was already started).
+    #[cfg(feature = "compression")]
+    pub fn compress_body(mut self, encoding: Encoding) -> RequestBuilder<'a> {
+        self.encoding = Some(encoding);
+        self.header(&b"content-encoding"[..], encoding.header_value())
+    }
+
+    /// Adds an `expect: 100-continue` header to the request.
+    ///
+    /// The request body is held back until the server sends a `100 Continue` interim response.
+    /// If the server responds with a final response instead (e.g. rejecting the request), the
+    /// body is never sent; any body chunks sent afterwards are dropped.
+    pub fn expect_continue(self) -> RequestBuilder<'a> {
+        self.header(&b"expect"[..], &b"100-continue"[..])
+    }
+
+    /// Marks the request as a gRPC call, by adding the headers that gRPC servers require: a
+    /// `content-type` of `application/grpc` and `te: trailers`, which tells the server that the
+    /// client supports trailers (where the outcome of the call is carried). Any other value of
+    /// the `te` header is still rejected, as HTTP/2 forbids connection-specific headers.
+    ///
+    /// Along with `grpc_timeout` and `H2RequestHandle::into_grpc_response`, this covers what a
+    /// gRPC call needs on top of a plain request.
+    pub fn grpc(self) -> RequestBuilder<'a> {
+        self.header(&b"content-type"[..], &b"application/grpc"[..])
+            .header(&b"te"[..], &b"trailers"[..])
+    }
+
+    /// Gives the request a deadline, for gRPC calls: the timeout is sent to the server in the
+    /// `grpc-timeout` header (e.g. `100m` or `5S`), so that it can give up on the call too, while
+    /// the response fails with `H2Error::Timeout` if it doesn't complete in time, body included.
+    pub fn grpc_timeout(mut self, timeout: Duration) -> RequestBuilder<'a> {
+        self.timeout = Some(timeout);
+        self.header(&b"grpc-timeout"[..], format_grpc_timeout(timeout))
+    }
+
+    /// Makes the request be re-submitted, on a new stream, if the server resets its stream with
+    /// REFUSED_STREAM, up to `max_attempts` times before the request fails with
+    /// `H2Error::StreamReset`. A refused stream is guaranteed not to have been processed, so this
+    /// is always safe, e.g. when racing against the server shutting down gracefully.
+    pub fn retry_refused(mut self, max_attempts: u32) -> RequestBuilder<'a> {
+        self.refused_retries = max_attempts;
+        self
+    }
+
+    /// Sends the request, returning the future of its response.
+    pub fn send(mut self) -> H2RequestHandle {
+        if let Some(err) = self.error.take() {
+            let err = H2Error::InvalidRequest(Http2Error::InvalidHeaders(err));
+            return self.client.failed_request(err);
+        }
+        if let Err(err) = self.compress_whole_body() {
+            return self.client.failed_request(H2Error::Io(err));
+        }
+
+        let RequestBuilder { client, method, path, headers, body, timeout, refused_retries, .. } =
+            self;
+        let response = if refused_retries > 0 {
+            client.request_retrying_refused(&method, &path, headers, body, refused_retries)
+        } else {
+            client.request(&method, &path, headers, body)
+        };
+        match timeout {
+            Some(timeout) => response.with_deadline(timeout),
+            None => response,
+        }
+    }
+
+    /// Starts the request right away, without committing to whether it has a body, just like
+    /// `H2Client::start_request`: the body is sent through the returned `RequestBodySender`,
+    /// while any body set by `body` is ignored. A request that is started isn't retried if the
+    /// server refuses its stream (see `retry_refused`), as its body can't be replayed.
+    pub fn start(mut self) -> (H2RequestHandle, RequestBodySender) {
+        if let Some(err) = self.error.take() {
+            let err = H2Error::InvalidRequest(Http2Error::InvalidHeaders(err));
+            let response = self.client.failed_request(err);
+            // Nothing is ever sent, so the receiving end of the body channel is simply dropped.
+            let (tx, _) = mpsc::channel(0);
+            let shared = response.shared.clone();
+            return (response, RequestBodySender { inner: tx, shared: shared, queued: 0 });
+        }
+
+        let headers = mem::replace(&mut self.headers, Vec::new());
+        let (response, body_tx) = self.client.streaming_request(&self.method, &self.path, headers);
+        let tx = self.body_sender(&response, body_tx);
+        let response = match self.timeout {
+            Some(timeout) => response.with_deadline(timeout),
+            None => response,
+        };
+        let shared = response.shared.clone();
+        (response, RequestBodySender { inner: tx, shared: shared, queued: 0 })
+    }
+
+    /// Compresses the body that is sent whole by `send`, if `compress_body` was called.
+    #[cfg(feature = "compression")]
+    fn compress_whole_body(&mut self) -> io::Result<()> {
+        if let (Some(encoding), Some(body)) = (self.encoding, self.body.as_mut()) {
+            *body = compression::compress(body, encoding)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn compress_whole_body(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the channel that the body chunks of a started request are posted onto, which
+    /// feeds them through a streaming encoder, if `compress_body` was called.
+    #[cfg(feature = "compression")]
+    fn body_sender(&self,
+                   response: &H2RequestHandle,
+                   body_tx: mpsc::Sender<Result<HttpRequestBody, io::Error>>)
+                   -> mpsc::Sender<Result<HttpRequestBody, io::Error>> {
+        let encoding = match self.encoding {
+            Some(encoding) => encoding,
+            None => return body_tx,
+        };
+        // Just like the channel of a `Body`, this one only buffers a chunk per sender.
+        let (tx, rx) = mpsc::channel(0);
+        let chunks = rx.then(|res| {
+            match res {
+                Ok(Ok(chunk)) => Ok(chunk.into_vec()),
+                Ok(Err(err)) => Err(err),
+                Err(()) => Err(io::Error::new(io::ErrorKind::Other, "the body channel failed")),
+            }
+        });
+        self.client.forward_body(response, body_tx, CompressedBody::new(chunks, encoding));
+        tx
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn body_sender(&self,
+                   _response: &H2RequestHandle,
+                   body_tx: mpsc::Sender<Result<HttpRequestBody, io::Error>>)
+                   -> mpsc::Sender<Result<HttpRequestBody, io::Error>> {
+        body_tx
+    }
+}
+
+/// A `Future` of a response, whose request carries a caller-supplied value. Produced by
+/// `H2RequestHandle::tag`.
+pub struct TaggedH2Response<T> {
+    response: H2RequestHandle,
+    /// Handed back once the response resolves.
+    tag: Option<T>,
+}
+
+impl<T> TaggedH2Response<T> {
+    /// Returns the ID of the HTTP/2 stream that the request was assigned to, if it has already
+    /// been started. See `H2RequestHandle::stream_id`.
+    pub fn stream_id(&self) -> Option<StreamId> {
+        self.response.stream_id()
+    }
+
+    /// Returns the headers that the request was sent with. See `H2RequestHandle::sent_headers`.
+    pub fn sent_headers(&self) -> Option<Vec<StaticHeader>> {
+        self.response.sent_headers()
+    }
+}
+
+impl<T> Future for TaggedH2Response<T> {
+    type Item = TaggedResponse<T>;
+    type Error = TaggedError<T>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let res = match self.response.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(response)) => Ok(response),
+            Err(err) => Err(err),
+        };
+
+        let tag = self.tag.take().expect("TaggedH2Response polled after completion");
+        match res {
+            Ok((headers, body)) => Ok(Async::Ready(TaggedResponse {
+                tag: tag,
+                headers: headers,
+                body: body,
+            })),
+            Err(err) => Err(TaggedError {
+                tag: tag,
+                error: err,
+            }),
+        }
+    }
+}
+
+/// The sending end of the body of a request started by `H2Client::start_request`.
+///
+/// Body chunks are sent through its `Sink` impl. The request's stream stays open on the local end
+/// until the body is explicitly ended with `finish` (or the sender is dropped), whether or not any
+/// chunks were sent before that. Once the request is done (e.g. as its body grew past
+/// `ConnectionOptions::max_request_body_size`), sending any more chunks fails.
+pub struct RequestBodySender {
+    inner: mpsc::Sender<Result<HttpRequestBody, io::Error>>,
+    /// The state shared with the transport, which tells whether the request is still going.
+    shared: SharedRequest,
+    /// The number of body bytes that were accepted by the sink so far.
+    queued: u64,
+}
+
+impl RequestBodySender {
+    /// Returns a future that resolves once all the chunks sent so far were pushed out onto the
+    /// connection, i.e. packed into DATA frames and handed to the socket, while keeping the
+    /// stream open for more chunks. This allows delivering each message of an interactive
+    /// protocol in a timely manner, without ending the body.
+    ///
+    /// The future fails if the request is done before all of the chunks could be sent.
+    pub fn flush(&mut self) -> FlushBody {
+        let target = self.queued;
+        FlushBody {
+            sender: self,
+            target: target,
+        }
+    }
+
+    /// Ends the request body, closing the request's stream on the local end. Any chunks that
+    /// were already sent are still delivered before the end of the body.
+    pub fn finish(self) {
+        trace!("request body finished");
+    }
+}
+
+impl Sink for RequestBodySender {
+    type SinkItem = Vec<u8>;
+    type SinkError = H2Error;
+
+    fn start_send(&mut self, chunk: Vec<u8>) -> StartSend<Vec<u8>, H2Error> {
+        if self.shared.is_done() {
+            return Err(request_body_closed());
+        }
+        let len = chunk.len() as u64;
+        match self.inner.start_send(Ok(HttpRequestBody::new(chunk))) {
+            Ok(AsyncSink::Ready) => {
+                self.queued += len;
+                Ok(AsyncSink::Ready)
+            },
+            Ok(AsyncSink::NotReady(Ok(chunk))) => Ok(AsyncSink::NotReady(chunk.into_vec())),
+            Ok(AsyncSink::NotReady(Err(_))) => unreachable!("only body chunks are sent"),
+            Err(_) => Err(request_body_closed()),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), H2Error> {
+        self.inner.poll_complete().map_err(|_| request_body_closed())
+    }
+}
+
+/// A `Future` that resolves once the chunks that were sent through a `RequestBodySender` up to
+/// some point were pushed out onto the connection. Produced by `RequestBodySender::flush`.
+pub struct FlushBody<'a> {
+    sender: &'a mut RequestBodySender,
+    /// The number of body bytes that have to be sent out.
+    target: u64,
+}
+
+impl<'a> Future for FlushBody<'a> {
+    type Item = ();
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<(), H2Error> {
+        // First, the chunks have to make it to the transport at all...
+        if let Async::NotReady = self.sender.poll_complete()? {
+            return Ok(Async::NotReady);
+        }
+        // ...which then has to pack them into DATA frames.
+        if let Async::NotReady = self.sender.shared.poll_body_sent(self.target) {
+            return Ok(Async::NotReady);
+        }
+        if self.sender.shared.body_bytes_sent() < self.target {
+            // The request is done without the rest of the body ever being sent.
+            return Err(request_body_closed());
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+/// The error that body chunks fail with once the request no longer accepts them, i.e. because it
+/// completed or failed.
+fn request_body_closed() -> H2Error {
+    H2Error::Io(io::Error::new(io::ErrorKind::BrokenPipe,
+                               "the request no longer accepts body chunks"))
+}
+
+/// A request submitted through a `RemoteH2Client`, along with the channel that its response is to
+/// be delivered onto.
+struct RemoteRequest {
+    method: Vec<u8>,
+    path: Vec<u8>,
+    headers: Vec<StaticHeader>,
+    body: Option<Vec<u8>>,
+    sender: oneshot::Sender<Result<HttpResponse, H2Error>>,
+}
+
+/// A handle to an `H2Client` that can be sent to (and cloned across) other threads, for making
+/// requests on a connection whose event loop runs on a dedicated thread. Obtained from
+/// `H2Client::into_remote`.
+///
+/// The requests are passed over a channel to the event loop's thread, where they're made on the
+/// underlying `H2Client`; the returned futures resolve on the caller's side, once the full
+/// response is in (e.g. by calling `wait` on them). The connection stays open for as long as any
+/// of the handles are around.
+#[derive(Clone)]
+pub struct RemoteH2Client {
+    requests: mpsc::Sender<RemoteRequest>,
+}
+
+impl RemoteH2Client {
+    /// Issues a GET request to the server. See `H2Client::get`.
+    pub fn get(&self, path: &[u8]) -> Box<Future<Item=HttpResponse, Error=H2Error> + Send> {
+        self.request(b"GET", path, Vec::new(), None)
+    }
+
+    /// Issues a POST request carrying the given body to the server. See `H2Client::post`.
+    pub fn post(&self, path: &[u8], body: Vec<u8>)
+            -> Box<Future<Item=HttpResponse, Error=H2Error> + Send> {
+        self.request(b"POST", path, Vec::new(), Some(body))
+    }
+
+    /// Performs a request with the given method, headers and body. See `H2Client::request`.
+    ///
+    /// If the client's event loop is gone (or stops running before the response arrives), the
+    /// request fails with `H2Error::ConnectionLost`.
+    pub fn request(&self,
+                   method: &[u8],
+                   path: &[u8],
+                   headers: Vec<StaticHeader>,
+                   body: Option<Vec<u8>>)
+                   -> Box<Future<Item=HttpResponse, Error=H2Error> + Send> {
+        let (tx, rx) = oneshot::channel();
+        let request = RemoteRequest {
+            method: method.to_vec(),
+            path: path.to_vec(),
+            headers: headers,
+            body: body,
+            sender: tx,
+        };
+        let response = self.requests.clone().send(request)
+            .map_err(|_| H2Error::ConnectionLost)
+            .and_then(|_requests| {
+                rx.then(|res| {
+                    match res {
+                        Ok(res) => res,
+                        Err(oneshot::Canceled) => Err(H2Error::ConnectionLost),
+                    }
+                })
+            });
+
+        Box::new(response)
     }
 }
 
@@ -123,15 +1433,180 @@ pub struct H2Client {
     /// The authority header (nee Host). Specifies the host name that the HTTP requests will
     /// be directed at. This is distinct from the socket address.
     authority: Vec<u8>,
+    /// The state shared with the underlying transport, which keeps the connection statistics and
+    /// health up to date. Also used to wake up the transport when a request asks it to do
+    /// something.
+    connection: SharedConnection,
+    /// The handle of the event loop that the client runs on, used for spawning helper tasks.
+    handle: Handle,
+    /// Limits the number of outstanding requests, if the caller asked for it.
+    inflight: Option<InflightLimit>,
+    /// Set if the `:authority` of each request is checked against the connection's.
+    strict_authority: bool,
 }
 
-impl H2Client {
-    /// Creates a new `H2Client` from the given `TokioClient`.
-    fn new(inner: TokioClient, authority: Vec<u8>) -> H2Client {
-        H2Client {
-            inner: inner,
-            authority: authority,
+impl H2Client {
+    /// Creates a new `H2Client` from the given `TokioClient`.
+    fn new(inner: TokioClient,
+           authority: Vec<u8>,
+           connection: SharedConnection,
+           handle: Handle)
+           -> H2Client {
+        H2Client {
+            inner: inner,
+            authority: authority,
+            connection: connection,
+            handle: handle,
+            inflight: None,
+            strict_authority: false,
+        }
+    }
+
+    /// Limits the number of requests that the client may have outstanding at once to `max`,
+    /// independently of how many concurrent streams the server allows.
+    ///
+    /// A request is outstanding until its response body is done (or dropped). Once `max` of them
+    /// are, any further request is held back, without being sent, until a slot frees up. As the
+    /// returned future is what waits for the slot, such a request only gets sent once its future
+    /// is polled.
+    pub fn with_max_inflight(mut self, max: usize) -> H2Client {
+        self.inflight = Some(InflightLimit::new(max));
+        self
+    }
+
+    /// Sets whether the `:authority` of every request is checked against the authority that the
+    /// client connected to, catching misrouted requests before they hit the wire. Off by default,
+    /// in which case the `:authority` is sent as given.
+    ///
+    /// In strict mode, a request is only sent if its `:authority` has the same port as the
+    /// connection's and either the same host or, over TLS, a host that the server's certificate
+    /// is valid for (as per its subject alternative names, which are only known once the
+    /// handshake is done). Any other request fails with `H2Error::InvalidRequest`, without being
+    /// sent. The `:authority` of the requests that do get sent is normalized to lowercase.
+    pub fn with_strict_authority(mut self, strict: bool) -> H2Client {
+        self.strict_authority = strict;
+        self
+    }
+
+    /// Returns a snapshot of the statistics of the underlying HTTP/2 connection.
+    pub fn stats(&self) -> H2Stats {
+        self.connection.stats.borrow().clone()
+    }
+
+    /// Returns the parameters that the connection's TLS session was established with (its
+    /// version, cipher suite and ALPN protocol), e.g. for auditing. `None` for cleartext
+    /// connections, as well as until the handshake is done.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.connection.tls_info()
+    }
+
+    /// Returns the number of bytes of request body DATA that the server currently allows on the
+    /// connection as a whole, as per its WINDOW_UPDATE frames, e.g. to tell whether a stalled
+    /// upload is held up by flow control.
+    ///
+    /// The windows are only tracked for diagnostics: a negative value means that more was sent
+    /// than the server allowed.
+    pub fn connection_send_window(&self) -> i64 {
+        self.connection.windows.borrow().connection_send()
+    }
+
+    /// Returns the number of bytes of response DATA that the server may currently send on the
+    /// connection as a whole, before it has to wait for the client to give back some window.
+    pub fn connection_recv_window(&self) -> i64 {
+        self.connection.windows.borrow().connection_recv()
+    }
+
+    /// Returns the flow control windows of the stream with the given ID (see
+    /// `H2RequestHandle::stream_id`), or `None` if no such stream is open.
+    pub fn stream_window(&self, stream_id: StreamId) -> Option<StreamWindows> {
+        self.connection.windows.borrow().stream(stream_id)
+    }
+
+    /// Returns a future that resolves once all the requests that are in flight at the time of the
+    /// call are done (i.e. their streams are closed), e.g. as a barrier between batches of
+    /// requests. Requests made after the call don't hold it up. It also resolves if the
+    /// connection gets closed, as that ends all of the requests too.
+    ///
+    /// A request also counts as done once its response future (and body) are dropped.
+    pub fn flush_inflight(&self) -> Box<Future<Item=(), Error=H2Error>> {
+        Box::new(FlushInflight {
+            connection: self.connection.clone(),
+            seq: self.connection.next_request_seq(),
+        })
+    }
+
+    /// Turns the client into a `RemoteH2Client`, which can make requests on the connection from
+    /// other threads. The client is moved into a task spawned on its event loop, which makes the
+    /// requests as they come in, so the event loop needs to keep running (e.g. on a dedicated
+    /// thread) for any of them to complete.
+    pub fn into_remote(self) -> RemoteH2Client {
+        let (tx, rx) = mpsc::channel(REMOTE_CHANNEL_CAPACITY);
+        let handle = self.handle.clone();
+        let mut client = self;
+        let serve = rx.for_each(move |request| {
+            let RemoteRequest { method, path, headers, body, sender } = request;
+            let response = client.request(&method, &path, headers, body)
+                .abort_on_drop(true)
+                .into_full_body_response();
+            client.handle.spawn(DeliverResponse {
+                response: response,
+                sender: Some(sender),
+            });
+            Ok(())
+        });
+        handle.spawn(serve);
+
+        RemoteH2Client {
+            requests: tx,
+        }
+    }
+
+    /// Returns whether the connection still looks usable for new requests, without sending
+    /// anything to the server.
+    ///
+    /// The connection is considered unhealthy once the server sent a GOAWAY, the socket hit an
+    /// EOF, or the connection failed with an error.
+    pub fn is_healthy(&self) -> bool {
+        self.connection.is_healthy()
+    }
+
+    /// Returns a future that resolves once the connection gets closed, with the reason why: the
+    /// server going away, the socket hitting an EOF or the connection failing with an error.
+    ///
+    /// This allows reacting to the loss of the connection (e.g. by reconnecting) right away,
+    /// instead of only once a request fails.
+    pub fn closed(&self) -> Box<Future<Item=ClosedReason, Error=()>> {
+        let connection = self.connection.clone();
+        Box::new(future::poll_fn(move || Ok(connection.poll_closed())))
+    }
+
+    /// Aborts the connection immediately, e.g. in response to a security event: every request in
+    /// flight fails with `H2Error::Aborted` without waiting for its response, the server is sent a
+    /// GOAWAY (as long as the socket still takes it) and the connection is torn down.
+    ///
+    /// Aborting a connection that is already closed (or broken) has no effect.
+    pub fn abort(&self) {
+        debug!("aborting the connection");
+        self.connection.request_abort();
+    }
+
+    /// Sends a PING to the server and resolves to the round-trip time once the server
+    /// acknowledges it.
+    ///
+    /// Each PING carries its own opaque data, so any number of them can be in flight at once. If
+    /// the connection is lost before the ACK arrives, the future fails with
+    /// `H2Error::ConnectionLost`.
+    pub fn ping(&mut self) -> Box<Future<Item=Duration, Error=H2Error>> {
+        if self.connection.is_closed() {
+            return Box::new(future::err(H2Error::ConnectionLost));
         }
+
+        let pong = self.connection.request_ping().then(|res| match res {
+            Ok(rtt) => rtt,
+            // The transport went away without resolving the PING.
+            Err(_canceled) => Err(H2Error::ConnectionLost),
+        });
+        Box::new(pong)
     }
 
     /// Connect to the given socket and yield a new `H2Client` that can be used to send HTTP/2
@@ -152,6 +1627,98 @@ impl H2Client {
             TlsConnector::<TcpStream>::new())
     }
 
+    /// Connect to the given socket over TLS, just like `connect`, but tune the resulting HTTP/2
+    /// connection with the given `ConnectionOptions`.
+    pub fn connect_with_options(authority: &str,
+                                socket_addr: &SocketAddr,
+                                handle: &Handle,
+                                options: ConnectionOptions)
+                                -> H2ClientNew<TlsConnector<TcpStream>> {
+        H2Client::with_connector_and_options(
+            authority,
+            socket_addr,
+            handle,
+            TlsConnector::<TcpStream>::new(),
+            options)
+    }
+
+    /// Connect to the given socket over TLS, just like `connect`, but use the given TLS
+    /// configuration for the handshake.
+    ///
+    /// This allows customizing things like the trusted root certificates, client certificates or
+    /// certificate verification. Whatever the configuration, the connector will offer `h2` over
+    /// ALPN and the connection will only be established if the server selects it.
+    ///
+    /// Returns a future that will resolve to the `H2Client`, or to an error if the TLS
+    /// configuration can't be turned into a connector.
+    pub fn connect_with_tls(authority: &str,
+                            socket_addr: &SocketAddr,
+                            handle: &Handle,
+                            tls_config: SslConnectorBuilder)
+                            -> Box<Future<Item=H2Client, Error=io::Error>> {
+        let ssl_connector = match make_connector_from(tls_config) {
+            Ok(ssl_connector) => ssl_connector,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        Box::new(H2Client::with_connector(
+            authority,
+            socket_addr,
+            handle,
+            TlsConnector::<TcpStream>::with_ssl_connector(ssl_connector)))
+    }
+
+    /// Connect to the given socket over TLS, just like `connect`, but offer exactly the given
+    /// protocols over ALPN instead of only `h2`.
+    ///
+    /// The list has to include `h2`. Whatever else is offered, the connection is only established
+    /// if the server selects `h2`; if it falls back to any other protocol (e.g. `http/1.1`), the
+    /// returned future resolves to an error naming the selected protocol.
+    pub fn connect_with_alpn(authority: &str,
+                             socket_addr: &SocketAddr,
+                             handle: &Handle,
+                             protocols: &[&[u8]])
+                             -> Box<Future<Item=H2Client, Error=io::Error>> {
+        let builder = match SslConnectorBuilder::new(SslMethod::tls()) {
+            Ok(builder) => builder,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let ssl_connector = match make_connector_with_alpn(builder, protocols) {
+            Ok(ssl_connector) => ssl_connector,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        Box::new(H2Client::with_connector(
+            authority,
+            socket_addr,
+            handle,
+            TlsConnector::<TcpStream>::with_ssl_connector(ssl_connector)))
+    }
+
+    /// Connect to the given socket over TLS, just like `connect`, but give up if the connection
+    /// isn't fully established within the given `timeout`.
+    ///
+    /// The timeout covers the whole connection establishment: the TCP connect, the TLS handshake
+    /// (including ALPN) and writing the client preface. If it elapses first, the returned future
+    /// resolves to an error of kind `io::ErrorKind::TimedOut`.
+    pub fn connect_with_timeout(authority: &str,
+                                socket_addr: &SocketAddr,
+                                handle: &Handle,
+                                timeout: Duration)
+                                -> Box<Future<Item=H2Client, Error=io::Error>> {
+        let connect = H2Client::connect(authority, socket_addr, handle);
+        with_timeout(connect, timeout, handle)
+    }
+
+    /// Connect to the given socket over TLS, just like `connect`, but only resolve once the
+    /// server has acknowledged a PING over the new connection (see `H2ClientNew::confirmed`).
+    pub fn connect_confirmed(authority: &str,
+                             socket_addr: &SocketAddr,
+                             handle: &Handle)
+                             -> Box<Future<Item=H2Client, Error=io::Error>> {
+        H2Client::connect(authority, socket_addr, handle).confirmed()
+    }
+
     /// Connect to the given socket and yield a new `H2Client` that can be used to send HTTP/2
     /// requests to this socket.
     ///
@@ -170,6 +1737,21 @@ impl H2Client {
             CleartextConnector::<TcpStream>::new())
     }
 
+    /// Connect to the given socket over cleartext TCP, just like `cleartext_connect`, but tune
+    /// the resulting HTTP/2 connection with the given `ConnectionOptions`.
+    pub fn cleartext_connect_with_options(authority: &str,
+                                          socket_addr: &SocketAddr,
+                                          handle: &Handle,
+                                          options: ConnectionOptions)
+                                          -> H2ClientNew<CleartextConnector<TcpStream>> {
+        H2Client::with_connector_and_options(
+            authority,
+            socket_addr,
+            handle,
+            CleartextConnector::<TcpStream>::new(),
+            options)
+    }
+
     /// Connect to the given socket and yield a new `H2Client` that can be used to send HTTP/2
     /// requests to this socket.
     ///
@@ -182,51 +1764,321 @@ impl H2Client {
         socket_addr: &SocketAddr,
         handle: &Handle,
         connector: Connector)
+        -> H2ClientNew<Connector>
+            where Connector: Service<Request=H2ConnectorParams<TcpStream>, Error=io::Error>,
+                  Connector::Response: Io {
+        H2Client::with_connector_and_options(
+            authority,
+            socket_addr,
+            handle,
+            connector,
+            ConnectionOptions::default())
+    }
+
+    /// Connect to the given socket using the provided `Connector`, just like `with_connector`,
+    /// but tune the resulting HTTP/2 connection with the given `ConnectionOptions`.
+    pub fn with_connector_and_options<Connector>(
+        authority: &str,
+        socket_addr: &SocketAddr,
+        handle: &Handle,
+        connector: Connector,
+        options: ConnectionOptions)
+        -> H2ClientNew<Connector>
+            where Connector: Service<Request=H2ConnectorParams<TcpStream>, Error=io::Error>,
+                  Connector::Response: Io {
+        H2Client::connect_proto(authority, vec![*socket_addr], handle, connector, options, false)
+    }
+
+    /// Connect over TLS, just like `connect`, but to the first of the given, already resolved,
+    /// addresses that accepts the connection. The addresses are tried in order, moving on to the
+    /// next one whenever the TCP connect fails; if none of them can be connected to, the returned
+    /// future resolves to the error of the last attempt. To race the addresses instead (e.g. the
+    /// IPv6 and IPv4 addresses of a dual-stack host), set the
+    /// `ConnectionOptions::connect_attempt_delay` via `with_connector_to_any`.
+    ///
+    /// This avoids resolving the authority on the event loop, as well as allows for any custom
+    /// resolution. The `authority` is still what is used for SNI and the `:authority` of the
+    /// requests.
+    pub fn connect_to_any(authority: &str,
+                          socket_addrs: &[SocketAddr],
+                          handle: &Handle)
+                          -> H2ClientNew<TlsConnector<TcpStream>> {
+        H2Client::with_connector_to_any(
+            authority,
+            socket_addrs,
+            handle,
+            TlsConnector::<TcpStream>::new(),
+            ConnectionOptions::default())
+    }
+
+    /// Connect over cleartext TCP, just like `cleartext_connect`, but to the first of the given
+    /// addresses that accepts the connection (see `connect_to_any`).
+    pub fn cleartext_connect_to_any(authority: &str,
+                                    socket_addrs: &[SocketAddr],
+                                    handle: &Handle)
+                                    -> H2ClientNew<CleartextConnector<TcpStream>> {
+        H2Client::with_connector_to_any(
+            authority,
+            socket_addrs,
+            handle,
+            CleartextConnector::<TcpStream>::new(),
+            ConnectionOptions::default())
+    }
+
+    /// Connect using the provided `Connector`, just like `with_connector_and_options`, but to the
+    /// first of the given addresses that accepts the connection (see `connect_to_any`).
+    pub fn with_connector_to_any<Connector>(
+        authority: &str,
+        socket_addrs: &[SocketAddr],
+        handle: &Handle,
+        connector: Connector,
+        options: ConnectionOptions)
+        -> H2ClientNew<Connector>
+            where Connector: Service<Request=H2ConnectorParams<TcpStream>, Error=io::Error>,
+                  Connector::Response: Io {
+        H2Client::connect_proto(authority, socket_addrs.to_vec(), handle, connector, options, false)
+    }
+
+    /// Connect to the given cleartext socket by upgrading from HTTP/1.1, for servers that don't
+    /// accept HTTP/2 with prior knowledge (see `cleartext_connect`).
+    ///
+    /// An HTTP/1.1 `GET /` request asking to upgrade to `h2c` is sent first. Once the server
+    /// responds with `101 Switching Protocols`, the connection continues as HTTP/2, with the
+    /// upgrade request counting as its first request: its response comes in on stream 1.
+    ///
+    /// Returns a future that resolves to the `H2Client` along with the response to the upgrade
+    /// request, or to an error if the server refuses to upgrade.
+    pub fn upgrade_connect(authority: &str,
+                           socket_addr: &SocketAddr,
+                           handle: &Handle)
+                           -> Box<Future<Item=(H2Client, H2RequestHandle), Error=io::Error>> {
+        let options = ConnectionOptions::default();
+        let connector = UpgradeConnector::<TcpStream>::new(initial_settings_payload(&options));
+        let connect = H2Client::connect_proto(
+            authority, vec![*socket_addr], handle, connector, options, true);
+
+        Box::new(connect.map(|mut client| {
+            let response = client.upgrade_request();
+            (client, response)
+        }))
+    }
+
+    /// Connects to the first of the given sockets that accepts the connection, using the provided
+    /// `Connector`. If the connector `upgrade`s the connection from HTTP/1.1, the client has to
+    /// issue its upgrade request first thing.
+    fn connect_proto<Connector>(
+        authority: &str,
+        socket_addrs: Vec<SocketAddr>,
+        handle: &Handle,
+        connector: Connector,
+        options: ConnectionOptions,
+        upgrade: bool)
         -> H2ClientNew<Connector>
             where Connector: Service<Request=H2ConnectorParams<TcpStream>, Error=io::Error>,
                   Connector::Response: Io {
 
+        let connection = SharedConnection::new();
         let proto = H2ClientTokioProto {
             connector: connector,
             authority: authority.into(),
+            connection: connection.clone(),
+            options: options,
+            upgrade: upgrade,
+            handle: handle.clone(),
+        };
+        let bind_handle = handle.clone();
+        let connect = ConnectAny::new(socket_addrs, proto.options.connect_attempt_delay, handle);
+        let connect = connect.and_then(move |socket| {
+            socket.set_nodelay(proto.options.tcp_nodelay)?;
+            Ok(BindClient::<StreamingMultiplex<RequestBodyStream>, TcpStream>::bind_client(
+                &proto, &bind_handle, socket))
+        });
+
+        H2ClientNew::new(Box::new(connect),
+                         authority.as_bytes().to_vec(),
+                         connection,
+                         handle.clone())
+    }
+
+    /// Yields a new `H2Client` that sends HTTP/2 requests over the given, already established,
+    /// `Io`.
+    ///
+    /// This allows using the client over transports that it does not natively know how to
+    /// establish, such as Unix domain sockets, proxy tunnels, or in-memory pipes. The `Io` is
+    /// assumed to already be able to carry HTTP/2 frames (i.e. no protocol negotiation is
+    /// performed); the client preface is written to it before any request is sent.
+    ///
+    /// The client is returned immediately. Any requests issued before the preface is written are
+    /// queued up until the connection is ready.
+    pub fn from_io<T>(io: T, authority: &str, handle: &Handle) -> H2Client
+            where T: 'static + Io {
+        let connection = SharedConnection::new();
+        let proto = H2ClientTokioProto {
+            connector: CleartextConnector::<T>::new(),
+            authority: authority.into(),
+            connection: connection.clone(),
+            options: ConnectionOptions::default(),
+            upgrade: false,
+            handle: handle.clone(),
         };
-        let client = TcpClient::<StreamingMultiplex<RequestBodyStream>, _>::new(proto);
-        let connect = client.connect(&socket_addr, &handle);
+        let client_proxy =
+            BindClient::<StreamingMultiplex<RequestBodyStream>, T>::bind_client(&proto, handle, io);
 
-        H2ClientNew::new(connect, authority.as_bytes().to_vec())
+        H2Client::new(client_proxy, authority.as_bytes().to_vec(), connection, handle.clone())
     }
 
     /// Issues a GET request to the server.
     ///
     /// Yields a future that resolves to an `HttpRequestHeaders` struct. This struct will carry
     /// both the response headers, as well as the response body.
-    pub fn get(&mut self, path: &[u8]) -> FutureH2Response {
+    pub fn get(&mut self, path: &[u8]) -> H2RequestHandle {
         self.request(b"GET", path, iter::empty(), None)
     }
 
+    /// Issues an `OPTIONS *` request to the server, i.e. one that asks about the capabilities of
+    /// the server as a whole, rather than those of any particular resource.
+    ///
+    /// The asterisk-form request target is only valid with the OPTIONS method; other requests
+    /// made with a `*` path fail with `H2Error::InvalidRequest`.
+    pub fn options_asterisk(&mut self) -> H2RequestHandle {
+        self.request(b"OPTIONS", b"*", iter::empty(), None)
+    }
+
+    /// Issues a GET request to the server, just like `get`, but delivers the full response onto
+    /// the given channel instead of returning a future, e.g. for code that can't hold on to the
+    /// future itself.
+    ///
+    /// The request is driven by a task spawned on the client's event loop. If the receiving end
+    /// of the channel is dropped before the response arrives, the request is canceled.
+    pub fn get_into(&mut self,
+                    path: &[u8],
+                    sender: oneshot::Sender<Result<HttpResponse, H2Error>>) {
+        let response = self.get(path).abort_on_drop(true).into_full_body_response();
+        self.handle.spawn(DeliverResponse {
+            response: response,
+            sender: Some(sender),
+        });
+    }
+
     /// Issues a POST request, carrying the given body.
-    pub fn post(&mut self, path: &[u8], body: Vec<u8>) -> FutureH2Response {
+    pub fn post(&mut self, path: &[u8], body: Vec<u8>) -> H2RequestHandle {
         self.request(b"POST", path, iter::empty(), Some(body))
     }
 
+    /// Issues a POST request, whose body is sent straight out of the given shared buffer (e.g. a
+    /// memory-mapped file), without copying it into the client's own buffers. See
+    /// `HttpRequestBody::from_shared`.
+    pub fn post_shared<B>(&mut self, path: &[u8], body: Arc<B>) -> H2RequestHandle
+            where B: 'static + AsRef<[u8]> + Send + Sync {
+        let mut request_headers = self.prepare_headers(b"POST", path, iter::empty());
+        let length = (*body).as_ref().len().to_string().into_bytes();
+        request_headers.headers.push(Header::new(b"content-length", length));
+        let body_stream = Body::from(HttpRequestBody::from_shared(body));
+        self.request_with_message(Message::WithBody(request_headers, body_stream))
+    }
+
+    /// Issues a POST request, whose body is read from the given `Stream` of byte chunks.
+    ///
+    /// The chunks are forwarded onto the request by a task spawned on the client's event loop.
+    /// Chunks are only pulled from the source as fast as the connection can send them out, so a
+    /// fast source does not end up buffered in memory while the flow control window is closed.
+    ///
+    /// Once the source ends, so does the request body. If the source fails, the request is
+    /// aborted by resetting its stream.
+    pub fn post_stream<S>(&mut self, path: &[u8], body: S) -> H2RequestHandle
+            where S: 'static + Stream<Item=Vec<u8>, Error=io::Error> {
+        let (response, tx) = self.streaming_request(b"POST", path, iter::empty());
+        self.forward_body(&response, tx, body);
+
+        response
+    }
+
+    /// Issues a POST request, whose body is read from the given blocking `Read` source (e.g. a
+    /// file), `chunk_size` bytes at a time.
+    ///
+    /// As blocking reads can't be done on the event loop, the source is read on a separate worker
+    /// thread, which hands the chunks over through a bounded channel. Only a few chunks are ever
+    /// buffered: the source is read only as fast as the request body can be sent out.
+    ///
+    /// The request body ends once the source hits an EOF. If reading fails, the request is
+    /// aborted by resetting its stream.
+    ///
+    /// Every call spawns a new OS thread, and there is no limit on how many of them may be around
+    /// at a time, so issuing many of these requests at once is best avoided (`post_stream` can
+    /// be used with a source driven by a thread pool instead). The thread is detached: once the
+    /// request is dropped, canceled or reset, the thread exits the next time it hands over a
+    /// chunk, i.e. after its current read returns. A read that blocks forever keeps the thread
+    /// (and the source) around for good.
+    pub fn post_reader<R>(&mut self, path: &[u8], reader: R, chunk_size: usize) -> H2RequestHandle
+            where R: 'static + Read + Send {
+        let (tx, rx) = mpsc::channel(READER_CHANNEL_CAPACITY);
+        thread::spawn(move || read_chunks(reader, chunk_size, tx));
+
+        let chunks = rx.then(|res| {
+            match res {
+                Ok(chunk) => chunk,
+                Err(()) => Err(io::Error::new(io::ErrorKind::Other, "the reader thread is gone")),
+            }
+        });
+        self.post_stream(path, chunks)
+    }
+
     /// Perform a request, providing manually the request method, headers, and body.
+    ///
+    /// If the headers contain an `:authority` pseudo-header, it is used instead of the authority
+    /// that the client connected to (see `RequestBuilder::authority`).
     pub fn request<I>(&mut self,
                       method: &[u8],
                       path: &[u8],
                       user_headers: I,
                       body: Option<Vec<u8>>)
-                      -> FutureH2Response
+                      -> H2RequestHandle
                       where I: IntoIterator<Item=StaticHeader> {
         let request_headers = self.prepare_headers(method, path, user_headers);
-        let tokio_message = match body {
-            None => Message::WithoutBody(request_headers),
-            Some(body) => {
-                let body_stream = Body::from(HttpRequestBody::new(body));
-                Message::WithBody(request_headers, body_stream)
-            },
+        self.request_with_message(message_with_body(request_headers, body))
+    }
+
+    /// Starts building a request with the given method and path, whose headers can then be added
+    /// through the returned `RequestBuilder`.
+    pub fn build_request(&mut self, method: &[u8], path: &[u8]) -> RequestBuilder {
+        RequestBuilder {
+            client: self,
+            method: method.to_vec(),
+            path: path.to_vec(),
+            headers: Vec::new(),
+            body: None,
+            timeout: None,
+            refused_retries: 0,
+            #[cfg(feature = "compression")]
+            encoding: None,
+            error: None,
+        }
+    }
+
+    /// Perform a request with exactly the given headers, pseudo-headers included, in the given
+    /// order.
+    ///
+    /// This is an escape hatch for testing how servers deal with unusual requests: no
+    /// pseudo-headers are added (not even `:authority`), no `content-length` is declared for the
+    /// body, and neither the headers nor the connection's limits on them are checked, so the
+    /// request can be malformed in any way. Servers are free to reject such a request, typically
+    /// by resetting its stream with `PROTOCOL_ERROR`.
+    pub fn raw_request(&mut self,
+                       headers: Vec<StaticHeader>,
+                       body: Option<Vec<u8>>)
+                       -> H2RequestHandle {
+        let mut headers = HttpRequestHeaders::with_headers(headers);
+        headers.raw = true;
+        let message = match body {
+            None => Message::WithoutBody(headers),
+            Some(body) => Message::WithBody(headers, Body::from(HttpRequestBody::new(body))),
         };
 
-        self.request_with_message(tokio_message)
+        // Bypasses `request_with_message`, as even the `:authority` goes unchecked.
+        let shared = SharedRequest::new(self.connection.waker.clone());
+        let response_future =
+            submit_request(&self.inner, &self.connection, self.inflight.as_ref(), message, &shared);
+        H2RequestHandle::new(response_future, shared, self.handle.clone())
     }
 
     /// Perform a request, where the method and path are already provided, while the body should be
@@ -236,7 +2088,7 @@ impl H2Client {
                                 method: &[u8],
                                 path: &[u8],
                                 user_headers: I)
-                                -> (FutureH2Response, mpsc::Sender<Result<HttpRequestBody, io::Error>>)
+                                -> (H2RequestHandle, mpsc::Sender<Result<HttpRequestBody, io::Error>>)
                                 where I: IntoIterator<Item=StaticHeader> {
 
         trace!("starting streaming request");
@@ -246,26 +2098,202 @@ impl H2Client {
         (self.request_with_message(Message::WithBody(headers, body)), tx)
     }
 
+    /// Starts a request right away, without committing to whether it has a body.
+    ///
+    /// The headers are sent out as soon as possible, but without the END_STREAM flag, so the
+    /// body can be decided on later: chunks can be sent through the returned `RequestBodySender`,
+    /// while the request only ends once `RequestBodySender::finish` is called. If it is called
+    /// before any chunks are sent, the request simply ends up without a body.
+    pub fn start_request<I>(&mut self,
+                            method: &[u8],
+                            path: &[u8],
+                            user_headers: I)
+                            -> (H2RequestHandle, RequestBodySender)
+                            where I: IntoIterator<Item=StaticHeader> {
+        let (response, tx) = self.streaming_request(method, path, user_headers);
+        let shared = response.shared.clone();
+        (response, RequestBodySender { inner: tx, shared: shared, queued: 0 })
+    }
+
+    /// Opens a tunnel to the given authority (`host:port`) by issuing a CONNECT request.
+    ///
+    /// Returns a future that resolves once the server accepts the tunnel (i.e. responds with a
+    /// 2xx status). It resolves to a pair of a channel onto which the bytes that should be sent
+    /// through the tunnel can be posted and a `Stream` of the bytes received through the tunnel.
+    ///
+    /// The tunnel is mapped onto a single h2 stream, whose local end stays open until the sending
+    /// channel is dropped.
+    pub fn connect_tunnel(&mut self, authority: &[u8])
+            -> Box<Future<Item=(mpsc::Sender<Result<HttpRequestBody, io::Error>>,
+                                ResponseBodyStream),
+                          Error=H2Error>> {
+        trace!("opening a tunnel to {:?}", authority);
+        // A CONNECT request carries neither the `:scheme` nor the `:path` pseudo-headers.
+        let headers = HttpRequestHeaders::with_headers(vec![
+            Header::new(b":method", b"CONNECT"),
+            Header::new(b":authority", authority.to_vec()),
+        ]);
+
+        self.open_tunnel(headers)
+    }
+
+    /// Opens a tunnel for the given protocol (e.g. `websocket`) to the resource at the given
+    /// path, by issuing an extended CONNECT request (RFC 8441).
+    ///
+    /// The server needs to have advertised support for extended CONNECT (via its
+    /// SETTINGS_ENABLE_CONNECT_PROTOCOL setting); otherwise, the returned future fails right away,
+    /// without anything being sent.
+    ///
+    /// Resolves to the same pair as `connect_tunnel`, once the server accepts the tunnel.
+    pub fn extended_connect(&mut self, path: &[u8], protocol: &[u8])
+            -> Box<Future<Item=(mpsc::Sender<Result<HttpRequestBody, io::Error>>,
+                                ResponseBodyStream),
+                          Error=H2Error>> {
+        trace!("opening a {:?} tunnel to {:?}", protocol, path);
+        let protocol = Header::new(b":protocol", protocol.to_vec());
+        let headers = self.prepare_headers(b"CONNECT", path, iter::once(protocol));
+
+        self.open_tunnel(headers)
+    }
+
+    /// Forwards the chunks of the given `Stream` onto the body of the given request, by a task
+    /// spawned on the client's event loop. Chunks are only pulled from the source as fast as the
+    /// connection can send them out.
+    ///
+    /// If the source fails, the request is aborted by resetting its stream.
+    fn forward_body<S>(&self,
+                       response: &H2RequestHandle,
+                       tx: mpsc::Sender<Result<HttpRequestBody, io::Error>>,
+                       body: S)
+            where S: 'static + Stream<Item=Vec<u8>, Error=io::Error> {
+        let shared = response.shared.clone();
+        let chunks = body
+            .map(|chunk| Ok(HttpRequestBody::new(chunk)))
+            .map_err(move |err| {
+                debug!("request body source failed: {:?}", err);
+                shared.request_reset(ErrorCode::Cancel);
+            });
+        let forward = tx
+            .sink_map_err(|_| ())
+            .send_all(chunks)
+            .map(|_| ());
+        self.handle.spawn(forward);
+    }
+
+    /// Issues the given CONNECT request, which keeps its stream open in both directions, and
+    /// resolves once the server accepts the tunnel.
+    fn open_tunnel(&mut self, headers: HttpRequestHeaders)
+            -> Box<Future<Item=(mpsc::Sender<Result<HttpRequestBody, io::Error>>,
+                                ResponseBodyStream),
+                          Error=H2Error>> {
+        let (tx, body) = Body::pair();
+
+        let response = self.request_with_message(Message::WithBody(headers, body));
+        let tunnel = response.and_then(move |(headers, body)| {
+            match headers.status() {
+                Some(status) if status >= 200 && status < 300 => Ok((tx, body)),
+                _ => {
+                    debug!("tunnel rejected; headers={:?}", headers);
+                    let err = io::Error::new(io::ErrorKind::ConnectionRefused,
+                                             "the server did not accept the tunnel");
+                    Err(H2Error::Io(err))
+                },
+            }
+        });
+
+        Box::new(tunnel)
+    }
+
     /// Actually performs the full request. Avoids monomorphizing the entire code, but rather only
     /// the bit that requires the use of the IntoIterator trait, before passing off to this.
     fn request_with_message(&mut self,
-                            message: Message<HttpRequestHeaders, RequestBodyStream>)
-                            -> FutureH2Response {
-        let response_future = Service::call(&self.inner, message).map(|response| {
-            debug!("resolved response message");
-
-            match response {
-                Message::WithoutBody(resp @ HttpResponseHeaders { .. }) => {
-                    // If there's no body, just yield an empty body stream.
-                    (resp, ResponseBodyStream::new(Body::empty()))
-                },
-                Message::WithBody(resp @ HttpResponseHeaders { .. }, body) => {
-                    (resp, ResponseBodyStream::new(body))
+                            mut message: Message<HttpRequestHeaders, RequestBodyStream>)
+                            -> H2RequestHandle {
+        if self.strict_authority {
+            let headers = match message {
+                Message::WithoutBody(ref mut headers) | Message::WithBody(ref mut headers, _) => {
+                    headers
                 },
+            };
+            if let Err(err) = self.check_authority(&mut headers.headers) {
+                debug!("refusing to send the request: {}", err);
+                let err = H2Error::InvalidRequest(Http2Error::InvalidHeaders(err));
+                return self.failed_request(err);
             }
-        });
+        }
+
+        // Set up the state that the response future shares with the transport.
+        let shared = SharedRequest::new(self.connection.waker.clone());
+        let response_future =
+            submit_request(&self.inner, &self.connection, self.inflight.as_ref(), message, &shared);
+
+        H2RequestHandle::new(response_future, shared, self.handle.clone())
+    }
+
+    /// Performs a request, just like `request`, but re-submits it up to `max_attempts` times if
+    /// the server refuses its stream (see `RequestBuilder::retry_refused`).
+    fn request_retrying_refused(&mut self,
+                                method: &[u8],
+                                path: &[u8],
+                                user_headers: Vec<StaticHeader>,
+                                body: Option<Vec<u8>>,
+                                max_attempts: u32)
+                                -> H2RequestHandle {
+        let mut headers = self.prepare_headers(method, path, user_headers).headers;
+        if self.strict_authority {
+            // The retries carry the same normalized `:authority` as the first attempt. If it
+            // doesn't match, the first attempt fails right away and nothing is ever retried.
+            let _ = self.check_authority(&mut headers);
+        }
+        let message = message_with_body(HttpRequestHeaders::with_headers(headers.clone()),
+                                        body.clone());
+        let mut response = self.request_with_message(message);
+
+        let retry = RetryRefused {
+            response: response.inner,
+            client: self.inner.clone(),
+            connection: self.connection.clone(),
+            inflight: self.inflight.clone(),
+            shared: response.shared.clone(),
+            headers: headers,
+            body: body,
+            attempts_left: max_attempts,
+        };
+        response.inner = Box::new(retry);
+        response
+    }
+
+    /// Hands the request that the connection was upgraded for to the connection, which maps it
+    /// onto stream 1 instead of sending it again.
+    fn upgrade_request(&mut self) -> H2RequestHandle {
+        let mut headers = self.prepare_headers(b"GET", b"/", iter::empty());
+        headers.upgrade = true;
+        self.request_with_message(Message::WithoutBody(headers))
+    }
+
+    /// Checks that the `:authority` among the given headers is covered by the connection (see
+    /// `with_strict_authority`), normalizing it to lowercase.
+    fn check_authority(&self, headers: &mut Vec<StaticHeader>)
+            -> Result<(), InvalidHeadersError> {
+        let certificate_names = self.connection.certificate_names().unwrap_or_else(Vec::new);
+        for header in headers.iter_mut() {
+            if header.name() != b":authority" {
+                continue;
+            }
+            if !authority_matches(header.value(), &self.authority, &certificate_names) {
+                return Err(InvalidHeadersError::AuthorityMismatch);
+            }
+            let normalized = header.value().to_ascii_lowercase();
+            *header = Header::new(b":authority", normalized);
+        }
+
+        Ok(())
+    }
 
-        FutureH2Response::new(response_future.boxed())
+    /// Returns the response of a request that failed before it could be handed to the connection.
+    fn failed_request(&self, err: H2Error) -> H2RequestHandle {
+        let shared = SharedRequest::new(self.connection.waker.clone());
+        H2RequestHandle::new(Box::new(future::err(err)), shared, self.handle.clone())
     }
 
     /// Creates `HttpRequestHeaders` that will include appropriate pseudo-headers, as well as the
@@ -276,11 +2304,21 @@ impl H2Client {
                           user_headers: I)
                           -> HttpRequestHeaders
                           where I: IntoIterator<Item=StaticHeader> {
+        // An `:authority` given among the user headers overrides the one that the connection was
+        // established to, which allows a single connection to be reused for multiple hosts
+        // (connection coalescing).
+        let (authority, user_headers): (Vec<StaticHeader>, Vec<StaticHeader>) =
+            user_headers.into_iter().partition(|header| header.name() == b":authority");
+        let authority = match authority.into_iter().last() {
+            Some(header) => header,
+            None => Header::new(b":authority", self.authority.clone()),
+        };
+
         let mut headers = Vec::new();
         headers.extend(vec![
             Header::new(b":method", method.to_vec()),
             Header::new(b":path", path.to_vec()),
-            Header::new(b":authority", self.authority.clone()),
+            authority,
             Header::new(b":scheme", b"http"),
         ].into_iter());
         headers.extend(user_headers.into_iter());
@@ -289,6 +2327,289 @@ impl H2Client {
     }
 }
 
+/// Builds the message of a request whose body, if any, is known upfront.
+fn message_with_body(mut headers: HttpRequestHeaders, body: Option<Vec<u8>>)
+        -> Message<HttpRequestHeaders, RequestBodyStream> {
+    match body {
+        None => Message::WithoutBody(headers),
+        Some(body) => {
+            // The length of the body is known upfront, so it can be declared, unless the caller
+            // already did so.
+            let has_length = headers.headers.iter()
+                .any(|header| header.name() == b"content-length");
+            if !has_length {
+                let length = body.len().to_string().into_bytes();
+                headers.headers.push(Header::new(b"content-length", length));
+            }
+            let body_stream = Body::from(HttpRequestBody::new(body));
+            Message::WithBody(headers, body_stream)
+        },
+    }
+}
+
+/// Submits the request to the connection as an open request of the given `SharedRequest`,
+/// waiting for a slot to free up first if the client limits the number of outstanding requests.
+fn submit_request(client: &TokioClient,
+                  connection: &SharedConnection,
+                  inflight: Option<&InflightLimit>,
+                  mut message: Message<HttpRequestHeaders, RequestBodyStream>,
+                  shared: &SharedRequest)
+                  -> ResponseFuture {
+    shared.set_open(connection.open_request());
+    match message {
+        Message::WithoutBody(ref mut headers) | Message::WithBody(ref mut headers, _) => {
+            headers.shared = Some(shared.clone());
+        },
+    }
+
+    match inflight {
+        None => send_request(client, message, None),
+        Some(limit) => {
+            let client = client.clone();
+            let slot = AcquireInflightSlot { limit: limit.clone() };
+            Box::new(slot.and_then(move |permit| {
+                trace!("acquired a slot for an outstanding request");
+                send_request(&client, message, Some(permit))
+            }))
+        },
+    }
+}
+
+/// Hands the request off to the connection, returning the future of its response. The given
+/// permit, if any, is held until the response body is done.
+fn send_request(client: &TokioClient,
+                message: Message<HttpRequestHeaders, RequestBodyStream>,
+                permit: Option<InflightPermit>)
+                -> ResponseFuture {
+    let response_future = Service::call(client, message).map(move |response| {
+        debug!("resolved response message");
+
+        match response {
+            Message::WithoutBody(resp @ HttpResponseHeaders { .. }) => {
+                // If there's no body, just yield an empty body stream.
+                (resp, ResponseBodyStream::new(Body::empty(), permit, Some(0)))
+            },
+            Message::WithBody(resp @ HttpResponseHeaders { .. }, body) => {
+                let content_length = resp.content_length();
+                (resp, ResponseBodyStream::new(body, permit, content_length))
+            },
+        }
+    }).map_err(H2Error::from);
+
+    Box::new(response_future)
+}
+
+/// The number of chunks that `post_reader`'s worker thread may read ahead.
+const READER_CHANNEL_CAPACITY: usize = 2;
+
+/// The number of chunks that `copy_body_to`'s worker thread may fall behind by.
+const WRITER_CHANNEL_CAPACITY: usize = 2;
+
+/// The number of requests that `RemoteH2Client`s may submit before the event loop gets to them.
+const REMOTE_CHANNEL_CAPACITY: usize = 64;
+
+/// The most bytes that `into_full_body_response` allocates for a body upfront, based on its
+/// `content-length`. Larger bodies grow past it as they come in.
+const MAX_PREALLOCATED_BODY_LEN: usize = 1024 * 1024;
+
+/// Reads the given source in chunks of (at most) `chunk_size` bytes, sending each one onto the
+/// given channel, until the source hits an EOF or fails. Blocks the current thread, so it needs
+/// to run off the event loop.
+fn read_chunks<R: Read>(mut reader: R,
+                        chunk_size: usize,
+                        mut tx: mpsc::Sender<Result<Vec<u8>, io::Error>>) {
+    loop {
+        let mut buf = vec![0; chunk_size];
+        let chunk = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => {
+                buf.truncate(read);
+                Ok(buf)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+
+        let failed = chunk.is_err();
+        tx = match tx.send(chunk).wait() {
+            Ok(tx) => tx,
+            // The request is gone, so there's no point in reading any further.
+            Err(_) => break,
+        };
+        if failed {
+            break;
+        }
+    }
+}
+
+/// Writes each chunk received on the given channel to the given sink, until the channel is closed
+/// or writing fails, returning the number of bytes written. Blocks the current thread, so it needs
+/// to run off the event loop.
+fn write_chunks<W: Write>(mut writer: W, rx: mpsc::Receiver<Vec<u8>>) -> io::Result<u64> {
+    let mut total = 0;
+    for chunk in rx.wait() {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(()) => break,
+        };
+        writer.write_all(&chunk)?;
+        total += chunk.len() as u64;
+    }
+    writer.flush()?;
+
+    Ok(total)
+}
+
+/// The error that `copy_body_to` fails with if its worker thread went away without reporting
+/// how the writing went.
+fn writer_thread_gone() -> H2Error {
+    H2Error::Io(io::Error::new(io::ErrorKind::Other, "the writer thread is gone"))
+}
+
+/// A `Future` that connects to the first of a list of addresses that accepts a TCP connection.
+/// It fails with the error of the last attempt once all of them failed.
+///
+/// Without an attempt delay, the addresses are tried one after the other, in order. With one, the
+/// next address is also tried whenever the latest attempt doesn't succeed within the delay, so
+/// that multiple attempts race each other (see `ConnectionOptions::connect_attempt_delay`).
+struct ConnectAny {
+    /// The addresses that are yet to be tried.
+    addrs: vec::IntoIter<SocketAddr>,
+    /// The connection attempts that are in progress.
+    attempts: Vec<TcpStreamNew>,
+    /// How long the latest attempt is given before the next one is started alongside it.
+    attempt_delay: Option<Duration>,
+    /// Fires once the latest attempt has had its head start.
+    timer: Option<Timeout>,
+    /// The error that the most recent failed attempt failed with.
+    last_error: Option<io::Error>,
+    handle: Handle,
+}
+
+impl ConnectAny {
+    fn new(addrs: Vec<SocketAddr>, attempt_delay: Option<Duration>, handle: &Handle)
+            -> ConnectAny {
+        let addrs = match attempt_delay {
+            Some(_) => interleave_families(addrs),
+            None => addrs,
+        };
+        ConnectAny {
+            addrs: addrs.into_iter(),
+            attempts: Vec::new(),
+            attempt_delay: attempt_delay,
+            timer: None,
+            last_error: None,
+            handle: handle.clone(),
+        }
+    }
+
+    /// Whether the latest attempt has had its head start, so the next one may be started.
+    fn head_start_over(&mut self) -> bool {
+        if self.attempts.is_empty() {
+            return true;
+        }
+        match self.timer {
+            // A broken timer only means that the attempts don't get to race.
+            Some(ref mut timer) => match timer.poll() {
+                Ok(Async::NotReady) => false,
+                Ok(Async::Ready(())) | Err(_) => true,
+            },
+            None => false,
+        }
+    }
+}
+
+impl Future for ConnectAny {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, io::Error> {
+        loop {
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].poll() {
+                    Ok(Async::NotReady) => i += 1,
+                    // Dropping the remaining attempts cancels them.
+                    Ok(Async::Ready(socket)) => return Ok(Async::Ready(socket)),
+                    Err(e) => {
+                        debug!("failed to connect: {}", e);
+                        self.attempts.swap_remove(i);
+                        self.last_error = Some(e);
+                    },
+                }
+            }
+
+            if !self.head_start_over() {
+                return Ok(Async::NotReady);
+            }
+            match self.addrs.next() {
+                Some(addr) => {
+                    trace!("connecting to {}", addr);
+                    self.attempts.push(TcpStream::connect(&addr, &self.handle));
+                    self.timer = self.attempt_delay
+                        .and_then(|delay| Timeout::new(delay, &self.handle).ok());
+                },
+                None if self.attempts.is_empty() => {
+                    return Err(self.last_error.take().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+                    }));
+                },
+                None => {
+                    self.timer = None;
+                    return Ok(Async::NotReady);
+                },
+            }
+        }
+    }
+}
+
+/// Reorders the given addresses to alternate between the address families, starting with the
+/// family of the first one, while keeping their order within each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = addrs.first().map_or(false, |addr| addr.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (first, second) => {
+                interleaved.extend(first);
+                interleaved.extend(second);
+            },
+        }
+    }
+    interleaved
+}
+
+/// Races the given future against a timer that fires after `timeout`, in which case the returned
+/// future resolves to an error of kind `io::ErrorKind::TimedOut`. The given future is dropped
+/// (i.e. canceled) if the timer fires first.
+fn with_timeout<F>(future: F, timeout: Duration, handle: &Handle)
+        -> Box<Future<Item=F::Item, Error=io::Error>>
+        where F: 'static + Future<Error=io::Error>,
+              F::Item: 'static {
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let timer = timer.and_then(|_| {
+        debug!("connection establishment timed out");
+        future::err::<F::Item, _>(
+            io::Error::new(io::ErrorKind::TimedOut, "connection establishment timed out"))
+    });
+
+    let raced = future
+        .select(timer)
+        .map(|(item, _timer)| item)
+        .map_err(|(err, _timer)| err);
+
+    Box::new(raced)
+}
+
 /// A simple `Future` implementation that resolves once the HTTP/2 client connection is
 /// established.
 pub struct H2ClientNew<Connector>
@@ -296,24 +2617,62 @@ pub struct H2ClientNew<Connector>
               Connector::Response: 'static + Io {
 
     /// The future that resolves to a new Tokio ClientProxy.
-    inner: Connect<StreamingMultiplex<RequestBodyStream>, H2ClientTokioProto<Connector>>,
+    inner: Box<Future<Item=TokioClient, Error=io::Error>>,
 
     /// The authority that the new client will send requests to.
     authority: Option<Vec<u8>>,
+
+    /// The state that the new client will share with its transport.
+    connection: SharedConnection,
+
+    /// The handle of the event loop that the new client will run on.
+    handle: Handle,
+
+    _phantom: ::std::marker::PhantomData<Connector>,
 }
 
 impl<Connector> H2ClientNew<Connector>
         where Connector: 'static + Service<Request=H2ConnectorParams<TcpStream>, Error=io::Error>,
               Connector::Response: 'static + Io {
 
-    fn new(connect: Connect<StreamingMultiplex<RequestBodyStream>, H2ClientTokioProto<Connector>>,
-           authority: Vec<u8>)
+    fn new(connect: Box<Future<Item=TokioClient, Error=io::Error>>,
+           authority: Vec<u8>,
+           connection: SharedConnection,
+           handle: Handle)
            -> H2ClientNew<Connector> {
         H2ClientNew {
             inner: connect,
             authority: Some(authority),
+            connection: connection,
+            handle: handle,
+            _phantom: ::std::marker::PhantomData,
         }
     }
+
+    /// Turns the future into one that only resolves once the server has acknowledged a PING
+    /// over the new connection.
+    ///
+    /// On its own, the connection counts as established once the client preface is written,
+    /// which says nothing about whether the server accepted the client as an HTTP/2 peer. A
+    /// confirmed client, on the other hand, is known to be working end to end, e.g. before a
+    /// pool hands it out. If the connection is lost before the PING is acknowledged, the future
+    /// fails; a server that never answers stalls it, so it's best combined with a timeout.
+    pub fn confirmed(self) -> Box<Future<Item=H2Client, Error=io::Error>> {
+        let confirmed = self.and_then(|mut client| {
+            let pong = client.ping();
+            pong.then(move |res| match res {
+                Ok(rtt) => {
+                    debug!("connection confirmed; PING round trip {:?}", rtt);
+                    Ok(client)
+                },
+                Err(err) => {
+                    debug!("connection not confirmed: {}", err);
+                    Err(io::Error::from(err))
+                },
+            })
+        });
+        Box::new(confirmed)
+    }
 }
 
 impl<Connector> Future for H2ClientNew<Connector>
@@ -330,7 +2689,11 @@ impl<Connector> Future for H2ClientNew<Connector>
             Ok(Async::Ready(client_proxy)) => {
                 trace!("client connected");
                 let authority = self.authority.take().expect("H2ClientNew future polled again");
-                Ok(Async::Ready(H2Client::new(client_proxy, authority)))
+                let client = H2Client::new(client_proxy,
+                                           authority,
+                                           self.connection.clone(),
+                                           self.handle.clone());
+                Ok(Async::Ready(client))
             },
             Err(e) => Err(e),
         }
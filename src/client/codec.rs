@@ -0,0 +1,107 @@
+//! Contains the `BodyDecoder` trait, which lets callers split a response body into items of their
+//! own framing (e.g. length-prefixed or newline-delimited messages), regardless of how the body
+//! happens to be split up into DATA frames.
+
+use std::io;
+
+use futures::{Async, Poll};
+use futures::stream::Stream;
+
+use super::{H2Error, HttpResponseBody};
+
+/// Decodes the items of a response body out of the bytes received so far. Modeled after the
+/// `Decoder` of `tokio-io`, minus the dependency.
+pub trait BodyDecoder {
+    /// The type of the decoded items.
+    type Item;
+    /// The type of the decoding errors. Any error of the body itself is converted into it.
+    type Error: From<H2Error>;
+
+    /// Decodes the next item off the front of the given buffer, which holds all body bytes that
+    /// weren't consumed yet.
+    ///
+    /// If a whole item is available, it should be removed from the buffer and returned.
+    /// Otherwise, `Ok(None)` tells the `FramedBody` to wait for more bytes; the buffer is kept as
+    /// it was left.
+    fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<Self::Item>, Self::Error>;
+
+    /// Decodes the next item once the body has ended, i.e. when no more bytes will arrive.
+    /// Called until it returns `Ok(None)`, which ends the stream.
+    ///
+    /// By default, this decodes the remaining items using `decode`, and fails with an
+    /// `UnexpectedEof` IO error if the body ends in the middle of an item.
+    fn decode_eof(&mut self, buf: &mut Vec<u8>) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(item) => Ok(Some(item)),
+            None if buf.is_empty() => Ok(None),
+            None => {
+                let err = io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("the body ends {} bytes into an item", buf.len()));
+                Err(H2Error::Io(err).into())
+            },
+        }
+    }
+}
+
+/// A `futures::Stream` of the items that a `BodyDecoder` decodes from a response body,
+/// reassembling them across the body chunks. Produced by `ResponseBodyStream::framed`.
+pub struct FramedBody<S, D> {
+    inner: S,
+    decoder: D,
+    /// The bytes of the body that are not part of any item that was yielded yet.
+    buf: Vec<u8>,
+    /// Whether the body has ended.
+    eof: bool,
+}
+
+impl<S, D> FramedBody<S, D> {
+    /// Creates a new `FramedBody` that decodes the body chunks of the given stream.
+    pub fn new(inner: S, decoder: D) -> FramedBody<S, D> {
+        FramedBody {
+            inner: inner,
+            decoder: decoder,
+            buf: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Returns a reference to the decoder.
+    pub fn decoder(&self) -> &D {
+        &self.decoder
+    }
+
+    /// Returns the body bytes that were received, but not decoded into an item yet.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl<S, D> Stream for FramedBody<S, D>
+        where S: Stream<Item=HttpResponseBody, Error=H2Error>,
+              D: BodyDecoder {
+    type Item = D::Item;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if self.eof {
+                return self.decoder.decode_eof(&mut self.buf).map(Async::Ready);
+            }
+            if let Some(item) = self.decoder.decode(&mut self.buf)? {
+                return Ok(Async::Ready(Some(item)));
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(chunk)) => {
+                    if self.buf.is_empty() {
+                        self.buf = chunk.body;
+                    } else {
+                        self.buf.extend_from_slice(&chunk.body);
+                    }
+                },
+                Async::Ready(None) => self.eof = true,
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Implements the compression of request bodies, which is available with the `compression`
+//! feature.
+//!
+//! The body chunks are fed through a streaming encoder, so that a body can be compressed as it's
+//! being sent out, without having to be buffered in full first.
+//!
+//! Response bodies are never decompressed, so no `accept-encoding` header is added to requests
+//! either: advertising an encoding makes the server free to use it, and the caller would then get
+//! a body that it didn't ask to decode. Callers that decode responses themselves can set the
+//! header explicitly, naming the encodings that they handle.
+
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use futures::{Async, Poll};
+use futures::stream::Stream;
+
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+/// The encodings that request bodies can be compressed with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// The gzip format (RFC 1952).
+    Gzip,
+    /// The zlib format (RFC 1950), which is what HTTP calls `deflate`.
+    Deflate,
+}
+
+impl Encoding {
+    /// Returns the value of the `content-encoding` header that denotes the encoding.
+    pub fn header_value(&self) -> &'static [u8] {
+        match *self {
+            Encoding::Gzip => b"gzip",
+            Encoding::Deflate => b"deflate",
+        }
+    }
+}
+
+/// The buffer that an encoder writes its output to, which is drained after each input chunk.
+#[derive(Clone)]
+struct OutBuf(Rc<RefCell<Vec<u8>>>);
+
+impl OutBuf {
+    /// Takes everything that the encoder has output so far.
+    fn take(&self) -> Vec<u8> {
+        self.0.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Write for OutBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A streaming encoder for one of the supported `Encoding`s.
+enum Encoder {
+    Gzip(GzEncoder<OutBuf>),
+    Deflate(ZlibEncoder<OutBuf>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding, out: OutBuf) -> Encoder {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(out, Compression::Default)),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(out, Compression::Default)),
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match *self {
+            Encoder::Gzip(ref mut encoder) => encoder.write_all(data),
+            Encoder::Deflate(ref mut encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Flushes whatever the encoder still holds and writes out the end of the compressed stream
+    /// (e.g. the gzip trailer).
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish().map(|_| ()),
+            Encoder::Deflate(encoder) => encoder.finish().map(|_| ()),
+        }
+    }
+}
+
+/// A `Stream` adapter that compresses the body chunks yielded by the wrapped `Stream`.
+///
+/// The encoder buffers its input internally, so the compressed chunks don't line up with the
+/// original ones. Once the wrapped `Stream` ends, the rest of the compressed body is yielded as
+/// the final chunk.
+pub struct CompressedBody<S> {
+    inner: S,
+    /// `None` once the compressed stream has been finished.
+    encoder: Option<Encoder>,
+    out: OutBuf,
+}
+
+impl<S> CompressedBody<S> where S: Stream<Item=Vec<u8>, Error=io::Error> {
+    /// Creates a new `CompressedBody` that compresses the chunks of the given `Stream` with the
+    /// given encoding.
+    pub fn new(inner: S, encoding: Encoding) -> CompressedBody<S> {
+        let out = OutBuf(Rc::new(RefCell::new(Vec::new())));
+        CompressedBody {
+            inner: inner,
+            encoder: Some(Encoder::new(encoding, out.clone())),
+            out: out,
+        }
+    }
+}
+
+impl<S> Stream for CompressedBody<S> where S: Stream<Item=Vec<u8>, Error=io::Error> {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        loop {
+            if self.encoder.is_none() {
+                return Ok(Async::Ready(None));
+            }
+
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(chunk)) => {
+                    if let Some(ref mut encoder) = self.encoder {
+                        encoder.write_all(&chunk)?;
+                    }
+                    let compressed = self.out.take();
+                    // The encoder may well hold on to all of the input for now.
+                    if !compressed.is_empty() {
+                        return Ok(Async::Ready(Some(compressed)));
+                    }
+                },
+                Async::Ready(None) => {
+                    if let Some(encoder) = self.encoder.take() {
+                        encoder.finish()?;
+                    }
+                    return Ok(Async::Ready(Some(self.out.take())));
+                },
+            }
+        }
+    }
+}
+
+/// Compresses the whole of the given body at once.
+pub fn compress(body: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    let out = OutBuf(Rc::new(RefCell::new(Vec::new())));
+    let mut encoder = Encoder::new(encoding, out.clone());
+    encoder.write_all(body)?;
+    encoder.finish()?;
+
+    Ok(out.take())
+}
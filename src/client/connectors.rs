@@ -9,9 +9,13 @@
 //! want to serve as protocol negotiators.
 
 use client::tls::TlsH2Stream;
+use client::upgrade::{upgrade_async, UpgradedStream};
+use client::shared::SharedConnection;
 
 use std::io::{self};
 
+use openssl::ssl::SslConnector;
+
 use tokio_core::io::{Io};
 use tokio_service::Service;
 use futures::future::{self, Future};
@@ -20,12 +24,25 @@ use futures::future::{self, Future};
 /// connection over the given socket, while performing ALPN and ensuring that the server accepts
 /// the use of http/2 on the application layer.
 pub struct TlsConnector<I> where I: 'static + Io {
+    /// The `SslConnector` that performs the TLS handshake. If not set, a default one is created
+    /// for every connection.
+    connector: Option<SslConnector>,
     _phantom: ::std::marker::PhantomData<I>,
 }
 
 impl<I> TlsConnector<I> where I: 'static + Io {
     pub fn new() -> TlsConnector<I> {
         TlsConnector {
+            connector: None,
+            _phantom: ::std::marker::PhantomData,
+        }
+    }
+
+    /// Creates a new `TlsConnector` that performs the TLS handshake using the given
+    /// `SslConnector`, which needs to be configured to offer `h2` over ALPN.
+    pub fn with_ssl_connector(connector: SslConnector) -> TlsConnector<I> {
+        TlsConnector {
+            connector: Some(connector),
             _phantom: ::std::marker::PhantomData,
         }
     }
@@ -41,30 +58,75 @@ impl<I> Service for TlsConnector<I> where I: 'static + Io {
         use client::tls::connect_async;
 
         // Negotiate the application protocol using ALPN (and initialize the TLS session).
-        let conn = connect_async(&req.authority, req.io);
+        let conn = connect_async(self.connector.as_ref(), &req.authority, req.io);
+        let connection = req.connection;
         let transport = conn
             .and_then(|io| {
                 trace!("ALPN complete");
                 // Make sure ALPN yielded the correct protocol
                 future::result(TlsH2Stream::new(io))
+            })
+            .map(move |stream| {
+                // Any other hosts that the certificate covers can be reached over the connection
+                // too (see `H2Client::with_strict_authority`).
+                connection.set_certificate_names(stream.certificate_names());
+                connection.set_tls_info(stream.tls_info());
+                stream
             });
 
         Box::new(transport)
     }
 }
 
+/// A `Service` impl that can serve as an http/2 connector for cleartext servers that require the
+/// HTTP/1.1 Upgrade mechanism. It sends an HTTP/1.1 request asking to upgrade to `h2c` over the
+/// given socket and only succeeds if the server switches protocols.
+///
+/// The HTTP/1.1 request counts as the first HTTP/2 request on the connection (stream 1).
+pub struct UpgradeConnector<I> where I: 'static + Io {
+    /// The payload of the client's SETTINGS frame, which is sent along with the upgrade request.
+    settings: Vec<u8>,
+    _phantom: ::std::marker::PhantomData<I>,
+}
+
+impl<I> UpgradeConnector<I> where I: 'static + Io {
+    /// Creates a new `UpgradeConnector` that announces the given settings (serialized as the
+    /// payload of a SETTINGS frame) in the upgrade request.
+    pub fn new(settings: Vec<u8>) -> UpgradeConnector<I> {
+        UpgradeConnector {
+            settings: settings,
+            _phantom: ::std::marker::PhantomData,
+        }
+    }
+}
+
+impl<I> Service for UpgradeConnector<I> where I: 'static + Io {
+    type Request = H2ConnectorParams<I>;
+    type Response = UpgradedStream<I>;
+    type Error = io::Error;
+    type Future = Box<Future<Item=Self::Response, Error=Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        upgrade_async(&req.authority, &self.settings, req.io)
+    }
+}
+
 /// The parameters provided to the http/2 connector `Service`. Provides the authority that the
-/// client wants to communicate to and the raw socket.
+/// client wants to communicate to and the raw socket, along with the state of the connection
+/// that it's negotiating, in which anything learned during the negotiation can be recorded.
 pub struct H2ConnectorParams<I> where I: 'static + Io {
     pub authority: String,
     pub io: I,
+    pub connection: SharedConnection,
 }
 
 impl<I> H2ConnectorParams<I> where I: 'static + Io {
-    pub fn new<S: Into<String>>(authority: S, io: I) -> H2ConnectorParams<I> {
+    pub fn new<S: Into<String>>(authority: S, io: I, connection: SharedConnection)
+            -> H2ConnectorParams<I> {
         H2ConnectorParams {
             authority: authority.into(),
             io: io,
+            connection: connection,
         }
     }
 }
@@ -0,0 +1,167 @@
+//! Contains helpers for gRPC clients, which carry the outcome of each call in the `grpc-status` and
+//! `grpc-message` trailers of the response, rather than in its HTTP status, and frame the
+//! messages of the body with a length prefix of their own.
+
+use std::fmt;
+use std::mem;
+use std::str;
+
+use solicit::http::StaticHeader;
+
+use super::H2Error;
+use super::codec::{BodyDecoder, FramedBody};
+
+/// The gRPC status code of a successful call.
+pub const GRPC_STATUS_OK: u32 = 0;
+
+/// The gRPC status code of a call that failed for an unknown reason; used for responses that
+/// don't carry a (valid) status at all.
+pub const GRPC_STATUS_UNKNOWN: u32 = 2;
+
+/// The gRPC status code of a call that broke the protocol, e.g. with a malformed message.
+pub const GRPC_STATUS_INTERNAL: u32 = 13;
+
+/// The length of the prefix of each message: a compression flag and a 4-byte length.
+const MESSAGE_PREFIX_LEN: usize = 5;
+
+/// The outcome of a gRPC call, as carried by the `grpc-status` and `grpc-message` headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrpcStatus {
+    /// The status code, e.g. `0` (OK) or `5` (NOT_FOUND).
+    pub code: u32,
+    /// The (percent-decoded) message that describes the status, if the server sent one.
+    pub message: Option<String>,
+}
+
+impl GrpcStatus {
+    /// Extracts the gRPC status from the given headers. Returns `None` if there's no
+    /// `grpc-status` among them, or if it isn't a valid status code.
+    pub fn from_headers(headers: &[StaticHeader]) -> Option<GrpcStatus> {
+        let code = headers.iter()
+            .find(|header| header.name() == b"grpc-status")
+            .and_then(|header| str::from_utf8(header.value()).ok())
+            .and_then(|code| code.parse().ok());
+        code.map(|code| {
+            let message = headers.iter()
+                .find(|header| header.name() == b"grpc-message")
+                .map(|header| percent_decode(header.value()));
+            GrpcStatus {
+                code: code,
+                message: message,
+            }
+        })
+    }
+
+    /// Returns whether the status is OK, i.e. whether the call succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.code == GRPC_STATUS_OK
+    }
+}
+
+impl fmt::Display for GrpcStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.message {
+            Some(ref message) => write!(fmt, "gRPC status {}: {}", self.code, message),
+            None => write!(fmt, "gRPC status {}", self.code),
+        }
+    }
+}
+
+/// Returns the value of the given hex digit, if it is one.
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Decodes a percent-encoded `grpc-message`. Any `%` that isn't followed by two hex digits is
+/// kept as is, and invalid UTF-8 is replaced, as the message is purely informational.
+fn percent_decode(value: &[u8]) -> String {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut i = 0;
+    while i < value.len() {
+        if value[i] == b'%' && i + 2 < value.len() {
+            if let (Some(high), Some(low)) = (hex_value(value[i + 1]), hex_value(value[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(value[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A single message of a gRPC response body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrpcMessage {
+    /// Whether the message is compressed, using the encoding that the `grpc-encoding` header of
+    /// the response names.
+    pub compressed: bool,
+    /// The (serialized) message, without its prefix.
+    pub data: Vec<u8>,
+}
+
+/// A `BodyDecoder` that splits a response body into the gRPC messages that it carries. Used by
+/// `ResponseBodyStream::grpc_messages`.
+///
+/// A body that ends in the middle of a message, or a message with an invalid compression flag,
+/// fails the stream with `H2Error::Grpc`, carrying the INTERNAL status.
+#[derive(Debug, Default)]
+pub struct GrpcDecoder;
+
+impl GrpcDecoder {
+    /// Creates a new `GrpcDecoder`.
+    pub fn new() -> GrpcDecoder {
+        GrpcDecoder
+    }
+}
+
+impl BodyDecoder for GrpcDecoder {
+    type Item = GrpcMessage;
+    type Error = H2Error;
+
+    fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<GrpcMessage>, H2Error> {
+        if buf.len() < MESSAGE_PREFIX_LEN {
+            return Ok(None);
+        }
+        let compressed = match buf[0] {
+            0 => false,
+            1 => true,
+            flag => return Err(malformed(format!("invalid compression flag {}", flag))),
+        };
+        let len = buf[1..MESSAGE_PREFIX_LEN].iter()
+            .fold(0usize, |len, &byte| len << 8 | byte as usize);
+        if buf.len() < MESSAGE_PREFIX_LEN + len {
+            return Ok(None);
+        }
+
+        let rest = buf.split_off(MESSAGE_PREFIX_LEN + len);
+        let mut data = mem::replace(buf, rest);
+        data.drain(..MESSAGE_PREFIX_LEN);
+        Ok(Some(GrpcMessage {
+            compressed: compressed,
+            data: data,
+        }))
+    }
+
+    fn decode_eof(&mut self, buf: &mut Vec<u8>) -> Result<Option<GrpcMessage>, H2Error> {
+        match self.decode(buf)? {
+            Some(message) => Ok(Some(message)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(malformed(format!("the body ends {} bytes into a message", buf.len()))),
+        }
+    }
+}
+
+/// A `futures::Stream` of the gRPC messages of a response body, reassembled across the body
+/// chunks. Produced by `ResponseBodyStream::grpc_messages`.
+pub type GrpcMessages<S> = FramedBody<S, GrpcDecoder>;
+
+/// Returns the error of a body that isn't a valid sequence of gRPC messages.
+fn malformed(message: String) -> H2Error {
+    debug!("malformed gRPC body: {}", message);
+    H2Error::Grpc(GrpcStatus {
+        code: GRPC_STATUS_INTERNAL,
+        message: Some(message),
+    })
+}
@@ -0,0 +1,272 @@
+//! Contains helpers for checking that a set of request headers is well-formed, before it is handed
+//! off to the HTTP/2 connection.
+//!
+//! The rules that are checked are the ones laid out by RFC 7540, Section 8.1.2: header names must
+//! be lowercase, all pseudo-headers must precede regular headers, only the request pseudo-headers
+//! may be used (each at most once), the required ones must be present, connection-specific
+//! headers must not be used, and the `:path` must be a valid request target.
+
+use std::fmt;
+use std::str;
+use std::error::Error;
+
+use solicit::http::StaticHeader;
+
+/// An enum of errors that can be found when validating the headers of a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvalidHeadersError {
+    /// One of the pseudo-headers that every request needs to have is missing.
+    MissingPseudoHeader,
+    /// A pseudo-header that is not defined for requests was used (e.g. `:status`).
+    UnknownPseudoHeader,
+    /// The same pseudo-header was given more than once.
+    DuplicatePseudoHeader,
+    /// A pseudo-header was found after a regular header.
+    PseudoHeaderAfterRegularHeader,
+    /// A header name is empty.
+    EmptyHeaderName,
+    /// A header name contains uppercase characters.
+    UppercaseHeaderName,
+    /// A connection-specific header (e.g. `connection` or `transfer-encoding`) was used.
+    ConnectionSpecificHeader,
+    /// The headers are larger than the server is willing to accept (as advertised by its
+    /// SETTINGS_MAX_HEADER_LIST_SIZE setting).
+    HeaderListTooLarge,
+    /// The value of the `content-length` header is not a valid length.
+    InvalidContentLength,
+    /// The `:protocol` pseudo-header was used on a request other than CONNECT.
+    ProtocolWithoutConnect,
+    /// The `:path` is neither an absolute path (starting with `/`), nor the asterisk form (`*`)
+    /// on an OPTIONS request.
+    InvalidPath,
+    /// The `:authority` names a host that the connection wasn't established to (nor one that
+    /// the server's certificate covers), which is only checked in strict authority mode.
+    AuthorityMismatch,
+}
+
+impl fmt::Display for InvalidHeadersError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "InvalidHeadersError: {}", self.description())
+    }
+}
+
+impl Error for InvalidHeadersError {
+    fn description(&self) -> &str {
+        match *self {
+            InvalidHeadersError::MissingPseudoHeader =>
+                "a required request pseudo-header is missing",
+            InvalidHeadersError::UnknownPseudoHeader =>
+                "a pseudo-header that is not valid for requests was used",
+            InvalidHeadersError::DuplicatePseudoHeader =>
+                "a pseudo-header was given more than once",
+            InvalidHeadersError::PseudoHeaderAfterRegularHeader =>
+                "a pseudo-header was given after a regular header",
+            InvalidHeadersError::EmptyHeaderName =>
+                "a header name is empty",
+            InvalidHeadersError::UppercaseHeaderName =>
+                "a header name contains uppercase characters",
+            InvalidHeadersError::ConnectionSpecificHeader =>
+                "a connection-specific header was used",
+            InvalidHeadersError::HeaderListTooLarge =>
+                "the header list exceeds the size that the server accepts",
+            InvalidHeadersError::InvalidContentLength =>
+                "the content-length header is not a valid length",
+            InvalidHeadersError::ProtocolWithoutConnect =>
+                "the :protocol pseudo-header can only be used with CONNECT",
+            InvalidHeadersError::InvalidPath =>
+                "the :path is not a valid request target",
+            InvalidHeadersError::AuthorityMismatch =>
+                "the :authority does not match the host that the connection was made to",
+        }
+    }
+}
+
+/// The pseudo-headers that are valid in a request.
+const REQUEST_PSEUDO_HEADERS: &'static [&'static [u8]] = &[
+    b":method",
+    b":scheme",
+    b":authority",
+    b":path",
+    // Only valid on extended CONNECT requests (RFC 8441).
+    b":protocol",
+];
+
+/// The pseudo-headers that every request needs to have...
+const REQUIRED_PSEUDO_HEADERS: &'static [&'static [u8]] = &[
+    b":method",
+    b":scheme",
+    b":path",
+];
+
+/// ...except for (plain) CONNECT requests, which carry only the method and the authority. Extended
+/// CONNECT requests (which carry a `:protocol`) need all of the above and the authority.
+const CONNECT_REQUIRED_PSEUDO_HEADERS: &'static [&'static [u8]] = &[
+    b":method",
+    b":authority",
+];
+
+/// The pseudo-headers that every extended CONNECT request needs to have.
+const EXTENDED_CONNECT_REQUIRED_PSEUDO_HEADERS: &'static [&'static [u8]] = &[
+    b":method",
+    b":scheme",
+    b":authority",
+    b":path",
+    b":protocol",
+];
+
+/// The headers that are specific to a single (HTTP/1.1) connection and therefore must not be
+/// used in HTTP/2.
+const CONNECTION_SPECIFIC_HEADERS: &'static [&'static [u8]] = &[
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade",
+];
+
+/// Checks whether the given header name is valid in an HTTP/2 header block: it needs to be
+/// non-empty and must not contain any uppercase characters.
+pub fn validate_header_name(name: &[u8]) -> Result<(), InvalidHeadersError> {
+    if name.is_empty() {
+        return Err(InvalidHeadersError::EmptyHeaderName);
+    }
+    if name.iter().any(|&c| c >= b'A' && c <= b'Z') {
+        return Err(InvalidHeadersError::UppercaseHeaderName);
+    }
+
+    Ok(())
+}
+
+/// Checks whether the given `:path` is a valid request target for the given method: either an
+/// absolute path, optionally followed by a query (e.g. `/index.html?q=1`), or, only for OPTIONS
+/// requests, the asterisk form (`*`), which targets the server as a whole rather than any resource.
+pub fn validate_path(method: &[u8], path: &[u8]) -> Result<(), InvalidHeadersError> {
+    match path.first() {
+        Some(&b'/') => Ok(()),
+        Some(&b'*') if path.len() == 1 && method == b"OPTIONS" => Ok(()),
+        _ => Err(InvalidHeadersError::InvalidPath),
+    }
+}
+
+/// Checks whether a request with the given `:authority` can be sent over a connection that was
+/// established to the `connected` authority: the port needs to be the same, while the host needs
+/// to either be the same one, or one of the given DNS names that the server's certificate is
+/// valid for (which may be wildcards, e.g. `*.example.com`). Host names are case-insensitive.
+pub fn authority_matches(authority: &[u8], connected: &[u8], certificate_names: &[String]) -> bool {
+    let (host, port) = split_authority(authority);
+    let (connected_host, connected_port) = split_authority(connected);
+    if port != connected_port {
+        return false;
+    }
+
+    host.eq_ignore_ascii_case(connected_host)
+        || certificate_names.iter().any(|name| certificate_name_matches(name.as_bytes(), host))
+}
+
+/// Splits the given authority into its host and its port, if it has one. The colons within a
+/// bracketed IPv6 address don't count as a port separator.
+fn split_authority(authority: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match authority.iter().rposition(|&c| c == b':') {
+        Some(pos) if !authority[pos..].contains(&b']') => {
+            (&authority[..pos], Some(&authority[pos + 1..]))
+        },
+        _ => (authority, None),
+    }
+}
+
+/// Checks whether the given certificate DNS name covers the given host. A wildcard only stands
+/// in for the (whole) leftmost label of the host.
+fn certificate_name_matches(name: &[u8], host: &[u8]) -> bool {
+    if name.starts_with(b"*.") {
+        let suffix = &name[1..];
+        match host.iter().position(|&c| c == b'.') {
+            Some(pos) => pos > 0 && host[pos..].eq_ignore_ascii_case(suffix),
+            None => false,
+        }
+    } else {
+        name.eq_ignore_ascii_case(host)
+    }
+}
+
+/// Parses the value of a `content-length` header. Returns `None` if it's not a valid length.
+pub fn parse_content_length(value: &[u8]) -> Option<u64> {
+    if value.is_empty() || value.iter().any(|&c| c < b'0' || c > b'9') {
+        return None;
+    }
+    str::from_utf8(value).ok().and_then(|value| value.parse().ok())
+}
+
+/// Returns the size of the given header list, as defined for SETTINGS_MAX_HEADER_LIST_SIZE: the
+/// uncompressed length of all names and values, plus an overhead of 32 bytes for each header.
+pub fn header_list_size(headers: &[StaticHeader]) -> usize {
+    headers.iter()
+        .map(|header| header.name().len() + header.value().len() + 32)
+        .sum()
+}
+
+/// Checks whether the given set of request headers is well-formed.
+pub fn validate_request_headers(headers: &[StaticHeader]) -> Result<(), InvalidHeadersError> {
+    let mut seen_pseudo: Vec<&[u8]> = Vec::with_capacity(REQUEST_PSEUDO_HEADERS.len());
+    let mut seen_regular = false;
+    let mut is_connect = false;
+    let mut method: &[u8] = &[];
+    let mut path = None;
+
+    for header in headers {
+        let name = header.name();
+        validate_header_name(name)?;
+
+        if name[0] == b':' {
+            if seen_regular {
+                return Err(InvalidHeadersError::PseudoHeaderAfterRegularHeader);
+            }
+            if !REQUEST_PSEUDO_HEADERS.iter().any(|&pseudo| pseudo == name) {
+                return Err(InvalidHeadersError::UnknownPseudoHeader);
+            }
+            if seen_pseudo.contains(&name) {
+                return Err(InvalidHeadersError::DuplicatePseudoHeader);
+            }
+            if name == b":method" {
+                method = header.value();
+                is_connect = method == b"CONNECT";
+            }
+            if name == b":path" {
+                path = Some(header.value());
+            }
+            seen_pseudo.push(name);
+        } else {
+            seen_regular = true;
+            if CONNECTION_SPECIFIC_HEADERS.iter().any(|&forbidden| forbidden == name) {
+                return Err(InvalidHeadersError::ConnectionSpecificHeader);
+            }
+            // The only allowed value of the `te` header is "trailers".
+            if name == b"te" && header.value() != b"trailers" {
+                return Err(InvalidHeadersError::ConnectionSpecificHeader);
+            }
+            if name == b"content-length" && parse_content_length(header.value()).is_none() {
+                return Err(InvalidHeadersError::InvalidContentLength);
+            }
+        }
+    }
+
+    let is_extended_connect = seen_pseudo.contains(&&b":protocol"[..]);
+    if is_extended_connect && !is_connect {
+        return Err(InvalidHeadersError::ProtocolWithoutConnect);
+    }
+
+    let required = if is_extended_connect {
+        EXTENDED_CONNECT_REQUIRED_PSEUDO_HEADERS
+    } else if is_connect {
+        CONNECT_REQUIRED_PSEUDO_HEADERS
+    } else {
+        REQUIRED_PSEUDO_HEADERS
+    };
+    if required.iter().any(|name| !seen_pseudo.contains(name)) {
+        return Err(InvalidHeadersError::MissingPseudoHeader);
+    }
+    if let Some(path) = path {
+        validate_path(method, path)?;
+    }
+
+    Ok(())
+}
@@ -4,16 +4,61 @@
 
 use std::io;
 use std::fmt;
+use std::str;
+use std::ascii;
 use std::error::Error;
+use std::sync::Arc;
 
-use solicit::http::{self as http2, StaticHeader};
+use solicit::http::{self as http2, StaticHeader, ErrorCode};
+
+use self::shared::{SharedRequest, BufferedResponse};
 
 mod tokio_layer;
 mod client_wrapper;
 mod connectors;
 mod tls;
+mod upgrade;
+mod stats;
+mod headers;
+mod shared;
+mod options;
+mod spans;
+mod grpc;
+mod codec;
+mod sse;
+mod reconnect;
+#[cfg(feature = "compression")]
+mod compression;
+#[cfg(test)]
+mod tests;
 
-pub use self::client_wrapper::H2Client;
+pub use self::client_wrapper::{
+    H2Client,
+    H2RequestHandle,
+    RemoteH2Client,
+    RequestBodySender,
+    FlushBody,
+    RequestBuilder,
+    GuardedBodyStream,
+    TaggedResponse,
+    TaggedError,
+};
+pub use self::stats::{H2Stats, StreamWindows};
+pub use self::tls::TlsInfo;
+pub use self::grpc::{GrpcStatus, GrpcMessage, GrpcMessages, GrpcDecoder};
+pub use self::codec::{BodyDecoder, FramedBody};
+pub use self::sse::{SseEvent, SseDecoder};
+pub use self::headers::InvalidHeadersError;
+pub use self::reconnect::{ReconnectingClient, BackoffPolicy, RoutedRequest};
+pub use self::options::{
+    ConnectionOptions,
+    FrameObserver,
+    FrameInfo,
+    FrameDirection,
+    StreamStartObserver,
+};
+#[cfg(feature = "compression")]
+pub use self::compression::Encoding;
 
 /// An enum of errors that can arise due to the Tokio layer becoming out-of-sync from the http2
 /// session state.
@@ -25,6 +70,12 @@ pub enum TokioSyncError {
     /// An error that would arise if we got a Tokio request with an ID that doesn't have a matching
     /// HTTP/2 stream, when one would be expected.
     UnmatchedRequestId,
+    /// An error that would arise if we got a new Tokio request with the same ID as a request
+    /// whose HTTP/2 stream is still open.
+    DuplicateRequestId,
+    /// An error that would arise if the request that a connection was upgraded for (from
+    /// HTTP/1.1) came in on a connection that wasn't upgraded, or after other requests.
+    UnexpectedUpgradeRequest,
 }
 
 impl fmt::Display for TokioSyncError {
@@ -40,10 +91,107 @@ impl Error for TokioSyncError {
                 "received a data chunk for a request previously marked complete",
             TokioSyncError::UnmatchedRequestId =>
                 "received a request id that doesn't have a matching h2 stream",
+            TokioSyncError::DuplicateRequestId =>
+                "received a new request with the id of a request that is still in flight",
+            TokioSyncError::UnexpectedUpgradeRequest =>
+                "received an upgrade request that doesn't match the connection's upgrade",
         }
     }
 }
 
+/// Indicates whether a failed request can be retried without risking that it gets processed by
+/// the server twice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetrySafety {
+    /// The server is known not to have processed the request (e.g. the stream was refused or
+    /// was above the last stream ID of a GOAWAY), so it can safely be retried.
+    Safe,
+    /// The server may have (partially) processed the request.
+    Unknown,
+}
+
+/// The reason why an in-flight request was terminated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestFailureReason {
+    /// The server reset the request's stream with the given error code.
+    StreamReset(ErrorCode),
+    /// The server sent a GOAWAY with the given error code, which the request was not covered by.
+    GoAway(ErrorCode),
+    /// The connection was lost (e.g. the server closed the socket) while the request was still
+    /// in flight.
+    ConnectionLost,
+    /// The server closed the request's stream without ever sending the response headers.
+    NoResponse,
+    /// The client canceled the request, resetting its stream.
+    Canceled,
+    /// The client aborted the whole connection.
+    Aborted,
+    /// The response body turned out to be of a different length than its `content-length`
+    /// header declared, e.g. as it got truncated.
+    ResponseLengthMismatch {
+        /// The declared length.
+        expected: u64,
+        /// The number of body bytes that were actually received.
+        received: u64,
+    },
+}
+
+/// The error that an in-flight request fails with when the server terminates its stream before
+/// the response is complete.
+///
+/// It travels from the transport to the request wrapped in an `io::Error`, which
+/// `RequestFailure::from_io_error` can be used to get to. The request's response future (and body
+/// stream) finally fails with the matching `H2Error`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestFailure {
+    /// The reason why the request failed.
+    pub reason: RequestFailureReason,
+    /// Whether the request can safely be retried.
+    pub retry_safety: RetrySafety,
+}
+
+impl RequestFailure {
+    /// Returns the `RequestFailure` carried by the given `io::Error`, if any.
+    pub fn from_io_error(err: &io::Error) -> Option<&RequestFailure> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<RequestFailure>())
+    }
+
+    /// Returns whether the request that failed with the given `io::Error` can safely be retried.
+    /// Errors that don't carry a `RequestFailure` are never considered to be safe to retry.
+    pub fn retry_safety(err: &io::Error) -> RetrySafety {
+        RequestFailure::from_io_error(err)
+            .map(|failure| failure.retry_safety)
+            .unwrap_or(RetrySafety::Unknown)
+    }
+}
+
+impl fmt::Display for RequestFailure {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "RequestFailure: {} ({:?})", self.description(), self.reason)
+    }
+}
+
+impl Error for RequestFailure {
+    fn description(&self) -> &str {
+        match self.reason {
+            RequestFailureReason::StreamReset(_) => "the server reset the request's stream",
+            RequestFailureReason::GoAway(_) => "the server went away before processing the request",
+            RequestFailureReason::ConnectionLost => "the connection was lost",
+            RequestFailureReason::NoResponse => "the server closed the stream without a response",
+            RequestFailureReason::Canceled => "the request was canceled",
+            RequestFailureReason::Aborted => "the connection was aborted",
+            RequestFailureReason::ResponseLengthMismatch { .. } =>
+                "the response body does not match its content-length",
+        }
+    }
+}
+
+impl From<RequestFailure> for io::Error {
+    fn from(failure: RequestFailure) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, failure)
+    }
+}
+
 /// An enum of errors that can be raised by the http/2 Transport/Protocol.
 #[derive(Debug)]
 pub enum Http2Error {
@@ -53,6 +201,28 @@ pub enum Http2Error {
     IoError(io::Error),
     /// Errors due to Tokio and the Transport going out of sync.
     TokioSync(TokioSyncError),
+    /// The headers of a request were not well-formed, so the request was not sent.
+    InvalidHeaders(InvalidHeadersError),
+    /// The length of a request body did not match the `content-length` header of the request.
+    ContentLengthMismatch,
+    /// A request body grew larger than `ConnectionOptions::max_request_body_size` allows.
+    RequestBodyLimitExceeded,
+    /// An extended CONNECT request was made, but the server hasn't advertised support for it.
+    ExtendedConnectNotSupported,
+    /// The server's connection preface did not start with a SETTINGS frame.
+    MissingSettings,
+    /// The server did not acknowledge the client's SETTINGS in time.
+    SettingsTimeout,
+    /// The server sent more response headers on a stream than
+    /// `ConnectionOptions::max_response_headers` or `max_response_header_bytes` allow.
+    ResponseHeadersTooLarge,
+    /// The server sent a WINDOW_UPDATE with an increment of `0`.
+    ZeroWindowIncrement,
+    /// The server sent a WINDOW_UPDATE that grew a flow control window past its largest allowed
+    /// size (2^31 - 1).
+    WindowOverflow,
+    /// The connection was aborted by the client (see `H2Client::abort`).
+    Aborted,
 }
 
 impl fmt::Display for Http2Error {
@@ -64,6 +234,16 @@ impl fmt::Display for Http2Error {
                 Http2Error::Protocol(_) => "Protocol",
                 Http2Error::IoError(_) => "IoError",
                 Http2Error::TokioSync(_) => "TokioSync",
+                Http2Error::InvalidHeaders(_) => "InvalidHeaders",
+                Http2Error::ContentLengthMismatch => "ContentLengthMismatch",
+                Http2Error::RequestBodyLimitExceeded => "RequestBodyLimitExceeded",
+                Http2Error::ExtendedConnectNotSupported => "ExtendedConnectNotSupported",
+                Http2Error::MissingSettings => "MissingSettings",
+                Http2Error::SettingsTimeout => "SettingsTimeout",
+                Http2Error::ResponseHeadersTooLarge => "ResponseHeadersTooLarge",
+                Http2Error::ZeroWindowIncrement => "ZeroWindowIncrement",
+                Http2Error::WindowOverflow => "WindowOverflow",
+                Http2Error::Aborted => "Aborted",
             },
             self.description())
     }
@@ -75,6 +255,24 @@ impl Error for Http2Error {
             Http2Error::Protocol(ref err) => err.description(),
             Http2Error::IoError(ref err) => err.description(),
             Http2Error::TokioSync(ref err) => err.description(),
+            Http2Error::InvalidHeaders(ref err) => err.description(),
+            Http2Error::ContentLengthMismatch =>
+                "the length of the request body does not match its content-length header",
+            Http2Error::RequestBodyLimitExceeded =>
+                "the request body exceeded the size limit",
+            Http2Error::ExtendedConnectNotSupported =>
+                "the server does not support extended CONNECT requests",
+            Http2Error::MissingSettings =>
+                "the server did not start the connection with a SETTINGS frame",
+            Http2Error::SettingsTimeout =>
+                "the server did not acknowledge the client's SETTINGS in time",
+            Http2Error::ResponseHeadersTooLarge =>
+                "the server sent more response headers than allowed",
+            Http2Error::ZeroWindowIncrement =>
+                "the server sent a WINDOW_UPDATE with a zero increment",
+            Http2Error::WindowOverflow =>
+                "the server grew a flow control window past its maximum size",
+            Http2Error::Aborted => "the connection was aborted",
         }
     }
 }
@@ -85,6 +283,12 @@ impl From<http2::HttpError> for Http2Error {
     }
 }
 
+impl From<InvalidHeadersError> for Http2Error {
+    fn from(err: InvalidHeadersError) -> Http2Error {
+        Http2Error::InvalidHeaders(err)
+    }
+}
+
 impl From<io::Error> for Http2Error {
     fn from(err: io::Error) -> Http2Error {
         Http2Error::IoError(err)
@@ -104,6 +308,208 @@ impl From<Http2Error> for io::Error {
             Http2Error::Protocol(err) => io::Error::new(io::ErrorKind::Other, err),
             Http2Error::IoError(err) => err,
             Http2Error::TokioSync(err) => io::Error::new(io::ErrorKind::Other, err),
+            Http2Error::InvalidHeaders(err) => io::Error::new(io::ErrorKind::InvalidInput, err),
+            Http2Error::ContentLengthMismatch => {
+                io::Error::new(io::ErrorKind::InvalidInput, Http2Error::ContentLengthMismatch)
+            },
+            Http2Error::RequestBodyLimitExceeded => {
+                io::Error::new(io::ErrorKind::InvalidInput, Http2Error::RequestBodyLimitExceeded)
+            },
+            Http2Error::ExtendedConnectNotSupported => {
+                io::Error::new(io::ErrorKind::Other, Http2Error::ExtendedConnectNotSupported)
+            },
+            Http2Error::MissingSettings => {
+                io::Error::new(io::ErrorKind::InvalidData, Http2Error::MissingSettings)
+            },
+            Http2Error::SettingsTimeout => {
+                io::Error::new(io::ErrorKind::Other, Http2Error::SettingsTimeout)
+            },
+            Http2Error::ResponseHeadersTooLarge => {
+                io::Error::new(io::ErrorKind::InvalidData, Http2Error::ResponseHeadersTooLarge)
+            },
+            Http2Error::ZeroWindowIncrement => {
+                io::Error::new(io::ErrorKind::InvalidData, Http2Error::ZeroWindowIncrement)
+            },
+            Http2Error::WindowOverflow => {
+                io::Error::new(io::ErrorKind::InvalidData, Http2Error::WindowOverflow)
+            },
+            Http2Error::Aborted => {
+                io::Error::new(io::ErrorKind::ConnectionAborted, Http2Error::Aborted)
+            },
+        }
+    }
+}
+
+/// The reason why a connection got closed, as reported by `H2Client::closed`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClosedReason {
+    /// The server sent a GOAWAY with the given error code.
+    GoAway(ErrorCode),
+    /// The server closed the socket.
+    Eof,
+    /// The connection failed with an error, described by the given message.
+    Error(String),
+    /// The connection was torn down on the client's end.
+    Dropped,
+    /// The connection was aborted by the client (see `H2Client::abort`).
+    Aborted,
+}
+
+/// The error that requests fail with.
+///
+/// It tells apart errors that affect the whole connection, after which it can't be used anymore,
+/// from errors that affect only a single request (see `is_connection_error`).
+#[derive(Debug)]
+pub enum H2Error {
+    /// The server closed the connection by sending a GOAWAY with the given error code, before it
+    /// processed the request.
+    ConnectionClosed(ErrorCode),
+    /// The connection was lost (e.g. the socket hit an EOF) while the request was in flight.
+    ConnectionLost,
+    /// The connection was aborted by the client (see `H2Client::abort`) while the request was
+    /// in flight.
+    Aborted,
+    /// The server reset the request's stream with the given error code.
+    StreamReset(ErrorCode),
+    /// The request did not complete in time.
+    Timeout,
+    /// The response body exceeded the size limit that the caller imposed on it.
+    BodyLimitExceeded,
+    /// The server closed the request's stream without sending any response headers.
+    NoResponse,
+    /// The request was canceled by the client.
+    Canceled,
+    /// The response was polled for after it had already been handed out (see
+    /// `H2RequestHandle::headers`).
+    ResponseAlreadyTaken,
+    /// The server sent a response body of a different length than its `content-length` header
+    /// declared (e.g. a truncated download).
+    ResponseLengthMismatch {
+        /// The declared length.
+        expected: u64,
+        /// The number of body bytes that were actually received.
+        received: u64,
+    },
+    /// The gRPC call failed with the given (non-OK) status, or the response carried no status.
+    Grpc(GrpcStatus),
+    /// The request was rejected before being sent, e.g. because its headers were not valid.
+    InvalidRequest(Http2Error),
+    /// An IO (or HTTP/2 protocol) error on the connection.
+    Io(io::Error),
+}
+
+impl H2Error {
+    /// Returns whether the error affects the whole connection. If it does, the connection can't
+    /// be used for any more requests; otherwise, only the failed request is affected.
+    pub fn is_connection_error(&self) -> bool {
+        match *self {
+            H2Error::ConnectionClosed(_) | H2Error::ConnectionLost | H2Error::Aborted
+                | H2Error::Io(_) => true,
+            H2Error::StreamReset(_) | H2Error::Timeout | H2Error::BodyLimitExceeded
+                | H2Error::NoResponse | H2Error::Canceled | H2Error::ResponseAlreadyTaken
+                | H2Error::ResponseLengthMismatch { .. } | H2Error::Grpc(_)
+                | H2Error::InvalidRequest(_) => false,
+        }
+    }
+
+    /// Returns whether the failed request can safely be retried.
+    pub fn retry_safety(&self) -> RetrySafety {
+        match *self {
+            // Only the requests that the server did not process are failed on a GOAWAY.
+            H2Error::ConnectionClosed(_) => RetrySafety::Safe,
+            H2Error::StreamReset(ErrorCode::RefusedStream) => RetrySafety::Safe,
+            // Nothing was sent for an invalid request, but it would just fail again.
+            _ => RetrySafety::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for H2Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            H2Error::ConnectionClosed(code) => {
+                write!(fmt, "H2Error: {} ({:?})", self.description(), code)
+            },
+            H2Error::StreamReset(code) => {
+                write!(fmt, "H2Error: {} ({:?})", self.description(), code)
+            },
+            H2Error::ResponseLengthMismatch { expected, received } => {
+                write!(fmt, "H2Error: {} (expected {} bytes, received {})",
+                       self.description(), expected, received)
+            },
+            H2Error::Grpc(ref status) => write!(fmt, "H2Error: {}", status),
+            H2Error::InvalidRequest(ref err) => write!(fmt, "H2Error: {}", err),
+            H2Error::Io(ref err) => write!(fmt, "H2Error: {}", err),
+            _ => write!(fmt, "H2Error: {}", self.description()),
+        }
+    }
+}
+
+impl Error for H2Error {
+    fn description(&self) -> &str {
+        match *self {
+            H2Error::ConnectionClosed(_) => "the server closed the connection",
+            H2Error::ConnectionLost => "the connection was lost",
+            H2Error::Aborted => "the connection was aborted",
+            H2Error::StreamReset(_) => "the server reset the request's stream",
+            H2Error::Timeout => "the request timed out",
+            H2Error::BodyLimitExceeded => "the response body exceeded the size limit",
+            H2Error::NoResponse => "the server closed the stream without a response",
+            H2Error::Canceled => "the request was canceled",
+            H2Error::ResponseAlreadyTaken => "the response was already taken",
+            H2Error::ResponseLengthMismatch { .. } =>
+                "the response body does not match its content-length",
+            H2Error::Grpc(_) => "the gRPC call failed",
+            H2Error::InvalidRequest(ref err) => err.description(),
+            H2Error::Io(ref err) => err.description(),
+        }
+    }
+}
+
+impl From<RequestFailure> for H2Error {
+    fn from(failure: RequestFailure) -> H2Error {
+        match failure.reason {
+            RequestFailureReason::StreamReset(code) => H2Error::StreamReset(code),
+            RequestFailureReason::GoAway(code) => H2Error::ConnectionClosed(code),
+            RequestFailureReason::ConnectionLost => H2Error::ConnectionLost,
+            RequestFailureReason::NoResponse => H2Error::NoResponse,
+            RequestFailureReason::Canceled => H2Error::Canceled,
+            RequestFailureReason::Aborted => H2Error::Aborted,
+            RequestFailureReason::ResponseLengthMismatch { expected, received } => {
+                H2Error::ResponseLengthMismatch { expected: expected, received: received }
+            },
+        }
+    }
+}
+
+// As Tokio requires the transport to use `io::Error`s, the errors of individual requests travel
+// wrapped in an `io::Error`. This recovers the original error, where there is one. Other
+// `TimedOut` errors, such as those of the connect timeout or of the socket itself, are not request
+// timeouts and stay `H2Error::Io`.
+impl From<io::Error> for H2Error {
+    fn from(err: io::Error) -> H2Error {
+        if let Some(&failure) = RequestFailure::from_io_error(&err) {
+            return failure.into();
+        }
+        let is_h2_error = err.get_ref().map_or(false, |inner| inner.is::<H2Error>());
+        if is_h2_error {
+            let inner = err.into_inner().expect("the error was just inspected");
+            return *inner.downcast::<H2Error>().expect("the error was just inspected");
+        }
+
+        H2Error::Io(err)
+    }
+}
+
+impl From<H2Error> for io::Error {
+    fn from(err: H2Error) -> io::Error {
+        match err {
+            H2Error::Io(err) => err,
+            H2Error::Timeout => io::Error::new(io::ErrorKind::TimedOut, H2Error::Timeout),
+            H2Error::InvalidRequest(err) => {
+                io::Error::new(io::ErrorKind::InvalidInput, H2Error::InvalidRequest(err))
+            },
+            err => io::Error::new(io::ErrorKind::Other, err),
         }
     }
 }
@@ -112,59 +518,278 @@ impl From<Http2Error> for io::Error {
 ///
 /// Theoretically, this same struct could be used regardless whether the underlying protocol is
 /// h2 or h11.
-#[derive(Debug)]
 pub struct HttpRequestHeaders {
     headers: Vec<StaticHeader>,
+    /// The state that the `H2Client` shares with the transport for this request, if any.
+    shared: Option<SharedRequest>,
+    /// Set for the request that the connection was upgraded for from HTTP/1.1. It was already
+    /// sent as part of the upgrade, so it isn't sent again: its response comes in on stream 1.
+    upgrade: bool,
+    /// Set for requests whose headers are sent exactly as given, without being validated (see
+    /// `H2Client::raw_request`).
+    raw: bool,
 }
 
 impl HttpRequestHeaders {
     pub fn new() -> HttpRequestHeaders {
         HttpRequestHeaders {
             headers: Vec::new(),
+            shared: None,
+            upgrade: false,
+            raw: false,
         }
     }
 
     pub fn with_headers(headers: Vec<StaticHeader>) -> HttpRequestHeaders {
         HttpRequestHeaders {
             headers: headers,
+            shared: None,
+            upgrade: false,
+            raw: false,
+        }
+    }
+}
+
+impl fmt::Debug for HttpRequestHeaders {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("HttpRequestHeaders")
+            .field("headers", &DebugHeaders(&self.headers))
+            .field("shared", &self.shared)
+            .finish()
+    }
+}
+
+/// The bytes of a chunk of a request body.
+enum BodyBytes {
+    Owned(Vec<u8>),
+    /// Bytes that are shared with the caller (e.g. a memory-mapped file), which are sent straight
+    /// out of the shared buffer, without ever being copied into one of the client's own.
+    Shared(Arc<AsRef<[u8]> + Send + Sync>),
+}
+
+impl AsRef<[u8]> for BodyBytes {
+    fn as_ref(&self) -> &[u8] {
+        match *self {
+            BodyBytes::Owned(ref bytes) => bytes,
+            BodyBytes::Shared(ref bytes) => (**bytes).as_ref(),
         }
     }
 }
 
 /// Represents a chunk of the body of an HTTP request.
 ///
-/// Currently requires the chunk to be owned.
-#[derive(Debug)]
+/// The chunk either owns its bytes or shares them with the caller (see `from_shared`).
 pub struct HttpRequestBody {
-    body: Vec<u8>,
+    body: BodyBytes,
 }
 
 impl HttpRequestBody {
     /// Create a new `HttpRequestBody` that will contain the bytes in the given `Vec`.
     pub fn new(body: Vec<u8>) -> HttpRequestBody {
         HttpRequestBody {
-            body: body,
+            body: BodyBytes::Owned(body),
+        }
+    }
+
+    /// Create a new `HttpRequestBody` whose bytes are read straight out of the given shared
+    /// buffer as they're sent, without being copied, e.g. for uploading a large memory-mapped
+    /// file in constant memory. The buffer is kept alive until the chunk has been sent.
+    pub fn from_shared<B>(body: Arc<B>) -> HttpRequestBody
+            where B: 'static + AsRef<[u8]> + Send + Sync {
+        HttpRequestBody {
+            body: BodyBytes::Shared(body),
+        }
+    }
+
+    /// Returns the number of bytes in the chunk.
+    pub fn len(&self) -> usize {
+        self.body.as_ref().len()
+    }
+
+    /// Returns whether the chunk is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Turns the chunk into an owned `Vec`, which copies the bytes of a shared chunk.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.body {
+            BodyBytes::Owned(bytes) => bytes,
+            BodyBytes::Shared(bytes) => (*bytes).as_ref().to_vec(),
         }
     }
 }
 
+impl fmt::Debug for HttpRequestBody {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "HttpRequestBody {{ {:?} }}", DebugBody(self.body.as_ref()))
+    }
+}
+
 /// The response to an HTTP request.
 ///
 /// Simply carries the response headers.
-#[derive(Debug)]
 pub struct HttpResponseHeaders {
     pub headers: Vec<StaticHeader>,
 }
 
+impl HttpResponseHeaders {
+    /// Returns the value of the `:status` pseudo-header, if it is present and a valid status code.
+    pub fn status(&self) -> Option<u16> {
+        self.headers.iter()
+            .find(|header| header.name() == b":status")
+            .and_then(|header| str::from_utf8(header.value()).ok())
+            .and_then(|status| status.parse().ok())
+    }
+
+    /// Returns the value of the `content-length` header, if it is present and a valid length.
+    pub fn content_length(&self) -> Option<u64> {
+        self.headers.iter()
+            .find(|header| header.name() == b"content-length")
+            .and_then(|header| headers::parse_content_length(header.value()))
+    }
+
+    /// Returns the gRPC status carried by the headers, if there is one. It's normally found in
+    /// the trailers, but "trailers-only" responses (e.g. immediate failures) carry it in the
+    /// headers instead.
+    pub fn grpc_status(&self) -> Option<GrpcStatus> {
+        GrpcStatus::from_headers(&self.headers)
+    }
+}
+
+impl fmt::Debug for HttpResponseHeaders {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("HttpResponseHeaders")
+            .field("headers", &DebugHeaders(&self.headers))
+            .finish()
+    }
+}
+
 /// A chunk of the response body.
-#[derive(Debug)]
+///
+/// Each chunk holds the data of exactly one DATA frame received from the server (without any
+/// padding): chunks are never merged together or split up. Protocols that care about frame
+/// boundaries can rely on this; everyone else can simply treat the body as a stream of bytes.
 pub struct HttpResponseBody {
     pub body: Vec<u8>,
+    /// Keeps the chunk counted among the connection's buffered response bytes until it's
+    /// consumed, if those are limited (see `ConnectionOptions::max_buffered_response_bytes`).
+    buffered: Option<BufferedResponse>,
 }
 
-/// The full response, including both all the headers (including pseudo-headers) and the full body.
+impl HttpResponseBody {
+    /// Creates a new `HttpResponseBody` that holds the given bytes.
+    pub fn new(body: Vec<u8>) -> HttpResponseBody {
+        HttpResponseBody {
+            body: body,
+            buffered: None,
+        }
+    }
+}
+
+impl fmt::Debug for HttpResponseBody {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "HttpResponseBody {{ {:?} }}", DebugBody(&self.body))
+    }
+}
+
+/// The events that make up a response, as yielded by the stream returned from
+/// `H2RequestHandle::into_event_stream`.
 #[derive(Debug)]
+pub enum ResponseEvent {
+    /// The response headers. Always the first event.
+    Headers(HttpResponseHeaders),
+    /// A chunk of the response body.
+    Body(HttpResponseBody),
+    /// The trailers of the response, if the server sent any. Always the last event.
+    Trailers(HttpResponseHeaders),
+}
+
+/// The full response, including both all the headers (including pseudo-headers) and the full body.
 pub struct HttpResponse {
     pub headers: Vec<StaticHeader>,
     pub body: Vec<u8>,
 }
+
+impl fmt::Debug for HttpResponse {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt,
+               "HttpResponse {{ headers: {:?}, body: {:?} }}",
+               DebugHeaders(&self.headers),
+               DebugBody(&self.body))
+    }
+}
+
+/// Bodies up to this size are printed out in full by the `Debug` impls; larger ones only have
+/// their size printed.
+const MAX_DEBUG_BODY_LEN: usize = 64;
+
+/// Formats a byte string as a (quoted) string: as-is if it's valid UTF-8, otherwise with all
+/// non-ASCII bytes hex-escaped.
+struct DebugBytes<'a>(&'a [u8]);
+
+impl<'a> fmt::Debug for DebugBytes<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match str::from_utf8(self.0) {
+            Ok(s) => write!(fmt, "{:?}", s),
+            Err(_) => {
+                write!(fmt, "\"")?;
+                for &b in self.0 {
+                    for c in ascii::escape_default(b) {
+                        write!(fmt, "{}", c as char)?;
+                    }
+                }
+                write!(fmt, "\"")
+            },
+        }
+    }
+}
+
+/// Formats a list of headers as a list of readable `name: value` pairs.
+struct DebugHeaders<'a>(&'a [StaticHeader]);
+
+impl<'a> fmt::Debug for DebugHeaders<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_map()
+            .entries(self.0.iter().map(|h| (DebugBytes(h.name()), DebugBytes(h.value()))))
+            .finish()
+    }
+}
+
+/// Formats a body as its size, along with the content itself if it's small enough.
+struct DebugBody<'a>(&'a [u8]);
+
+impl<'a> fmt::Debug for DebugBody<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.len() > MAX_DEBUG_BODY_LEN {
+            write!(fmt, "{} bytes", self.0.len())
+        } else {
+            write!(fmt, "{} bytes: {:?}", self.0.len(), DebugBytes(self.0))
+        }
+    }
+}
+
+/// The error that a capped full-body response resolves to.
+#[derive(Debug)]
+pub enum CappedBodyError {
+    /// The response could not be obtained, as the request failed.
+    Request(H2Error),
+    /// The response body exceeded the given limit. The response that was received up to that
+    /// point (i.e. the headers and the partial body) is available for diagnostics.
+    LimitExceeded(HttpResponse),
+}
+
+impl fmt::Display for CappedBodyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "CappedBodyError: {}", self.description())
+    }
+}
+
+impl Error for CappedBodyError {
+    fn description(&self) -> &str {
+        match *self {
+            CappedBodyError::Request(ref err) => err.description(),
+            CappedBodyError::LimitExceeded(_) => "the response body exceeded the size limit",
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Exposes the `ConnectionOptions`, which allow tuning the behavior of a single HTTP/2
+//! connection.
+
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use solicit::http::StreamId;
+
+/// The default `window_update_threshold`: half of the default initial flow control window.
+const DEFAULT_WINDOW_UPDATE_THRESHOLD: u32 = 65535 / 2;
+
+/// The default `read_buffer_size`: a single maximum-size (default) frame fits into it.
+const DEFAULT_READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// The default `max_coalesced_write`.
+const DEFAULT_MAX_COALESCED_WRITE: usize = 16 * 1024;
+
+/// Options that tune the behavior of an HTTP/2 connection.
+///
+/// Any options that aren't explicitly set keep their default values, e.g.
+/// `ConnectionOptions { window_update_threshold: 0, ..Default::default() }`.
+///
+/// There is no option for the size of the dynamic table of the HPACK encoder that compresses the
+/// request headers: it always has the protocol's default size of 4096 bytes, as the
+/// `HttpConnection` keeps its encoder to itself and an `hpack::Encoder` can't be resized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionOptions {
+    /// Invoked for every frame that is sent or received on the connection, e.g. for tracing the
+    /// connection at the protocol level. None is set by default.
+    pub frame_observer: Option<FrameObserver>,
+    /// Invoked as soon as a request is assigned its HTTP/2 stream ID, with the (Tokio-level)
+    /// request ID and the stream ID, e.g. for correlating the two in instrumentation. None is set
+    /// by default.
+    pub stream_start_observer: Option<StreamStartObserver>,
+    /// The number of bytes of the receive flow control window that need to be consumed before
+    /// they are given back to the server in a WINDOW_UPDATE frame. It applies separately to each
+    /// stream and to the connection as a whole.
+    ///
+    /// Smaller values mean more WINDOW_UPDATE frames, while a `0` sends one for every consumed
+    /// body chunk. It should be kept well below the initial window size (65535 bytes), as the
+    /// server can't send anything once the window is used up.
+    pub window_update_threshold: u32,
+    /// The most bytes that are read off the socket in a single read.
+    ///
+    /// On high-bandwidth connections, a larger buffer means fewer syscalls for the same amount of
+    /// data, at the cost of more memory held by each connection.
+    pub read_buffer_size: usize,
+    /// Whether to set `TCP_NODELAY` on the connection's socket, disabling Nagle's algorithm.
+    ///
+    /// On by default: HTTP/2 does its own framing, so holding back small frames (e.g. HEADERS or
+    /// WINDOW_UPDATEs) only adds latency.
+    pub tcp_nodelay: bool,
+    /// The most bytes of consecutive pending frames (e.g. a SETTINGS ACK, WINDOW_UPDATEs and the
+    /// HEADERS of a burst of requests) that are merged into a single write to the socket.
+    ///
+    /// Larger values mean fewer syscalls, at the cost of copying the frames into a shared buffer.
+    /// A `0` disables the coalescing, writing every frame on its own. A frame that is larger than
+    /// the limit is always written on its own.
+    pub max_coalesced_write: usize,
+    /// How long the server is given to acknowledge the client's initial SETTINGS, after which
+    /// the connection is failed with a SETTINGS_TIMEOUT error. Not enforced by default.
+    ///
+    /// Until the SETTINGS are acknowledged, the client can't be sure that the server applies them
+    /// (e.g. that it won't push any streams).
+    pub settings_ack_timeout: Option<Duration>,
+    /// The most bytes that the body of a single request may carry. Not limited by default.
+    ///
+    /// This bounds the memory held by a streaming request whose body is produced faster than it
+    /// can be sent (e.g. while the flow control window is closed). A body chunk that would take
+    /// the body past the limit fails the request with `Http2Error::RequestBodyLimitExceeded`
+    /// instead, resetting its stream.
+    pub max_request_body_size: Option<u64>,
+    /// The number of padding bytes added to each DATA frame that is sent, e.g. to obscure the
+    /// sizes of request bodies. No padding is added by default (`0`).
+    ///
+    /// The padding (along with the byte that declares its length) takes up room that would
+    /// otherwise go to body data, so each frame stays within the size limits, and it counts
+    /// against the server's flow control window just like the data does.
+    pub data_padding: u8,
+    /// When connecting to a list of addresses (e.g. `H2Client::connect_to_any`), how long each
+    /// connection attempt is given before the next address is tried in parallel ("Happy
+    /// Eyeballs", RFC 8305). The first attempt to succeed is used and the rest are canceled.
+    ///
+    /// With a delay, the addresses are also reordered to alternate between IPv6 and IPv4 (starting
+    /// with the family of the first one), so that a broken family only costs a single delay. Not
+    /// set by default, in which case the addresses are tried one after the other, in order.
+    pub connect_attempt_delay: Option<Duration>,
+    /// The most header fields that the responses on a single stream may carry in total, across
+    /// any interim responses, the final response and the trailers. Not limited by default.
+    ///
+    /// This guards against servers that send enormous numbers of headers to exhaust memory. As
+    /// the offending header block has already updated the HPACK state that all streams share,
+    /// exceeding the limit fails the whole connection with an ENHANCE_YOUR_CALM GOAWAY and
+    /// `Http2Error::ResponseHeadersTooLarge`.
+    pub max_response_headers: Option<usize>,
+    /// The most bytes that the decoded names and values of all the response headers on a single
+    /// stream may add up to, enforced just like `max_response_headers`. Not limited by default.
+    pub max_response_header_bytes: Option<usize>,
+    /// The most response body bytes that may be buffered across all the streams of the
+    /// connection, i.e. handed off by the connection, but not yet consumed by the application.
+    /// Not limited by default, in which case only each stream's flow control window bounds it.
+    ///
+    /// Once the limit is reached, the connection's flow control window is no longer given back to
+    /// the server until the application consumes (or drops) enough of the buffered chunks, which
+    /// holds back all of the streams at once.
+    pub max_buffered_response_bytes: Option<usize>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            window_update_threshold: DEFAULT_WINDOW_UPDATE_THRESHOLD,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            tcp_nodelay: true,
+            max_coalesced_write: DEFAULT_MAX_COALESCED_WRITE,
+            frame_observer: None,
+            stream_start_observer: None,
+            settings_ack_timeout: None,
+            max_request_body_size: None,
+            data_padding: 0,
+            connect_attempt_delay: None,
+            max_response_headers: None,
+            max_response_header_bytes: None,
+            max_buffered_response_bytes: None,
+        }
+    }
+}
+
+/// Whether a frame was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameDirection {
+    Sent,
+    Received,
+}
+
+/// A summary of a single frame, handed to a `FrameObserver`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameInfo {
+    pub direction: FrameDirection,
+    /// The type of the frame, as found in its header (e.g. `0x1` for HEADERS).
+    pub frame_type: u8,
+    pub flags: u8,
+    pub stream_id: StreamId,
+    /// The length of the frame's payload.
+    pub length: u32,
+}
+
+/// A callback that observes the frames of a connection. See `ConnectionOptions::frame_observer`.
+///
+/// It's invoked on the connection's event loop as frames are queued up for sending or read off
+/// the socket, so it should return quickly.
+#[derive(Clone)]
+pub struct FrameObserver(Rc<Fn(&FrameInfo)>);
+
+impl FrameObserver {
+    /// Creates a new `FrameObserver` that invokes the given function for every frame.
+    pub fn new<F>(observer: F) -> FrameObserver where F: Fn(&FrameInfo) + 'static {
+        FrameObserver(Rc::new(observer))
+    }
+
+    /// Reports the given frame to the observer.
+    #[inline]
+    pub fn observe(&self, frame: &FrameInfo) {
+        (self.0)(frame)
+    }
+}
+
+impl fmt::Debug for FrameObserver {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "FrameObserver")
+    }
+}
+
+impl PartialEq for FrameObserver {
+    /// Two observers are only equal if they're (clones of) the same one.
+    fn eq(&self, other: &FrameObserver) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// A callback that is told about every stream that is started on a connection. See
+/// `ConnectionOptions::stream_start_observer`.
+///
+/// It's invoked on the connection's event loop, right as the stream ID is assigned, so it should
+/// return quickly.
+#[derive(Clone)]
+pub struct StreamStartObserver(Rc<Fn(u64, StreamId)>);
+
+impl StreamStartObserver {
+    /// Creates a new `StreamStartObserver` that invokes the given function with the request ID
+    /// and the stream ID of every started stream.
+    pub fn new<F>(observer: F) -> StreamStartObserver where F: Fn(u64, StreamId) + 'static {
+        StreamStartObserver(Rc::new(observer))
+    }
+
+    /// Reports that the request with the given ID was assigned the given stream ID.
+    #[inline]
+    pub fn observe(&self, request_id: u64, stream_id: StreamId) {
+        (self.0)(request_id, stream_id)
+    }
+}
+
+impl fmt::Debug for StreamStartObserver {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "StreamStartObserver")
+    }
+}
+
+impl PartialEq for StreamStartObserver {
+    /// Two observers are only equal if they're (clones of) the same one.
+    fn eq(&self, other: &StreamStartObserver) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
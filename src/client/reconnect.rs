@@ -0,0 +1,380 @@
+//! Exposes the `ReconnectingClient`, which keeps a connection to a server available across GOAWAYs,
+//! EOFs and connection failures, by re-establishing it (with exponential backoff) whenever it's
+//! lost.
+//!
+//! The connection is supervised by a task spawned on the event loop, which watches the live
+//! connection through `H2Client::closed` (and its health, so that a GOAWAY is acted upon right
+//! away) and reconnects as soon as it's gone. Requests are routed to whichever connection is live
+//! at the time; while there's none, they wait for the next one to be established.
+
+use std::io;
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+use std::cmp;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use solicit::http::StaticHeader;
+
+use client::{H2Client, H2Error, H2RequestHandle, HttpResponseHeaders, RetrySafety, ClosedReason};
+use client::client_wrapper::ResponseBodyStream;
+
+/// The function that establishes a new connection.
+type Connect = Box<Fn(&Handle) -> Box<Future<Item=H2Client, Error=io::Error>>>;
+
+/// How a `ReconnectingClient` spaces out its attempts to (re-)establish the connection.
+///
+/// After each failed attempt, the client waits for `initial_delay`, multiplied by `multiplier`
+/// for every attempt that failed before it, but never longer than `max_delay`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackoffPolicy {
+    /// The delay after the first failed attempt.
+    pub initial_delay: Duration,
+    /// The longest delay between two attempts.
+    pub max_delay: Duration,
+    /// The factor that the delay grows by with each failed attempt.
+    pub multiplier: u32,
+    /// The fraction (between `0.0` and `1.0`) of each delay that is randomly taken off, so that
+    /// many clients that lost their connections at once don't all retry at once, too.
+    pub jitter: f64,
+    /// The most attempts in a row that may fail. Once they have, the requests waiting for the
+    /// connection fail with the error of the last attempt.
+    pub max_attempts: u32,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> BackoffPolicy {
+        BackoffPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2,
+            jitter: 0.2,
+            max_attempts: 10,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Returns the delay after the given number of failed attempts (at least one). The `random`
+    /// value (between `0.0` and `1.0`) determines how much of the jitter is applied.
+    fn delay(&self, failed_attempts: u32, random: f64) -> Duration {
+        let mut delay = self.initial_delay;
+        for _ in 1..failed_attempts {
+            delay = match delay.checked_mul(self.multiplier) {
+                Some(delay) if delay < self.max_delay => delay,
+                _ => self.max_delay,
+            };
+        }
+        let delay = cmp::min(delay, self.max_delay);
+
+        let millis = delay.as_secs() as f64 * 1000.0 + delay.subsec_nanos() as f64 / 1e6;
+        Duration::from_millis((millis * (1.0 - self.jitter * random)) as u64)
+    }
+}
+
+/// The connection of a `ReconnectingClient`.
+enum Connection {
+    /// The live connection.
+    Connected(H2Client),
+    /// The connection is being (re-)established.
+    Connecting,
+    /// The connection could not be established within the `BackoffPolicy`'s attempts; carries
+    /// the error of the last attempt.
+    Failed(io::ErrorKind, String),
+}
+
+/// The state that the `ReconnectingClient` handles share with the supervising task.
+struct Inner {
+    connection: RefCell<Connection>,
+    /// The tasks of the requests waiting for the connection to be established (or to fail).
+    waiting: RefCell<Vec<Task>>,
+    connect: Connect,
+    policy: BackoffPolicy,
+    handle: Handle,
+}
+
+impl Inner {
+    /// Replaces the connection, letting all the requests that wait for one know about it.
+    fn set_connection(&self, connection: Connection) {
+        *self.connection.borrow_mut() = connection;
+        for task in self.waiting.borrow_mut().drain(..) {
+            task.unpark();
+        }
+    }
+}
+
+/// An HTTP/2 client that transparently re-establishes its connection whenever it's lost (e.g.
+/// the server sent a GOAWAY or closed the socket), backing off between failed attempts as
+/// described by its `BackoffPolicy`.
+///
+/// New requests always go to the live connection, waiting for it if it's being re-established.
+/// A request that fails in a way that is known to be safe to retry (see `RetrySafety`), e.g. as
+/// the server went away before processing it, is retried once on the next connection. Any other
+/// failure, as well as the failure to establish a connection within the policy's attempts, is
+/// handed to the request; a request made after that starts a new round of attempts.
+#[derive(Clone)]
+pub struct ReconnectingClient {
+    inner: Rc<Inner>,
+}
+
+impl ReconnectingClient {
+    /// Creates a new `ReconnectingClient` that establishes each connection with the given
+    /// function, e.g. `|handle| Box::new(H2Client::cleartext_connect(authority, &addr, handle))`.
+    ///
+    /// The first connection is established right away.
+    pub fn new<F>(connect: F, policy: BackoffPolicy, handle: &Handle) -> ReconnectingClient
+            where F: 'static + Fn(&Handle) -> Box<Future<Item=H2Client, Error=io::Error>> {
+        let client = ReconnectingClient {
+            inner: Rc::new(Inner {
+                connection: RefCell::new(Connection::Connecting),
+                waiting: RefCell::new(Vec::new()),
+                connect: Box::new(connect),
+                policy: policy,
+                handle: handle.clone(),
+            }),
+        };
+        client.supervise();
+
+        client
+    }
+
+    /// Creates a new `ReconnectingClient` that connects to the given socket over TLS (see
+    /// `H2Client::connect`), giving each attempt at most `connect_timeout` to complete.
+    pub fn connect(authority: &str,
+                   socket_addr: &SocketAddr,
+                   handle: &Handle,
+                   connect_timeout: Duration,
+                   policy: BackoffPolicy)
+                   -> ReconnectingClient {
+        let authority = authority.to_owned();
+        let socket_addr = *socket_addr;
+        ReconnectingClient::new(
+            move |handle| {
+                H2Client::connect_with_timeout(&authority, &socket_addr, handle, connect_timeout)
+            },
+            policy,
+            handle)
+    }
+
+    /// Starts the task that (re-)establishes the connection whenever it's needed.
+    fn supervise(&self) {
+        let attempt = (self.inner.connect)(&self.inner.handle);
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|now| now.subsec_nanos() as u64)
+            .unwrap_or(0);
+        self.inner.handle.spawn(Supervisor {
+            inner: Rc::downgrade(&self.inner),
+            phase: Phase::Connecting(attempt),
+            failed_attempts: 0,
+            rng: seed | 1,
+        });
+    }
+
+    /// Issues a GET request on the live connection. See `H2Client::get`.
+    pub fn get(&self, path: &[u8]) -> RoutedRequest {
+        self.request(b"GET", path, Vec::new(), None)
+    }
+
+    /// Issues a POST request on the live connection. See `H2Client::post`.
+    pub fn post(&self, path: &[u8], body: Vec<u8>) -> RoutedRequest {
+        self.request(b"POST", path, Vec::new(), Some(body))
+    }
+
+    /// Performs a request with the given method, headers and body on the live connection, once
+    /// there is one. See `H2Client::request`.
+    pub fn request(&self,
+                   method: &[u8],
+                   path: &[u8],
+                   headers: Vec<StaticHeader>,
+                   body: Option<Vec<u8>>)
+                   -> RoutedRequest {
+        let failed = match *self.inner.connection.borrow() {
+            Connection::Failed(..) => true,
+            _ => false,
+        };
+        if failed {
+            debug!("connection previously failed; reconnecting for a new request");
+            self.inner.set_connection(Connection::Connecting);
+            self.supervise();
+        }
+
+        RoutedRequest {
+            inner: self.inner.clone(),
+            method: method.to_vec(),
+            path: path.to_vec(),
+            headers: headers,
+            body: body,
+            response: None,
+            retried: false,
+        }
+    }
+}
+
+/// A `Future` of the response to a request made through a `ReconnectingClient`.
+pub struct RoutedRequest {
+    inner: Rc<Inner>,
+    method: Vec<u8>,
+    path: Vec<u8>,
+    headers: Vec<StaticHeader>,
+    body: Option<Vec<u8>>,
+    /// The response of the request, once it's been sent on a connection.
+    response: Option<H2RequestHandle>,
+    /// Set once the request has been retried on a new connection.
+    retried: bool,
+}
+
+impl RoutedRequest {
+    /// Sends the request on the live connection, if there is one.
+    fn poll_send(&mut self) -> Poll<H2RequestHandle, H2Error> {
+        let mut connection = self.inner.connection.borrow_mut();
+        match *connection {
+            Connection::Connected(ref mut client) => {
+                // A connection that is no longer healthy is about to be replaced.
+                if client.is_healthy() {
+                    let response = client.request(&self.method,
+                                                  &self.path,
+                                                  self.headers.clone(),
+                                                  self.body.clone());
+                    return Ok(Async::Ready(response));
+                }
+            },
+            Connection::Failed(kind, ref msg) => {
+                return Err(H2Error::Io(io::Error::new(kind, msg.clone())));
+            },
+            Connection::Connecting => {},
+        }
+        self.inner.waiting.borrow_mut().push(task::park());
+        Ok(Async::NotReady)
+    }
+}
+
+impl Future for RoutedRequest {
+    type Item = (HttpResponseHeaders, ResponseBodyStream);
+    type Error = H2Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(ref mut response) = self.response {
+                match response.poll() {
+                    Err(ref err) if !self.retried && err.retry_safety() == RetrySafety::Safe => {
+                        debug!("request failed before being processed ({}); retrying", err);
+                        self.retried = true;
+                    },
+                    res => return res,
+                }
+            }
+
+            self.response = match self.poll_send()? {
+                Async::Ready(response) => Some(response),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+        }
+    }
+}
+
+/// What the `Supervisor` is currently doing.
+enum Phase {
+    /// Waiting for a connection attempt to complete.
+    Connecting(Box<Future<Item=H2Client, Error=io::Error>>),
+    /// Waiting before the next attempt.
+    Backoff(Timeout),
+    /// Watching the live connection.
+    Connected(Box<Future<Item=ClosedReason, Error=()>>),
+}
+
+/// A `Future` that keeps the connection of a `ReconnectingClient` established. It runs until the
+/// connection can't be established within the `BackoffPolicy`'s attempts, or until all the
+/// `ReconnectingClient`s are gone.
+struct Supervisor {
+    inner: Weak<Inner>,
+    phase: Phase,
+    failed_attempts: u32,
+    /// The state of the (xorshift) generator of the jitter.
+    rng: u64,
+}
+
+/// Advances the given xorshift generator, returning a random value between `0.0` and `1.0`.
+fn next_random(rng: &mut u64) -> f64 {
+    *rng ^= *rng << 13;
+    *rng ^= *rng >> 7;
+    *rng ^= *rng << 17;
+    (*rng % 1_000_000) as f64 / 1_000_000.0
+}
+
+impl Future for Supervisor {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let inner = match self.inner.upgrade() {
+            Some(inner) => inner,
+            None => {
+                trace!("all the reconnecting clients are gone");
+                return Ok(Async::Ready(()));
+            },
+        };
+
+        loop {
+            let next = match self.phase {
+                Phase::Connecting(ref mut attempt) => {
+                    match attempt.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(client)) => {
+                            debug!("connection established");
+                            self.failed_attempts = 0;
+                            let closed = client.closed();
+                            inner.set_connection(Connection::Connected(client));
+                            Phase::Connected(closed)
+                        },
+                        Err(err) => {
+                            self.failed_attempts += 1;
+                            debug!("connection attempt {} failed: {}", self.failed_attempts, err);
+                            if self.failed_attempts >= inner.policy.max_attempts {
+                                inner.set_connection(Connection::Failed(err.kind(),
+                                                                        err.to_string()));
+                                return Ok(Async::Ready(()));
+                            }
+                            let random = next_random(&mut self.rng);
+                            let delay = inner.policy.delay(self.failed_attempts, random);
+                            match Timeout::new(delay, &inner.handle) {
+                                Ok(timer) => Phase::Backoff(timer),
+                                Err(err) => {
+                                    inner.set_connection(Connection::Failed(err.kind(),
+                                                                            err.to_string()));
+                                    return Ok(Async::Ready(()));
+                                },
+                            }
+                        },
+                    }
+                },
+                Phase::Backoff(ref mut timer) => {
+                    match timer.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        _ => Phase::Connecting((inner.connect)(&inner.handle)),
+                    }
+                },
+                Phase::Connected(ref mut closed) => {
+                    let closed = closed.poll();
+                    let healthy = match *inner.connection.borrow() {
+                        Connection::Connected(ref client) => client.is_healthy(),
+                        _ => false,
+                    };
+                    match closed {
+                        Ok(Async::NotReady) if healthy => return Ok(Async::NotReady),
+                        _ => {
+                            debug!("connection lost; reconnecting");
+                            inner.set_connection(Connection::Connecting);
+                            Phase::Connecting((inner.connect)(&inner.handle))
+                        },
+                    }
+                },
+            };
+            self.phase = next;
+        }
+    }
+}
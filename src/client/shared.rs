@@ -0,0 +1,644 @@
+//! Exposes the pieces of state that are shared between the client-facing side (`H2Client` and the
+//! futures/streams that it hands out) and the `H2ClientTokioTransport`.
+//!
+//! As everything runs on the same event loop, the state is simply kept in `Rc<RefCell<...>>`s.
+//! When the client-facing side asks the transport to do something, it also needs to wake up the
+//! transport's task (via the `TransportWaker`), as the transport otherwise only gets polled once
+//! there's some IO to be done.
+
+use std::fmt;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, VecDeque};
+use std::time::Duration;
+
+use futures::Async;
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+
+use solicit::http::{ErrorCode, StreamId, StaticHeader};
+
+use client::{HttpResponseHeaders, H2Error, ClosedReason};
+use client::stats::{H2Stats, FlowWindows};
+use client::tls::TlsInfo;
+
+/// Keeps track of the task that drives the transport, so that it can be woken up from outside.
+#[derive(Clone)]
+pub struct TransportWaker {
+    task: Rc<RefCell<Option<Task>>>,
+}
+
+impl TransportWaker {
+    /// Creates a new `TransportWaker` that doesn't have a registered task yet.
+    pub fn new() -> TransportWaker {
+        TransportWaker {
+            task: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// Registers the current task as the one that should be woken up. Must be called from within
+    /// the transport's `poll`.
+    pub fn register(&self) {
+        *self.task.borrow_mut() = Some(task::park());
+    }
+
+    /// Wakes up the transport's task, if it was already registered.
+    pub fn wake(&self) {
+        if let Some(ref task) = *self.task.borrow() {
+            task.unpark();
+        }
+    }
+}
+
+/// Tracks whether the connection can still be used for new requests.
+#[derive(Default)]
+struct Health {
+    /// The error code of the GOAWAY, once the server sent one.
+    goaway: Option<ErrorCode>,
+    /// Set once the connection is closed, to the reason why it got closed.
+    closed: Option<ClosedReason>,
+    /// The tasks waiting for the connection to get closed.
+    closed_tasks: Vec<Task>,
+    /// Set when the client-facing side asks for the connection to be aborted, until the
+    /// transport picks it up.
+    abort_requested: bool,
+}
+
+/// A PING that the client-facing side asked for, which the transport still needs to send out.
+pub struct PingRequest {
+    /// The opaque data that the PING carries, which is unique within the connection.
+    pub opaque_data: u64,
+    /// Completed with the round-trip time once the matching PING ACK arrives.
+    pub complete: oneshot::Sender<Result<Duration, H2Error>>,
+}
+
+/// The PINGs that were asked for, but not yet picked up by the transport.
+#[derive(Default)]
+struct Pings {
+    /// The opaque data of the next PING.
+    next_opaque_data: u64,
+    queued: VecDeque<PingRequest>,
+}
+
+/// The state of the connection that is shared between the `H2Client` and its transport.
+#[derive(Clone)]
+pub struct SharedConnection {
+    /// The connection statistics, kept up to date by the transport.
+    pub stats: Rc<RefCell<H2Stats>>,
+    /// The flow control windows of the connection and its streams, kept up to date by the
+    /// transport.
+    pub windows: Rc<RefCell<FlowWindows>>,
+    /// Allows the client-facing side to wake up the transport's task.
+    pub waker: TransportWaker,
+    health: Rc<RefCell<Health>>,
+    pings: Rc<RefCell<Pings>>,
+    open_requests: Rc<RefCell<OpenRequests>>,
+    /// The DNS names that the server's certificate is valid for, once the TLS handshake is done.
+    /// Never set for cleartext connections.
+    certificate_names: Rc<RefCell<Option<Vec<String>>>>,
+    /// The parameters of the TLS session, once the handshake is done. Never set for cleartext
+    /// connections.
+    tls_info: Rc<RefCell<Option<TlsInfo>>>,
+    /// The number of response body bytes that were handed off by the transport, but not yet
+    /// consumed, as far as they're being tracked.
+    buffered_response_bytes: Rc<Cell<usize>>,
+}
+
+impl SharedConnection {
+    /// Creates the shared state of a new connection.
+    pub fn new() -> SharedConnection {
+        SharedConnection {
+            stats: Rc::new(RefCell::new(H2Stats::default())),
+            windows: Rc::new(RefCell::new(FlowWindows::default())),
+            waker: TransportWaker::new(),
+            health: Rc::new(RefCell::new(Health::default())),
+            pings: Rc::new(RefCell::new(Pings::default())),
+            open_requests: Rc::new(RefCell::new(OpenRequests::default())),
+            certificate_names: Rc::new(RefCell::new(None)),
+            tls_info: Rc::new(RefCell::new(None)),
+            buffered_response_bytes: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Records the DNS names that the server's certificate is valid for.
+    pub fn set_certificate_names(&self, names: Vec<String>) {
+        *self.certificate_names.borrow_mut() = Some(names);
+    }
+
+    /// Returns the DNS names that the server's certificate is valid for, if the connection is
+    /// over TLS and the handshake is done.
+    pub fn certificate_names(&self) -> Option<Vec<String>> {
+        self.certificate_names.borrow().clone()
+    }
+
+    /// Records the parameters that the TLS session was established with.
+    pub fn set_tls_info(&self, info: TlsInfo) {
+        *self.tls_info.borrow_mut() = Some(info);
+    }
+
+    /// Returns the parameters of the TLS session, if the connection is over TLS and the handshake
+    /// is done.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.tls_info.borrow().clone()
+    }
+
+    /// Starts counting the given number of response body bytes as buffered, until the returned
+    /// guard is dropped, i.e. until whoever ends up with the body chunk is done with it.
+    pub fn track_buffered_response(&self, len: usize) -> BufferedResponse {
+        self.buffered_response_bytes.set(self.buffered_response_bytes.get() + len);
+        BufferedResponse {
+            bytes: self.buffered_response_bytes.clone(),
+            len: len,
+            waker: self.waker.clone(),
+        }
+    }
+
+    /// Returns the number of response body bytes that are currently buffered (see
+    /// `track_buffered_response`).
+    pub fn buffered_response_bytes(&self) -> usize {
+        self.buffered_response_bytes.get()
+    }
+
+    /// Records that the server sent a GOAWAY, i.e. that no new requests will be processed.
+    ///
+    /// Anyone waiting for the connection to get closed is woken up too, as the connection is no
+    /// longer healthy, even though it stays open until the remaining requests complete.
+    pub fn set_goaway_received(&self, error_code: ErrorCode) {
+        let mut health = self.health.borrow_mut();
+        health.goaway = Some(error_code);
+        for task in health.closed_tasks.drain(..) {
+            task.unpark();
+        }
+    }
+
+    /// Records that the connection got closed for the given reason, waking up anyone waiting for
+    /// it. Only the first reason is kept; if the server sent a GOAWAY, that is the reason,
+    /// however the connection ends up being closed afterwards.
+    pub fn set_closed(&self, reason: ClosedReason) {
+        let mut health = self.health.borrow_mut();
+        if health.closed.is_some() {
+            return;
+        }
+        health.closed = Some(match health.goaway {
+            Some(error_code) => ClosedReason::GoAway(error_code),
+            None => reason,
+        });
+        for task in health.closed_tasks.drain(..) {
+            task.unpark();
+        }
+    }
+
+    /// Asks the transport to abort the connection.
+    pub fn request_abort(&self) {
+        self.health.borrow_mut().abort_requested = true;
+        self.waker.wake();
+    }
+
+    /// Takes the pending request to abort the connection, returning whether there was one.
+    pub fn take_abort_request(&self) -> bool {
+        let mut health = self.health.borrow_mut();
+        let requested = health.abort_requested;
+        health.abort_requested = false;
+        requested
+    }
+
+    /// Returns whether the connection can still be used for new requests.
+    pub fn is_healthy(&self) -> bool {
+        let health = self.health.borrow();
+        health.goaway.is_none() && health.closed.is_none()
+    }
+
+    /// Returns whether the connection got closed.
+    pub fn is_closed(&self) -> bool {
+        self.health.borrow().closed.is_some()
+    }
+
+    /// Polls for the connection getting closed, resolving to the reason why.
+    pub fn poll_closed(&self) -> Async<ClosedReason> {
+        let mut health = self.health.borrow_mut();
+        match health.closed {
+            Some(ref reason) => return Async::Ready(reason.clone()),
+            None => {},
+        }
+        health.closed_tasks.push(task::park());
+        Async::NotReady
+    }
+
+    /// Asks the transport to send out a new PING, with opaque data that no other PING on the
+    /// connection carries.
+    ///
+    /// Returns the receiving end that the round-trip time is delivered to.
+    pub fn request_ping(&self) -> oneshot::Receiver<Result<Duration, H2Error>> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pings = self.pings.borrow_mut();
+            let opaque_data = pings.next_opaque_data;
+            pings.next_opaque_data = pings.next_opaque_data.wrapping_add(1);
+            pings.queued.push_back(PingRequest {
+                opaque_data: opaque_data,
+                complete: tx,
+            });
+        }
+        self.waker.wake();
+
+        rx
+    }
+
+    /// Takes all the PINGs that still need to be sent out.
+    pub fn take_ping_requests(&self) -> Vec<PingRequest> {
+        self.pings.borrow_mut().queued.drain(..).collect()
+    }
+
+    /// Records a new request as being open on the connection, until the returned `OpenRequest` is
+    /// dropped.
+    pub fn open_request(&self) -> OpenRequest {
+        let mut requests = self.open_requests.borrow_mut();
+        let seq = requests.next_seq;
+        requests.next_seq += 1;
+        requests.open.insert(seq);
+        OpenRequest {
+            requests: self.open_requests.clone(),
+            seq: seq,
+        }
+    }
+
+    /// Returns the sequence number that the next opened request will get. All the requests that
+    /// are open at this point have lower ones.
+    pub fn next_request_seq(&self) -> u64 {
+        self.open_requests.borrow().next_seq
+    }
+
+    /// Polls for all the requests with a sequence number lower than `seq` to be done.
+    pub fn poll_requests_done(&self, seq: u64) -> Async<()> {
+        let mut requests = self.open_requests.borrow_mut();
+        if requests.open.range(..seq).next().is_none() {
+            return Async::Ready(());
+        }
+        requests.waiting.push(task::park());
+        Async::NotReady
+    }
+}
+
+/// Counts a chunk of a response body among the connection's buffered response bytes for as long
+/// as it's around. See `SharedConnection::track_buffered_response`.
+pub struct BufferedResponse {
+    bytes: Rc<Cell<usize>>,
+    len: usize,
+    waker: TransportWaker,
+}
+
+impl Drop for BufferedResponse {
+    fn drop(&mut self) {
+        self.bytes.set(self.bytes.get() - self.len);
+        // The transport may be holding back the flow control window until enough is consumed.
+        self.waker.wake();
+    }
+}
+
+/// The requests that are open on a connection, i.e. that were handed to it, but are not done yet.
+#[derive(Default)]
+struct OpenRequests {
+    /// The sequence number of the next request.
+    next_seq: u64,
+    open: BTreeSet<u64>,
+    /// The tasks waiting for (some of) the requests to be done.
+    waiting: Vec<Task>,
+}
+
+/// A request that is open on the connection; see `SharedConnection::open_request`. The request is
+/// done once this is dropped.
+pub struct OpenRequest {
+    requests: Rc<RefCell<OpenRequests>>,
+    seq: u64,
+}
+
+impl Drop for OpenRequest {
+    fn drop(&mut self) {
+        let mut requests = self.requests.borrow_mut();
+        requests.open.remove(&self.seq);
+        // The waiters can't tell which request they wait for is done, so all of them check again.
+        for task in requests.waiting.drain(..) {
+            task.unpark();
+        }
+    }
+}
+
+/// The state of a single request.
+struct RequestState {
+    /// The h2 stream that the request got assigned to, once it's been started.
+    stream_id: Option<StreamId>,
+    /// The task that is waiting for the request to be assigned a stream.
+    stream_id_task: Option<Task>,
+    /// Set when the stream should be reset with the given error code.
+    reset_requested: Option<ErrorCode>,
+    /// The interim (1xx) responses that were received, but not yet handed out. `None` unless
+    /// the caller asked to observe them.
+    informational: Option<VecDeque<HttpResponseHeaders>>,
+    /// Set once no more interim responses can arrive (the final response has been received or
+    /// the stream is gone).
+    informational_done: bool,
+    /// The task that is waiting for the next interim response.
+    informational_task: Option<Task>,
+    /// The trailers of the response, if the server sent any.
+    trailers: Option<HttpResponseHeaders>,
+    /// The full set of headers that the request was sent with, once it was handed to the
+    /// connection.
+    sent_headers: Option<Vec<StaticHeader>>,
+    /// Set when the request should be turned into a background request, until the transport
+    /// picks it up.
+    background_requested: bool,
+    /// The weight that the request's stream should be re-prioritized to, until the transport
+    /// picks it up.
+    priority_requested: Option<u16>,
+    /// The request's entry among the open requests of the connection, until it's done: either
+    /// once its stream is gone, or once nothing refers to the request anymore.
+    open: Option<OpenRequest>,
+    /// The number of request body bytes that were packed into DATA frames so far.
+    body_bytes_sent: u64,
+    /// The task that is waiting for the request body to be sent out up to some point.
+    body_flush_task: Option<Task>,
+}
+
+/// The state of a single request, shared between the client-facing side, which can ask for the
+/// stream to be reset, and the transport, which fills in the stream ID once it's been assigned.
+#[derive(Clone)]
+pub struct SharedRequest {
+    state: Rc<RefCell<RequestState>>,
+    waker: TransportWaker,
+}
+
+impl SharedRequest {
+    /// Creates the shared state of a new request, which will use the given `TransportWaker` to
+    /// notify the transport about any changes.
+    pub fn new(waker: TransportWaker) -> SharedRequest {
+        SharedRequest {
+            state: Rc::new(RefCell::new(RequestState {
+                stream_id: None,
+                stream_id_task: None,
+                reset_requested: None,
+                informational: None,
+                informational_done: false,
+                informational_task: None,
+                trailers: None,
+                sent_headers: None,
+                background_requested: false,
+                priority_requested: None,
+                open: None,
+                body_bytes_sent: 0,
+                body_flush_task: None,
+            })),
+            waker: waker,
+        }
+    }
+
+    /// Returns the ID of the h2 stream that the request was assigned to, if it has already been
+    /// started.
+    pub fn stream_id(&self) -> Option<StreamId> {
+        self.state.borrow().stream_id
+    }
+
+    /// Records the ID of the h2 stream that the request was assigned to.
+    pub fn set_stream_id(&self, stream_id: StreamId) {
+        let task = {
+            let mut state = self.state.borrow_mut();
+            state.stream_id = Some(stream_id);
+            state.stream_id_task.take()
+        };
+        if let Some(task) = task {
+            task.unpark();
+        }
+    }
+
+    /// Checks whether the request was assigned a stream, resolving to `None` if the request is
+    /// done without ever getting one. If neither happened yet, the current task is notified once
+    /// either does.
+    pub fn poll_stream_id(&self) -> Async<Option<StreamId>> {
+        let mut state = self.state.borrow_mut();
+        if state.stream_id.is_some() || state.open.is_none() {
+            return Async::Ready(state.stream_id);
+        }
+        state.stream_id_task = Some(task::park());
+        Async::NotReady
+    }
+
+    /// Asks the transport to reset the request's stream with the given error code.
+    pub fn request_reset(&self, error_code: ErrorCode) {
+        self.state.borrow_mut().reset_requested = Some(error_code);
+        self.waker.wake();
+    }
+
+    /// Takes the pending reset request, if there is one.
+    pub fn take_reset_request(&self) -> Option<ErrorCode> {
+        self.state.borrow_mut().reset_requested.take()
+    }
+
+    /// Asks the transport to turn the request into a background request.
+    pub fn request_background(&self) {
+        self.state.borrow_mut().background_requested = true;
+        self.waker.wake();
+    }
+
+    /// Takes the pending request to turn the request into a background request, returning
+    /// whether there was one.
+    pub fn take_background_request(&self) -> bool {
+        let mut state = self.state.borrow_mut();
+        let requested = state.background_requested;
+        state.background_requested = false;
+        requested
+    }
+
+    /// Asks the transport to re-prioritize the request's stream to the given weight.
+    pub fn request_priority(&self, weight: u16) {
+        self.state.borrow_mut().priority_requested = Some(weight);
+        self.waker.wake();
+    }
+
+    /// Takes the pending request to re-prioritize the request's stream, if there is one.
+    pub fn take_priority_request(&self) -> Option<u16> {
+        self.state.borrow_mut().priority_requested.take()
+    }
+
+    /// Records that the request is open on the connection.
+    pub fn set_open(&self, open: OpenRequest) {
+        self.state.borrow_mut().open = Some(open);
+    }
+
+    /// Records that the request is done, as its stream is gone.
+    pub fn set_done(&self) {
+        let (open, flush_task, stream_id_task) = {
+            let mut state = self.state.borrow_mut();
+            (state.open.take(), state.body_flush_task.take(), state.stream_id_task.take())
+        };
+        drop(open);
+        // Nothing more of the body is going to be sent, nor is a stream going to be assigned.
+        for task in flush_task.into_iter().chain(stream_id_task) {
+            task.unpark();
+        }
+    }
+
+    /// Returns whether the request is done (or was never handed to the connection at all).
+    pub fn is_done(&self) -> bool {
+        self.state.borrow().open.is_none()
+    }
+
+    /// Records that the given number of request body bytes were packed into a DATA frame.
+    pub fn record_body_sent(&self, len: u64) {
+        let task = {
+            let mut state = self.state.borrow_mut();
+            state.body_bytes_sent += len;
+            state.body_flush_task.take()
+        };
+        if let Some(task) = task {
+            task.unpark();
+        }
+    }
+
+    /// Returns the number of request body bytes that were packed into DATA frames so far.
+    pub fn body_bytes_sent(&self) -> u64 {
+        self.state.borrow().body_bytes_sent
+    }
+
+    /// Checks whether at least the given number of request body bytes were packed into DATA
+    /// frames, or the request is done. If not, the current task is notified once more is sent.
+    pub fn poll_body_sent(&self, len: u64) -> Async<()> {
+        let mut state = self.state.borrow_mut();
+        if state.body_bytes_sent >= len || state.open.is_none() {
+            return Async::Ready(());
+        }
+        state.body_flush_task = Some(task::park());
+        Async::NotReady
+    }
+
+    /// Records the headers that the request is sent with.
+    pub fn set_sent_headers(&self, headers: Vec<StaticHeader>) {
+        self.state.borrow_mut().sent_headers = Some(headers);
+    }
+
+    /// Returns the headers that the request was sent with, if it was already handed to the
+    /// connection.
+    pub fn sent_headers(&self) -> Option<Vec<StaticHeader>> {
+        self.state.borrow().sent_headers.clone()
+    }
+
+    /// Records the trailers of the response.
+    pub fn set_trailers(&self, trailers: HttpResponseHeaders) {
+        self.state.borrow_mut().trailers = Some(trailers);
+    }
+
+    /// Takes the trailers of the response, if any were received. As the trailers end the
+    /// response, they're guaranteed to be available once the end of the body has been reached.
+    pub fn take_trailers(&self) -> Option<HttpResponseHeaders> {
+        self.state.borrow_mut().trailers.take()
+    }
+
+    /// Starts keeping the interim responses of the request, so that they can be polled for.
+    /// Until this is called, any interim responses are simply dropped.
+    pub fn observe_informational(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.informational.is_none() {
+            state.informational = Some(VecDeque::new());
+        }
+    }
+
+    /// Records a newly received interim response.
+    pub fn push_informational(&self, headers: HttpResponseHeaders) {
+        let mut state = self.state.borrow_mut();
+        if let Some(ref mut queue) = state.informational {
+            queue.push_back(headers);
+        }
+        if let Some(task) = state.informational_task.take() {
+            task.unpark();
+        }
+    }
+
+    /// Signals that there will be no more interim responses for the request.
+    pub fn finish_informational(&self) {
+        let mut state = self.state.borrow_mut();
+        state.informational_done = true;
+        if let Some(task) = state.informational_task.take() {
+            task.unpark();
+        }
+    }
+
+    /// Polls for the next interim response. Resolves to `None` once all of them have been
+    /// handed out and the final response has been received.
+    pub fn poll_informational(&self) -> Async<Option<HttpResponseHeaders>> {
+        let mut state = self.state.borrow_mut();
+        let next = state.informational.as_mut().and_then(|queue| queue.pop_front());
+        match next {
+            Some(headers) => Async::Ready(Some(headers)),
+            None if state.informational_done => Async::Ready(None),
+            None => {
+                state.informational_task = Some(task::park());
+                Async::NotReady
+            },
+        }
+    }
+}
+
+impl fmt::Debug for SharedRequest {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "SharedRequest {{ stream_id: {:?} }}", self.stream_id())
+    }
+}
+
+/// The state of an `InflightLimit`.
+struct InflightState {
+    /// The most requests that may be outstanding at once.
+    max: usize,
+    /// The number of requests that are currently outstanding.
+    active: usize,
+    /// The tasks waiting for a slot to free up.
+    waiting: Vec<Task>,
+}
+
+/// Limits the number of requests that a client may have outstanding at once. Each outstanding
+/// request holds an `InflightPermit`, which frees up its slot once it's dropped.
+#[derive(Clone)]
+pub struct InflightLimit {
+    state: Rc<RefCell<InflightState>>,
+}
+
+impl InflightLimit {
+    /// Creates a new limit that allows for at most `max` outstanding requests.
+    pub fn new(max: usize) -> InflightLimit {
+        InflightLimit {
+            state: Rc::new(RefCell::new(InflightState {
+                max: max,
+                active: 0,
+                waiting: Vec::new(),
+            })),
+        }
+    }
+
+    /// Takes up a slot, if there's one available. Otherwise, the current task is woken up once
+    /// one frees up.
+    pub fn poll_acquire(&self) -> Async<InflightPermit> {
+        let mut state = self.state.borrow_mut();
+        if state.active < state.max {
+            state.active += 1;
+            Async::Ready(InflightPermit {
+                state: self.state.clone(),
+            })
+        } else {
+            state.waiting.push(task::park());
+            Async::NotReady
+        }
+    }
+}
+
+/// A slot taken up by an outstanding request; see `InflightLimit`.
+pub struct InflightPermit {
+    state: Rc<RefCell<InflightState>>,
+}
+
+impl Drop for InflightPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.borrow_mut();
+        state.active -= 1;
+        // All the waiters get to race for the slot; those that lose out simply wait again.
+        for task in state.waiting.drain(..) {
+            task.unpark();
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Exposes the `Span`s that group together everything that's logged for a connection and for each
+//! of its requests.
+//!
+//! With the `tracing` feature, they are backed by `tracing` spans: a connection span, carrying the
+//! authority, and a span per request, carrying the Tokio request ID and the h2 stream ID. The
+//! crate keeps logging through the `log` macros, so the log lines end up within the spans once
+//! `log` records are forwarded to `tracing` (e.g. by `tracing-log`'s `LogTracer`).
+//!
+//! Without the feature, the spans are no-ops.
+
+#[cfg(not(feature = "tracing"))]
+use std::marker::PhantomData;
+
+#[cfg(feature = "tracing")]
+use tracing;
+
+use solicit::http::StreamId;
+
+/// A span that is entered while working on behalf of a connection or a single request.
+#[derive(Clone)]
+pub struct Span {
+    #[cfg(feature = "tracing")]
+    inner: tracing::Span,
+}
+
+/// Guards an entered `Span`: the span is exited once it's dropped.
+pub struct Entered<'a> {
+    #[cfg(feature = "tracing")]
+    _inner: tracing::span::Entered<'a>,
+    #[cfg(not(feature = "tracing"))]
+    _span: PhantomData<&'a Span>,
+}
+
+#[cfg(feature = "tracing")]
+impl Span {
+    /// Creates the span of the connection to the given authority.
+    pub fn connection(authority: &str) -> Span {
+        Span {
+            inner: tracing::debug_span!("h2_connection", authority = authority),
+        }
+    }
+
+    /// Creates the span of the request with the given Tokio ID, within the current span. The
+    /// stream ID is only filled in once it's assigned (see `record_stream_id`).
+    pub fn request(request_id: u64) -> Span {
+        Span {
+            inner: tracing::debug_span!("h2_request",
+                                        request_id = request_id,
+                                        stream_id = tracing::field::Empty),
+        }
+    }
+
+    /// Records the ID of the h2 stream that the span's request was assigned to.
+    pub fn record_stream_id(&self, stream_id: StreamId) {
+        self.inner.record("stream_id", &stream_id);
+    }
+
+    /// Enters the span until the returned guard is dropped.
+    pub fn enter(&self) -> Entered {
+        Entered {
+            _inner: self.inner.enter(),
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    /// Creates the span of the connection to the given authority.
+    pub fn connection(_authority: &str) -> Span {
+        Span {}
+    }
+
+    /// Creates the span of the request with the given Tokio ID, within the current span. The
+    /// stream ID is only filled in once it's assigned (see `record_stream_id`).
+    pub fn request(_request_id: u64) -> Span {
+        Span {}
+    }
+
+    /// Records the ID of the h2 stream that the span's request was assigned to.
+    pub fn record_stream_id(&self, _stream_id: StreamId) {}
+
+    /// Enters the span until the returned guard is dropped.
+    pub fn enter(&self) -> Entered {
+        Entered {
+            _span: PhantomData,
+        }
+    }
+}
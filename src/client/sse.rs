@@ -0,0 +1,164 @@
+//! Contains a `BodyDecoder` for `text/event-stream` bodies, i.e. Server-Sent Events, following the
+//! parsing rules of the HTML specification.
+
+use std::mem;
+use std::time::Duration;
+
+use super::H2Error;
+use super::codec::BodyDecoder;
+
+/// The byte order mark that the stream may start with.
+const BOM: &'static [u8] = b"\xEF\xBB\xBF";
+
+/// The event type of events that don't carry an `event` field.
+const DEFAULT_EVENT_TYPE: &'static str = "message";
+
+/// A single event of an event stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    /// The type of the event, as set by its `event` field; `message` if it has none.
+    pub event: String,
+    /// The data of the event: its `data` fields, joined by newlines.
+    pub data: String,
+    /// The last event ID that the stream set, if any. The ID carries over to all following
+    /// events, until another `id` field changes it.
+    pub id: Option<String>,
+    /// The reconnection time that the server asked for in a `retry` field since the previous
+    /// event, if any.
+    pub retry: Option<Duration>,
+}
+
+/// A `BodyDecoder` that parses a `text/event-stream` body into `SseEvent`s. Used by
+/// `ResponseBodyStream::sse_events`.
+///
+/// As the specification requires, comments and unknown fields are ignored, invalid UTF-8 is
+/// replaced (rather than failing the stream), and an event that the body ends in the middle of
+/// is discarded.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    /// Whether the start of the stream (and the byte order mark it may have) was processed.
+    started: bool,
+    /// Whether the previous line ended with a CR, in which case an LF that follows belongs to the
+    /// same line break.
+    skip_lf: bool,
+    /// The type of the event being assembled.
+    event: Option<String>,
+    /// The data of the event being assembled, with each of its `data` fields followed by an LF.
+    data: String,
+    last_id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl SseDecoder {
+    /// Creates a new `SseDecoder`, for a stream that starts with no last event ID.
+    pub fn new() -> SseDecoder {
+        SseDecoder::default()
+    }
+
+    /// Returns the last event ID that the stream set, e.g. for the `last-event-id` header of a
+    /// request that resumes it.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_id.as_ref().map(|id| &id[..])
+    }
+
+    /// Processes a single line (without its line break), returning the event that it completes,
+    /// if any.
+    fn process_line(&mut self, line: &[u8]) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line[0] == b':' {
+            // A comment.
+            return None;
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let (field, value) = match line.find(':') {
+            Some(i) => {
+                let value = &line[i + 1..];
+                (&line[..i], if value.starts_with(' ') { &value[1..] } else { value })
+            },
+            None => (&line[..], ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_owned()),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            },
+            "id" if !value.contains('\0') => self.last_id = Some(value.to_owned()),
+            "retry" if !value.is_empty() && value.bytes().all(|b| b >= b'0' && b <= b'9') => {
+                if let Ok(millis) = value.parse() {
+                    self.retry = Some(Duration::from_millis(millis));
+                }
+            },
+            _ => trace!("ignoring the event stream field {:?}", field),
+        }
+        None
+    }
+
+    /// Completes the event being assembled, on a blank line. Events without any data are not
+    /// dispatched at all.
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if self.data.is_empty() {
+            return None;
+        }
+        let mut data = mem::replace(&mut self.data, String::new());
+        data.pop();
+        Some(SseEvent {
+            event: event.unwrap_or_else(|| DEFAULT_EVENT_TYPE.to_owned()),
+            data: data,
+            id: self.last_id.clone(),
+            retry: self.retry.take(),
+        })
+    }
+}
+
+impl BodyDecoder for SseDecoder {
+    type Item = SseEvent;
+    type Error = H2Error;
+
+    fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<SseEvent>, H2Error> {
+        let mut pos = 0;
+        if !self.started {
+            if buf.len() < BOM.len() && BOM.starts_with(&buf[..]) {
+                // Can't tell yet whether this is the byte order mark.
+                return Ok(None);
+            }
+            if buf.starts_with(BOM) {
+                pos = BOM.len();
+            }
+            self.started = true;
+        }
+
+        let mut event = None;
+        while event.is_none() {
+            if self.skip_lf && pos < buf.len() {
+                if buf[pos] == b'\n' {
+                    pos += 1;
+                }
+                self.skip_lf = false;
+            }
+            let end = match buf[pos..].iter().position(|&b| b == b'\n' || b == b'\r') {
+                Some(len) => pos + len,
+                // The rest of the line is still to come.
+                None => break,
+            };
+            self.skip_lf = buf[end] == b'\r';
+            event = self.process_line(&buf[pos..end]);
+            pos = end + 1;
+        }
+        buf.drain(..pos);
+        Ok(event)
+    }
+
+    fn decode_eof(&mut self, buf: &mut Vec<u8>) -> Result<Option<SseEvent>, H2Error> {
+        let event = self.decode(buf)?;
+        if event.is_none() {
+            // Whatever is left is an incomplete line or event, which is dropped.
+            buf.clear();
+        }
+        Ok(event)
+    }
+}
@@ -0,0 +1,171 @@
+//! Exposes the `H2Stats` struct, which carries connection-level counters that the
+//! `H2ClientTokioTransport` maintains while it drives an HTTP/2 connection, along with the
+//! bookkeeping of the connection's flow control windows.
+
+use std::collections::HashMap;
+
+use solicit::http::StreamId;
+
+/// The size of the flow control windows that every connection and stream starts out with.
+const DEFAULT_WINDOW_SIZE: i64 = 65535;
+
+/// The largest that a flow control window may ever grow.
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+/// A snapshot of the connection-level statistics of a single HTTP/2 connection.
+///
+/// The transport updates a shared instance of this struct as it processes frames; the `H2Client`
+/// hands out copies of it via `H2Client::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct H2Stats {
+    /// The total number of streams (i.e. requests) that were opened on the connection.
+    pub streams_opened: u64,
+    /// The number of streams that were closed normally.
+    pub streams_closed: u64,
+    /// The number of streams that were closed by a RST_STREAM frame.
+    pub streams_reset: u64,
+    /// The total number of bytes written to the underlying `Io`.
+    pub bytes_sent: u64,
+    /// The total number of bytes of HTTP/2 frames that were received and processed.
+    pub bytes_received: u64,
+    /// The number of DATA frames that were sent out.
+    pub data_frames_sent: u64,
+    /// The total size of the names and values of all request headers, before HPACK compression.
+    pub request_header_bytes: u64,
+    /// The total number of bytes of the HEADERS (and CONTINUATION) frames that the request
+    /// headers were sent in, i.e. after HPACK compression and including the frame headers.
+    pub request_header_bytes_sent: u64,
+    /// The total size of the names and values of all response headers (including interim
+    /// responses and trailers), after HPACK decompression.
+    pub response_header_bytes: u64,
+    /// The total number of bytes of the HEADERS (and CONTINUATION) frames that the response
+    /// headers were received in, including the frame headers.
+    pub response_header_bytes_received: u64,
+    /// The number of streams that are currently active (opened, but not yet closed).
+    pub active_streams: usize,
+}
+
+/// The flow control windows of a single stream, as handed out by `H2Client::stream_window`.
+///
+/// A window can become negative, e.g. if the server shrinks its initial window size while the
+/// stream is open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamWindows {
+    /// The number of bytes of DATA that the client may still send on the stream, as per the
+    /// WINDOW_UPDATE frames received from the server.
+    pub send: i64,
+    /// The number of bytes of DATA that the server may still send on the stream, i.e. what was
+    /// granted to it minus what it has sent.
+    pub recv: i64,
+}
+
+/// Keeps track of the flow control windows of a connection and its open streams, as they follow
+/// from the frames that the transport sends and receives. They are only recorded for
+/// diagnostics: nothing is held back because of them.
+pub struct FlowWindows {
+    /// The connection-level window for sending DATA.
+    connection_send: i64,
+    /// The connection-level window for receiving DATA.
+    connection_recv: i64,
+    /// The send window that new streams start out with, as per the server's SETTINGS.
+    initial_send: i64,
+    /// The windows of the streams that are currently open.
+    streams: HashMap<StreamId, StreamWindows>,
+}
+
+impl Default for FlowWindows {
+    fn default() -> FlowWindows {
+        FlowWindows {
+            connection_send: DEFAULT_WINDOW_SIZE,
+            connection_recv: DEFAULT_WINDOW_SIZE,
+            initial_send: DEFAULT_WINDOW_SIZE,
+            streams: HashMap::new(),
+        }
+    }
+}
+
+impl FlowWindows {
+    /// Returns the connection-level send window.
+    pub fn connection_send(&self) -> i64 {
+        self.connection_send
+    }
+
+    /// Returns the connection-level receive window.
+    pub fn connection_recv(&self) -> i64 {
+        self.connection_recv
+    }
+
+    /// Returns the windows of the given stream, if it's open.
+    pub fn stream(&self, stream_id: StreamId) -> Option<StreamWindows> {
+        self.streams.get(&stream_id).cloned()
+    }
+
+    /// Starts tracking the windows of a newly opened stream.
+    pub fn open_stream(&mut self, stream_id: StreamId) {
+        self.streams.insert(stream_id, StreamWindows {
+            send: self.initial_send,
+            recv: DEFAULT_WINDOW_SIZE,
+        });
+    }
+
+    /// Stops tracking the windows of a stream that got closed.
+    pub fn close_stream(&mut self, stream_id: StreamId) {
+        self.streams.remove(&stream_id);
+    }
+
+    /// Applies a new SETTINGS_INITIAL_WINDOW_SIZE of the server, which shifts the send windows of
+    /// all open streams by the difference.
+    pub fn set_initial_send(&mut self, size: u32) {
+        let delta = size as i64 - self.initial_send;
+        self.initial_send = size as i64;
+        for windows in self.streams.values_mut() {
+            windows.send += delta;
+        }
+    }
+
+    /// Records that a DATA frame, of the given flow-controlled length, was sent on the stream.
+    pub fn data_sent(&mut self, stream_id: StreamId, length: u32) {
+        self.connection_send -= length as i64;
+        if let Some(windows) = self.streams.get_mut(&stream_id) {
+            windows.send -= length as i64;
+        }
+    }
+
+    /// Records that a DATA frame, of the given flow-controlled length, was received on the stream.
+    pub fn data_received(&mut self, stream_id: StreamId, length: u32) {
+        self.connection_recv -= length as i64;
+        if let Some(windows) = self.streams.get_mut(&stream_id) {
+            windows.recv -= length as i64;
+        }
+    }
+
+    /// Records a WINDOW_UPDATE that the server sent, for the connection if `stream_id` is `0`.
+    ///
+    /// Returns whether the window stays within the largest allowed size; if it doesn't, the
+    /// update is not applied.
+    pub fn send_window_update(&mut self, stream_id: StreamId, increment: u32) -> bool {
+        let window = if stream_id == 0 {
+            &mut self.connection_send
+        } else {
+            match self.streams.get_mut(&stream_id) {
+                Some(windows) => &mut windows.send,
+                None => return true,
+            }
+        };
+        if *window + increment as i64 > MAX_WINDOW_SIZE {
+            return false;
+        }
+        *window += increment as i64;
+        true
+    }
+
+    /// Records a WINDOW_UPDATE that was sent to the server, for the connection if `stream_id` is
+    /// `0`.
+    pub fn recv_window_update(&mut self, stream_id: StreamId, increment: u32) {
+        if stream_id == 0 {
+            self.connection_recv += increment as i64;
+        } else if let Some(windows) = self.streams.get_mut(&stream_id) {
+            windows.recv += increment as i64;
+        }
+    }
+}
@@ -0,0 +1,531 @@
+//! End-to-end tests of the client, run against the scripted `MockH2Server` over an in-memory
+//! pipe.
+
+use std::io;
+
+use futures::{future, Async, Future, Stream};
+use tokio_core::reactor::Core;
+
+use solicit::http::{ErrorCode, StaticHeader};
+
+use mock::{self, MockH2Server, MockAction, MockResponse, FrameLog, pack_frame, settle};
+use super::{GrpcMessage, H2Client, H2Error, ResponseEvent};
+
+const DATA_FRAME_TYPE: u8 = 0x0;
+const HEADERS_FRAME_TYPE: u8 = 0x1;
+const SETTINGS_FRAME_TYPE: u8 = 0x4;
+const RST_STREAM_FRAME_TYPE: u8 = 0x3;
+const PING_FRAME_TYPE: u8 = 0x6;
+const GOAWAY_FRAME_TYPE: u8 = 0x7;
+const WINDOW_UPDATE_FRAME_TYPE: u8 = 0x8;
+
+const END_STREAM_FLAG: u8 = 0x1;
+const END_HEADERS_FLAG: u8 = 0x4;
+const ACK_FLAG: u8 = 0x1;
+
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+
+/// Connects a new client to a `MockH2Server`, which is set up by the given function and spawned
+/// onto a new event loop. Returns the event loop, the client and the server's frame log.
+fn connect_with<F>(setup: F) -> (Core, H2Client, FrameLog)
+        where F: FnOnce(MockH2Server) -> MockH2Server {
+    let core = Core::new().expect("failed to create an event loop");
+    let (client_io, server_io) = mock::pipe();
+    let server = setup(MockH2Server::new(server_io));
+    let log = server.frame_log();
+    core.handle().spawn(server.map_err(|err| panic!("the mock server failed: {}", err)));
+    let client = H2Client::from_io(client_io, "example.com", &core.handle());
+
+    (core, client, log)
+}
+
+/// Connects a new client to a `MockH2Server` that takes the given actions, one per request.
+fn connect(actions: Vec<MockAction>) -> (Core, H2Client, FrameLog) {
+    connect_with(|server| actions.into_iter().fold(server, MockH2Server::then))
+}
+
+/// Returns whether the given headers include one with the given name and value.
+fn has_header(headers: &[StaticHeader], name: &[u8], value: &[u8]) -> bool {
+    headers.iter().any(|header| header.name() == name && header.value() == value)
+}
+
+#[test]
+fn get_receives_canned_headers_body_and_trailers() {
+    let response = MockResponse::new(b"200")
+        .header(b"content-type", b"text/plain")
+        .body_chunk(b"hello, ")
+        .body_chunk(b"world")
+        .trailers(vec![(b"x-checksum".to_vec(), b"abc".to_vec())]);
+    let (mut core, mut client, log) = connect(vec![MockAction::Respond(response)]);
+
+    let events = core.run(client.get(b"/index.html").into_event_stream().collect()).unwrap();
+
+    assert_eq!(events.len(), 4);
+    match events[0] {
+        ResponseEvent::Headers(ref headers) => {
+            assert_eq!(headers.status(), Some(200));
+            assert!(has_header(&headers.headers, b"content-type", b"text/plain"));
+        },
+        _ => panic!("expected the headers first"),
+    }
+    match (&events[1], &events[2]) {
+        (&ResponseEvent::Body(ref first), &ResponseEvent::Body(ref second)) => {
+            assert_eq!(first.body, b"hello, ");
+            assert_eq!(second.body, b"world");
+        },
+        _ => panic!("expected two body chunks"),
+    }
+    match events[3] {
+        ResponseEvent::Trailers(ref trailers) => {
+            assert!(has_header(&trailers.headers, b"x-checksum", b"abc"));
+        },
+        _ => panic!("expected the trailers last"),
+    }
+
+    let requests = log.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].0, 1);
+    let headers = &requests[0].1;
+    assert!(headers.contains(&(b":method".to_vec(), b"GET".to_vec())));
+    assert!(headers.contains(&(b":path".to_vec(), b"/index.html".to_vec())));
+    assert!(headers.contains(&(b":authority".to_vec(), b"example.com".to_vec())));
+}
+
+#[test]
+fn header_block_split_across_continuation_frames_is_reassembled() {
+    let cookie = |i: usize| format!("cookie-{}={}", i, "x".repeat(64)).into_bytes();
+    let mut response = MockResponse::new(b"200").header_fragment_size(100);
+    for i in 0..50 {
+        response = response.header(b"set-cookie", &cookie(i));
+    }
+    let (mut core, mut client, _log) = connect(vec![MockAction::Respond(response)]);
+
+    let response = core.run(client.get(b"/").into_full_body_response()).unwrap();
+
+    let cookies: Vec<_> = response.headers.iter()
+        .filter(|header| header.name() == b"set-cookie")
+        .map(|header| header.value().to_vec())
+        .collect();
+    assert_eq!(cookies.len(), 50);
+    for (i, value) in cookies.iter().enumerate() {
+        assert_eq!(*value, cookie(i));
+    }
+}
+
+#[test]
+fn header_block_interrupted_by_another_frame_fails_the_connection() {
+    // A HEADERS frame without END_HEADERS has to be followed by its CONTINUATION frames.
+    let mut frames = pack_frame(HEADERS_FRAME_TYPE, 0, 1, &[0x88]);
+    frames.extend(pack_frame(PING_FRAME_TYPE, 0, 0, &[0; 8]));
+    let (mut core, mut client, _log) = connect(vec![MockAction::Raw(frames)]);
+
+    let err = core.run(client.get(b"/").into_full_body_response()).unwrap_err();
+
+    assert!(err.is_connection_error(), "unexpected error: {}", err);
+    assert!(!client.is_healthy());
+}
+
+#[test]
+fn empty_post_body_is_a_single_empty_data_frame_ending_the_stream() {
+    let (mut core, mut client, log) = connect(vec![
+        MockAction::Respond(MockResponse::new(b"204")),
+    ]);
+
+    let response = core.run(client.post(b"/upload", Vec::new()).into_full_body_response()).unwrap();
+
+    assert!(has_header(&response.headers, b":status", b"204"));
+    let data_frames: Vec<_> = log.frames().into_iter()
+        .filter(|frame| frame.frame_type == DATA_FRAME_TYPE)
+        .collect();
+    assert_eq!(data_frames.len(), 1);
+    assert_eq!(data_frames[0].stream_id, 1);
+    assert!(data_frames[0].payload.is_empty());
+    assert_eq!(data_frames[0].flags & END_STREAM_FLAG, END_STREAM_FLAG);
+}
+
+#[test]
+fn each_data_frame_is_yielded_as_its_own_body_chunk() {
+    let chunks: Vec<Vec<u8>> = (0..5).map(|i| vec![b'a' + i; 10 * (i as usize + 1)]).collect();
+    let response = chunks.iter()
+        .fold(MockResponse::new(b"200"), |response, chunk| response.body_chunk(chunk));
+    let (mut core, mut client, _log) = connect(vec![MockAction::Respond(response)]);
+
+    let received = core.run(client.get(b"/").and_then(|(_headers, body)| body.collect())).unwrap();
+
+    let received: Vec<Vec<u8>> = received.into_iter().map(|chunk| chunk.body).collect();
+    assert_eq!(received, chunks);
+}
+
+#[test]
+fn only_request_timeouts_become_h2_timeouts() {
+    let request_timeout = io::Error::from(H2Error::Timeout);
+    assert!(match H2Error::from(request_timeout) { H2Error::Timeout => true, _ => false });
+
+    let connect_timeout = io::Error::new(io::ErrorKind::TimedOut, "connecting timed out");
+    match H2Error::from(connect_timeout) {
+        H2Error::Io(err) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+        err => panic!("expected an I/O error, got {:?}", err),
+    }
+}
+
+#[test]
+fn head_response_ends_together_with_its_headers() {
+    // The HEADERS frame carries END_STREAM, and no DATA follows.
+    let response = MockResponse::new(b"200").header(b"content-length", b"42");
+    let (mut core, mut client, _log) = connect(vec![MockAction::Respond(response)]);
+
+    let request = client.request(b"HEAD", b"/", Vec::new(), None);
+    let (headers, mut body) = core.run(request).unwrap();
+
+    assert!(has_header(&headers.headers, b"content-length", b"42"));
+    // The end of the body was delivered along with the headers, so it's there without the event
+    // loop having to run again.
+    let end = future::lazy(move || body.poll()).wait().unwrap();
+    assert!(match end { Async::Ready(None) => true, _ => false });
+}
+
+#[test]
+fn requests_get_consecutive_odd_stream_ids_in_call_order() {
+    let actions = (0..10).map(|_| MockAction::Respond(MockResponse::new(b"204"))).collect();
+    let (mut core, mut client, log) = connect(actions);
+
+    let paths: Vec<Vec<u8>> = (0..10).map(|i| format!("/{}", i).into_bytes()).collect();
+    let requests: Vec<_> = paths.iter().map(|path| client.get(path)).collect();
+    core.run(future::join_all(requests)).unwrap();
+
+    let received = log.requests();
+    assert_eq!(received.len(), paths.len());
+    for (i, &(stream_id, ref headers)) in received.iter().enumerate() {
+        assert_eq!(stream_id, 2 * i as u32 + 1);
+        assert!(headers.iter().any(|&(ref name, ref value)| {
+            &name[..] == b":path" && value == &paths[i]
+        }));
+    }
+}
+
+#[test]
+fn builder_sets_the_authority_and_expect_headers() {
+    let (mut core, mut client, log) = connect(vec![MockAction::Respond(MockResponse::new(b"204"))]);
+
+    let request = client.build_request(b"GET", b"/")
+        .authority(&b"other.example.com"[..])
+        .expect_continue()
+        .send();
+    core.run(request).unwrap();
+
+    let requests = log.requests();
+    let headers = &requests[0].1;
+    let has = |name: &[u8], value: &[u8]| {
+        headers.iter().any(|&(ref n, ref v)| &n[..] == name && &v[..] == value)
+    };
+    assert!(has(b":authority", b"other.example.com"));
+    assert!(!has(b":authority", b"example.com"));
+    assert!(has(b"expect", b"100-continue"));
+}
+
+/// Checks that the request failed because the server reset its stream with the given code.
+fn assert_stream_reset(err: H2Error, code: ErrorCode) {
+    match err {
+        H2Error::StreamReset(reset_code) => assert_eq!(reset_code, code),
+        err => panic!("expected the stream to be reset, got {:?}", err),
+    }
+}
+
+#[test]
+fn reset_before_headers_fails_the_response() {
+    let (mut core, mut client, _log) = connect(vec![MockAction::Reset(ErrorCode::InternalError)]);
+
+    let err = core.run(client.get(b"/")).err().expect("the request should fail");
+
+    assert_stream_reset(err, ErrorCode::InternalError);
+    assert_eq!(client.stats().streams_reset, 1);
+}
+
+#[test]
+fn reset_after_headers_fails_the_body() {
+    let response = MockResponse::new(b"200");
+    let (mut core, mut client, _log) =
+        connect(vec![MockAction::RespondAndReset(response, ErrorCode::Cancel)]);
+
+    let (headers, body) = core.run(client.get(b"/")).unwrap();
+    assert!(has_header(&headers.headers, b":status", b"200"));
+    let err = core.run(body.collect()).err().expect("the body should fail");
+
+    assert_stream_reset(err, ErrorCode::Cancel);
+    assert_eq!(client.stats().streams_reset, 1);
+}
+
+#[test]
+fn reset_mid_body_fails_the_body_after_the_received_chunks() {
+    let response = MockResponse::new(b"200").body_chunk(b"partial");
+    let (mut core, mut client, _log) =
+        connect(vec![MockAction::RespondAndReset(response, ErrorCode::InternalError)]);
+
+    let (_headers, body) = core.run(client.get(b"/")).unwrap();
+    let (first, body) = core.run(body.into_future()).map_err(|(err, _)| err).unwrap();
+    assert_eq!(first.expect("a body chunk").body, b"partial".to_vec());
+    let err = core.run(body.collect()).err().expect("the body should fail");
+
+    assert_stream_reset(err, ErrorCode::InternalError);
+    assert_eq!(client.stats().streams_reset, 1);
+}
+
+#[test]
+fn data_after_canceling_mid_body_still_counts_against_the_connection_window() {
+    // The first response never ends on its own; the server only sends the rest of its body
+    // (48000 bytes) after the client canceled the request, along with the second response.
+    let mut first = pack_frame(HEADERS_FRAME_TYPE, END_HEADERS_FLAG, 1, &[0x88]);
+    first.extend(pack_frame(DATA_FRAME_TYPE, 0, 1, &[0; 1000]));
+    let mut second = Vec::new();
+    for _ in 0..3 {
+        second.extend(pack_frame(DATA_FRAME_TYPE, 0, 1, &[0; 16000]));
+    }
+    second.extend(pack_frame(HEADERS_FRAME_TYPE, END_HEADERS_FLAG | END_STREAM_FLAG, 3, &[0x88]));
+    let third = MockResponse::new(b"200").body_chunk(&[0; 10000]).body_chunk(&[0; 10000]);
+    let (mut core, mut client, log) = connect(vec![
+        MockAction::Raw(first),
+        MockAction::Raw(second),
+        MockAction::Respond(third),
+    ]);
+
+    let mut request = client.get(b"/");
+    core.run(request.headers()).unwrap();
+    let body = request.body().expect("the response body");
+    let (chunk, _body) = core.run(body.into_future()).map_err(|(err, _)| err).unwrap();
+    assert_eq!(chunk.expect("a body chunk").body.len(), 1000);
+    request.cancel();
+    settle(&mut core);
+    core.run(client.get(b"/")).unwrap();
+
+    // Without the orphaned DATA being released, the last response would overflow the window.
+    let response = core.run(client.get(b"/").into_full_body_response()).unwrap();
+    assert_eq!(response.body.len(), 20000);
+    assert!(log.frames().iter().any(|frame| {
+        frame.frame_type == WINDOW_UPDATE_FRAME_TYPE && frame.stream_id == 0
+    }));
+}
+
+#[test]
+fn stream_ended_before_headers_fails_with_no_response() {
+    for payload in &[&b""[..], &b"early"[..]] {
+        let data = pack_frame(DATA_FRAME_TYPE, END_STREAM_FLAG, 1, payload);
+        let (mut core, mut client, _log) = connect(vec![MockAction::Raw(data)]);
+
+        match core.run(client.get(b"/")) {
+            Err(H2Error::NoResponse) => {},
+            Err(err) => panic!("expected NoResponse, got {:?}", err),
+            Ok(_) => panic!("expected NoResponse, got a response"),
+        }
+        // Only the stream was failed.
+        assert!(client.is_healthy());
+    }
+}
+
+#[test]
+fn server_settings_are_acknowledged_exactly_once() {
+    let (mut core, mut client, log) = connect(vec![MockAction::Respond(MockResponse::new(b"204"))]);
+
+    core.run(client.get(b"/")).unwrap();
+    settle(&mut core);
+
+    let settings: Vec<_> = log.frames().into_iter()
+        .filter(|frame| frame.frame_type == SETTINGS_FRAME_TYPE)
+        .collect();
+    // The client's own SETTINGS come first, followed by its ACK of the server's.
+    assert_eq!(settings.len(), 2);
+    assert_eq!(settings[0].flags & ACK_FLAG, 0);
+    assert_eq!(settings[1].flags & ACK_FLAG, ACK_FLAG);
+    assert!(settings[1].payload.is_empty());
+}
+
+#[test]
+fn polling_a_handle_after_taking_its_headers_fails_instead_of_panicking() {
+    let response = MockResponse::new(b"200").body_chunk(b"body");
+    let (mut core, mut client, _log) = connect(vec![MockAction::Respond(response)]);
+
+    let mut request = client.get(b"/");
+    core.run(request.headers()).unwrap();
+    assert!(request.body().is_some());
+
+    match future::lazy(|| request.poll()).wait() {
+        Err(H2Error::ResponseAlreadyTaken) => {},
+        Err(err) => panic!("expected ResponseAlreadyTaken, got {:?}", err),
+        Ok(_) => panic!("the response was handed out twice"),
+    }
+}
+
+#[test]
+fn settings_sent_before_the_client_starts_are_not_lost() {
+    let mut core = Core::new().expect("failed to create an event loop");
+    let (client_io, server_io) = mock::pipe();
+    let server = MockH2Server::new(server_io).then(MockAction::Respond(MockResponse::new(b"204")));
+    let log = server.frame_log();
+    core.handle().spawn(server.map_err(|err| panic!("the mock server failed: {}", err)));
+    // The server's SETTINGS are already waiting in the pipe before the client writes its
+    // preface, just like bytes buffered by the TLS layer during the handshake.
+    settle(&mut core);
+    let mut client = H2Client::from_io(client_io, "example.com", &core.handle());
+
+    // A response without the SETTINGS preceding it would fail the connection.
+    let (headers, _body) = core.run(client.get(b"/")).unwrap();
+    assert!(has_header(&headers.headers, b":status", b"204"));
+    settle(&mut core);
+    let acked = log.frames().iter().any(|frame| {
+        frame.frame_type == SETTINGS_FRAME_TYPE && frame.flags & ACK_FLAG != 0
+    });
+    assert!(acked);
+}
+
+#[test]
+fn malformed_settings_fail_the_connection() {
+    let cases = [
+        // A payload that isn't made up of whole settings.
+        (0, &[0, 4, 0, 0, 0][..], FRAME_SIZE_ERROR),
+        // SETTINGS always apply to the whole connection.
+        (1, &[0, 4, 0, 0, 0, 1][..], PROTOCOL_ERROR),
+    ];
+    for &(stream_id, payload, error_code) in &cases {
+        let frame = pack_frame(SETTINGS_FRAME_TYPE, 0, stream_id, payload);
+        let (mut core, mut client, log) = connect(vec![MockAction::Raw(frame)]);
+
+        let err = core.run(client.get(b"/")).err().expect("the request should fail");
+        settle(&mut core);
+
+        assert!(err.is_connection_error(), "unexpected error: {}", err);
+        assert!(!client.is_healthy());
+        assert_eq!(sent_error_code(&log, GOAWAY_FRAME_TYPE), error_code);
+    }
+}
+
+/// Has the server answer a GET with a WINDOW_UPDATE of the given increment on the given stream.
+/// Returns the error that the request failed with, once everything the client sent in reaction
+/// reached the server.
+fn fail_with_window_update(stream_id: u32, increment: u32) -> (H2Error, H2Client, FrameLog) {
+    let payload = [
+        (increment >> 24) as u8, (increment >> 16) as u8, (increment >> 8) as u8, increment as u8,
+    ];
+    let frame = pack_frame(WINDOW_UPDATE_FRAME_TYPE, 0, stream_id, &payload);
+    let (mut core, mut client, log) = connect(vec![MockAction::Raw(frame)]);
+
+    let err = core.run(client.get(b"/")).err().expect("the request should fail");
+    settle(&mut core);
+
+    (err, client, log)
+}
+
+/// Returns the error code carried by the only frame of the given type that the client sent.
+fn sent_error_code(log: &FrameLog, frame_type: u8) -> u32 {
+    let frames: Vec<_> = log.frames().into_iter()
+        .filter(|frame| frame.frame_type == frame_type)
+        .collect();
+    assert_eq!(frames.len(), 1);
+    // A GOAWAY carries the last stream ID ahead of its error code.
+    let payload = &frames[0].payload[frames[0].payload.len() - 4..];
+    ((payload[0] as u32) << 24) | ((payload[1] as u32) << 16)
+        | ((payload[2] as u32) << 8) | payload[3] as u32
+}
+
+#[test]
+fn zero_stream_window_increment_resets_the_stream() {
+    let (err, client, log) = fail_with_window_update(1, 0);
+
+    match err {
+        H2Error::Io(ref err) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+        err => panic!("expected an invalid data error, got {:?}", err),
+    }
+    // Only the stream was failed.
+    assert!(client.is_healthy());
+    assert_eq!(sent_error_code(&log, RST_STREAM_FRAME_TYPE), PROTOCOL_ERROR);
+}
+
+#[test]
+fn overflowing_stream_window_update_resets_the_stream() {
+    let (err, client, log) = fail_with_window_update(1, 0x7fffffff);
+
+    match err {
+        H2Error::Io(ref err) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+        err => panic!("expected an invalid data error, got {:?}", err),
+    }
+    // Only the stream was failed.
+    assert!(client.is_healthy());
+    assert_eq!(sent_error_code(&log, RST_STREAM_FRAME_TYPE), FLOW_CONTROL_ERROR);
+}
+
+#[test]
+fn zero_connection_window_increment_fails_the_connection() {
+    let (err, client, log) = fail_with_window_update(0, 0);
+
+    assert!(err.is_connection_error());
+    assert!(!client.is_healthy());
+    assert_eq!(sent_error_code(&log, GOAWAY_FRAME_TYPE), PROTOCOL_ERROR);
+}
+
+#[test]
+fn overflowing_connection_window_update_fails_the_connection() {
+    let (err, client, log) = fail_with_window_update(0, 0x7fffffff);
+
+    assert!(err.is_connection_error());
+    assert!(!client.is_healthy());
+    assert_eq!(sent_error_code(&log, GOAWAY_FRAME_TYPE), FLOW_CONTROL_ERROR);
+}
+
+#[test]
+fn a_burst_of_requests_goes_out_in_a_few_writes() {
+    let actions = (0..10).map(|_| MockAction::Respond(MockResponse::new(b"204"))).collect();
+    let (mut core, mut client, log) = connect(actions);
+    // Get the preface and the SETTINGS exchange out of the way.
+    core.run(client.ping()).unwrap();
+    settle(&mut core);
+    let writes_before = log.client_write_count();
+
+    let requests: Vec<_> = (0..10).map(|_| client.get(b"/")).collect();
+    core.run(future::join_all(requests)).unwrap();
+
+    // Rather than one write per HEADERS frame.
+    let writes = log.client_write_count() - writes_before;
+    assert!(writes <= 3, "the requests took {} writes", writes);
+}
+
+#[test]
+fn stream_id_assigned_resolves_once_the_request_is_started() {
+    let actions = (0..2).map(|_| MockAction::Respond(MockResponse::new(b"204"))).collect();
+    let (mut core, mut client, _log) = connect(actions);
+
+    let first = client.get(b"/");
+    let second = client.get(b"/");
+    assert_eq!(second.stream_id(), None);
+
+    assert_eq!(core.run(second.stream_id_assigned()).unwrap(), Some(3));
+    assert_eq!(first.stream_id(), Some(1));
+    core.run(first.join(second)).unwrap();
+}
+
+#[test]
+fn stream_id_assigned_resolves_to_none_for_a_request_that_is_never_started() {
+    let (mut core, mut client, _log) = connect(Vec::new());
+
+    let request = client.build_request(b"GET", b"/").header(&b"Invalid"[..], &b""[..]).send();
+
+    assert_eq!(core.run(request.stream_id_assigned()).unwrap(), None);
+}
+
+#[test]
+fn grpc_messages_are_reassembled_across_data_frames() {
+    // Two messages, split up so that a chunk boundary falls within each message and its prefix.
+    let body = b"\x00\x00\x00\x00\x03abc\x01\x00\x00\x00\x02de";
+    let response = MockResponse::new(b"200")
+        .body_chunk(&body[..2])
+        .body_chunk(&body[2..10])
+        .body_chunk(&body[10..]);
+    let (mut core, mut client, _log) = connect(vec![MockAction::Respond(response)]);
+
+    let messages = client.get(b"/").and_then(|(_headers, body)| body.grpc_messages().collect());
+    let messages = core.run(messages).unwrap();
+
+    assert_eq!(messages, vec![
+        GrpcMessage { compressed: false, data: b"abc".to_vec() },
+        GrpcMessage { compressed: true, data: b"de".to_vec() },
+    ]);
+}
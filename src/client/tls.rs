@@ -15,54 +15,131 @@ use tokio_core::io::{Io};
 use futures::{Async, Future};
 use futures::future;
 
+/// The ALPN identifier of HTTP/2 over TLS.
+const H2_PROTOCOL: &'static [u8] = b"h2";
+
 /// Performs the TLS handshake on the given socket, assuming it's freshly connected. Returns
 /// a (boxed) future that resolves to an `SslStream` with initialized TLS. It also negotiates
 /// the application protocol using ALPN to be `h2`.
-pub fn connect_async<I>(authority: &str,
+///
+/// If no `SslConnector` is given, a default one is created. A given connector needs to be
+/// configured to do ALPN (see `make_connector_from`).
+pub fn connect_async<I>(connector: Option<&SslConnector>,
+                        authority: &str,
                         io: I)
                         -> Box<Future<Item=SslStream<I>, Error=io::Error>>
                         where I: 'static + Io {
 
-    let connector = match make_connector() {
-        Err(e) => return Box::new(future::err(e)),
-        Ok(connector) => connector,
+    let conn = match connector {
+        Some(connector) => connector.connect_async(authority, io),
+        None => {
+            let connector = match make_connector() {
+                Err(e) => return Box::new(future::err(e)),
+                Ok(connector) => connector,
+            };
+            connector.connect_async(authority, io)
+        },
     };
 
-    let conn = connector.connect_async(authority, io);
     let conn = conn.map_err(|_e| io::Error::from(io::ErrorKind::NotConnected));
     Box::new(conn)
 }
 
 /// A helper function to create an `SslConnector` that is configured to do ALPN.
 fn make_connector() -> Result<SslConnector, io::Error> {
-    let mut builder = SslConnectorBuilder::new(SslMethod::tls())?;
-    builder.builder_mut().set_alpn_protocols(&[b"h2"])?;
+    make_connector_from(SslConnectorBuilder::new(SslMethod::tls())?)
+}
+
+/// Creates an `SslConnector` from the given, user-configured, builder. Regardless of how the
+/// builder was configured, the connector will offer `h2` over ALPN.
+pub fn make_connector_from(builder: SslConnectorBuilder) -> Result<SslConnector, io::Error> {
+    make_connector_with_alpn(builder, &[b"h2"])
+}
+
+/// Creates an `SslConnector` from the given builder that offers exactly the given protocols over
+/// ALPN, in the given order of preference.
+///
+/// As only HTTP/2 can be spoken over the resulting connections, the list has to include `h2`.
+/// Offering other protocols doesn't change that: a connection on which the server selects any
+/// other protocol is rejected once the handshake completes (see `TlsH2Stream::new`).
+pub fn make_connector_with_alpn(mut builder: SslConnectorBuilder, protocols: &[&[u8]])
+        -> Result<SslConnector, io::Error> {
+    if !protocols.iter().any(|&protocol| protocol == H2_PROTOCOL) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "the ALPN protocol list must include h2"));
+    }
+    builder.builder_mut().set_alpn_protocols(protocols)?;
 
     Ok(builder.build())
 }
 
+/// The parameters that a TLS connection was established with, as negotiated during the handshake.
+/// Obtained from `H2Client::tls_info`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsInfo {
+    /// The TLS version, e.g. `"TLSv1.2"`.
+    pub version: String,
+    /// The name of the cipher suite, if one was negotiated.
+    pub cipher: Option<String>,
+    /// The application protocol that the server selected over ALPN (always `h2`).
+    pub alpn_protocol: Vec<u8>,
+}
+
 /// A TLS stream after negotiating to use http2 as the application protocol over ALPN.
 pub struct TlsH2Stream<I: Io> {
     inner: SslStream<I>,
 }
 
 impl<I> TlsH2Stream<I> where I: Io {
+    /// Wraps the given `SslStream`, as long as the server selected `h2` during ALPN. If it
+    /// selected any other protocol (e.g. fell back to `http/1.1`) or none at all, fails with an
+    /// error that says so.
     pub fn new(inner: SslStream<I>) -> Result<TlsH2Stream<I>, io::Error> {
-        let is_h2 = {
-            let protocol = inner.get_ref().ssl().selected_alpn_protocol();
-            let protocol = protocol.ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
-            trace!("Negotiated application protocol: {:?}", protocol);
+        let selected = inner.get_ref().ssl().selected_alpn_protocol().map(|p| p.to_vec());
+        trace!("Negotiated application protocol: {:?}", selected);
+
+        match selected {
+            Some(ref protocol) if &protocol[..] == H2_PROTOCOL => {
+                Ok(TlsH2Stream {
+                    inner: inner,
+                })
+            },
+            Some(protocol) => {
+                let msg = format!("the server selected the {} protocol instead of h2",
+                                  String::from_utf8_lossy(&protocol));
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg))
+            },
+            None => {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                   "the server did not select a protocol over ALPN"))
+            },
+        }
+    }
+}
 
-            protocol == b"h2"
-        };
+impl<I> TlsH2Stream<I> where I: Io {
+    /// Returns the parameters that the TLS session was established with.
+    pub fn tls_info(&self) -> TlsInfo {
+        let ssl = self.inner.get_ref().ssl();
+        TlsInfo {
+            version: ssl.version().to_owned(),
+            cipher: ssl.current_cipher().map(|cipher| cipher.name().to_owned()),
+            alpn_protocol: ssl.selected_alpn_protocol().unwrap_or(H2_PROTOCOL).to_vec(),
+        }
+    }
 
-        if is_h2 {
-            Ok(TlsH2Stream {
-                inner: inner,
+    /// Returns the DNS names that the server's certificate is valid for, as listed among its
+    /// subject alternative names.
+    pub fn certificate_names(&self) -> Vec<String> {
+        let ssl = self.inner.get_ref().ssl();
+        ssl.peer_certificate()
+            .and_then(|cert| cert.subject_alt_names())
+            .map(|names| {
+                names.iter()
+                    .filter_map(|name| name.dnsname().map(|name| name.to_owned()))
+                    .collect()
             })
-        } else {
-            Err(io::Error::from(io::ErrorKind::UnexpectedEof))
-        }
+            .unwrap_or_else(Vec::new)
     }
 }
 
@@ -12,34 +12,63 @@ use super::{
     TokioSyncError,
     HttpRequestHeaders,
     HttpRequestBody,
+    BodyBytes,
     HttpResponseHeaders,
-    HttpResponseBody
+    HttpResponseBody,
+    RequestFailure,
+    RequestFailureReason,
+    RetrySafety,
+    H2Error,
+    ClosedReason,
 };
 use client::connectors::H2ConnectorParams;
+use client::headers::{
+    validate_request_headers,
+    header_list_size,
+    parse_content_length,
+    InvalidHeadersError,
+};
+use client::shared::{SharedRequest, SharedConnection};
+use client::options::{ConnectionOptions, FrameDirection};
+use client::spans::Span;
+use client::stats::{H2Stats, FlowWindows};
 
-use io::{FrameSender, FrameReceiver};
+use io::{FrameSender, FrameReceiver, FrameContainer, frame_info, pack_frame};
 
+use std::mem;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::io::{self, Read};
-use std::collections::{HashMap,VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
 use futures::{Async, AsyncSink, Future, Poll, StartSend};
-use futures::future::{self};
 use futures::sink::Sink;
 use futures::stream::{Stream};
+use futures::sync::oneshot;
 use futures::task;
 
 use tokio_core::io::{Io, self as tokio_io};
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_service::Service;
 use tokio_proto::streaming::multiplex::{ClientProto, Transport, Frame};
 
 use solicit::http::{
     HttpScheme,
     Header, StaticHeader, OwnedHeader,
-    StreamId
+    StreamId,
+    ErrorCode,
+    HttpError,
+};
+use solicit::http::frame::{
+    RstStreamFrame,
+    WindowUpdateFrame,
+    PingFrame,
+    GoawayFrame,
+    RawFrame,
+    FrameHeader,
 };
-use solicit::http::connection::{HttpConnection, SendStatus};
+use solicit::http::connection::{HttpConnection, SendFrame, SendStatus};
 use solicit::http::session::{
     Client as ClientMarker,
     Stream as SolicitStream,
@@ -48,7 +77,7 @@ use solicit::http::session::{
     StreamDataError, StreamDataChunk,
     StreamState,
 };
-use solicit::http::client::{self, ClientConnection, RequestStream};
+use solicit::http::client::{ClientConnection, RequestStream};
 
 /// An enum that represents different response parts that can be generated by an HTTP/2 stream
 /// for an associated request.
@@ -59,6 +88,9 @@ enum ResponseChunk {
     Body(HttpResponseBody),
     /// Signals that there will be no more body chunks yielded by the stream.
     EndOfBody,
+    /// Signals that the request failed before the response completed, e.g. because the server
+    /// terminated the stream or because the request itself was invalid.
+    Error(io::Error),
 }
 
 /// A helper struct that is used by the `H2Stream` to place its `ResponseChunk`s into a shared
@@ -106,6 +138,179 @@ impl ResponseChunkReceiver {
     }
 }
 
+/// The frame type identifier of DATA frames.
+const DATA_FRAME_TYPE: u8 = 0x0;
+/// The frame type identifier of HEADERS frames.
+const HEADERS_FRAME_TYPE: u8 = 0x1;
+/// The frame type identifier of PRIORITY frames.
+const PRIORITY_FRAME_TYPE: u8 = 0x2;
+/// The frame type identifier of RST_STREAM frames.
+const RST_STREAM_FRAME_TYPE: u8 = 0x3;
+/// The frame type identifier of SETTINGS frames.
+const SETTINGS_FRAME_TYPE: u8 = 0x4;
+/// The frame type identifier of PING frames.
+const PING_FRAME_TYPE: u8 = 0x6;
+/// The frame type identifier of GOAWAY frames.
+const GOAWAY_FRAME_TYPE: u8 = 0x7;
+/// The frame type identifier of WINDOW_UPDATE frames.
+const WINDOW_UPDATE_FRAME_TYPE: u8 = 0x8;
+/// The frame type identifier of CONTINUATION frames.
+const CONTINUATION_FRAME_TYPE: u8 = 0x9;
+/// The END_HEADERS flag of HEADERS and CONTINUATION frames.
+const END_HEADERS_FLAG: u8 = 0x4;
+/// The PADDED flag of DATA frames.
+const PADDED_FLAG: u8 = 0x8;
+/// The ACK flag of SETTINGS and PING frames.
+const ACK_FLAG: u8 = 0x1;
+/// The identifier of the SETTINGS_ENABLE_PUSH setting.
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+/// The identifier of the SETTINGS_INITIAL_WINDOW_SIZE setting.
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+/// The identifier of the SETTINGS_MAX_HEADER_LIST_SIZE setting.
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+/// The identifier of the SETTINGS_ENABLE_CONNECT_PROTOCOL setting (RFC 8441).
+const SETTINGS_ENABLE_CONNECT_PROTOCOL: u16 = 0x8;
+/// The number of request body bytes that may be buffered for a single stream, before new body
+/// chunks are rejected until some of the buffered ones get sent out.
+const MAX_BUFFERED_BODY_LEN: usize = 64 * 1024;
+/// The header list size limit that is assumed until the server's SETTINGS arrive.
+const DEFAULT_MAX_HEADER_LIST_SIZE: u32 = 16 * 1024;
+/// The weight that streams are given unless asked otherwise.
+const DEFAULT_WEIGHT: u16 = 16;
+
+/// Reads a big-endian `u32` from the start of the given buffer. The buffer needs to hold at least
+/// 4 bytes.
+fn read_u32(buf: &[u8]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | (buf[3] as u32)
+}
+
+/// Reads a big-endian `u16` from the start of the given buffer. The buffer needs to hold at least
+/// 2 bytes.
+fn read_u16(buf: &[u8]) -> u16 {
+    ((buf[0] as u16) << 8) | (buf[1] as u16)
+}
+
+/// The fixed sequence of octets that every client connection preface starts with.
+const CLIENT_PREFACE_MAGIC: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Returns the settings that the client announces in its connection preface.
+fn initial_settings(_options: &ConnectionOptions) -> Vec<(u16, u32)> {
+    vec![
+        // Server push isn't supported.
+        (SETTINGS_ENABLE_PUSH, 0),
+    ]
+}
+
+/// Serializes the payload of the SETTINGS frame that the client announces its initial settings
+/// with. Besides the connection preface, it's also sent in the `HTTP2-Settings` header when
+/// upgrading from HTTP/1.1.
+pub fn initial_settings_payload(options: &ConnectionOptions) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for (id, value) in initial_settings(options) {
+        payload.extend_from_slice(&[(id >> 8) as u8, id as u8]);
+        payload.extend_from_slice(&[
+            (value >> 24) as u8,
+            (value >> 16) as u8,
+            (value >> 8) as u8,
+            value as u8,
+        ]);
+    }
+    payload
+}
+
+/// Serializes the full client connection preface: the magic octets immediately followed by the
+/// initial SETTINGS frame, so that both go out in a single write and the server sees a complete
+/// preface right away.
+fn serialize_preface(options: &ConnectionOptions) -> Vec<u8> {
+    let payload = initial_settings_payload(options);
+    let mut preface = CLIENT_PREFACE_MAGIC.to_vec();
+    preface.extend(pack_frame(SETTINGS_FRAME_TYPE, 0, 0, &payload));
+    preface
+}
+
+/// Returns the total size of the names and values of the given headers, i.e. how large they are
+/// before HPACK compression.
+fn header_bytes(headers: &[StaticHeader]) -> usize {
+    headers.iter().map(|header| header.name().len() + header.value().len()).sum()
+}
+
+/// A header block that was started by a HEADERS frame without the END_HEADERS flag, which is
+/// still waiting for (the rest of) its CONTINUATION frames.
+struct PendingHeaderBlock {
+    stream_id: StreamId,
+    /// The flags of the HEADERS frame, without the PADDED flag, as the padding is stripped.
+    flags: u8,
+    /// The payload of the HEADERS frame (the priority fields, if any, and the first header block
+    /// fragment), followed by the fragments of all CONTINUATION frames received so far.
+    payload: Vec<u8>,
+}
+
+impl PendingHeaderBlock {
+    /// Serializes the complete header block into a single raw HEADERS frame that carries the
+    /// END_HEADERS flag.
+    fn into_raw_frame(self) -> Vec<u8> {
+        pack_frame(HEADERS_FRAME_TYPE, self.flags | END_HEADERS_FLAG, self.stream_id, &self.payload)
+    }
+}
+
+/// Buffers the header block fragment carried by the given frame, which is either a HEADERS frame
+/// without the END_HEADERS flag (starting a new block) or a CONTINUATION frame (when a block is
+/// already pending).
+///
+/// Returns the whole header block, serialized as a single HEADERS frame, once the last fragment
+/// has been received. Any frame other than a CONTINUATION on the same stream interrupting the
+/// block is a protocol error.
+fn buffer_header_fragment(pending: &mut Option<PendingHeaderBlock>,
+                          header: FrameHeader,
+                          payload: &[u8])
+                          -> Result<Option<Vec<u8>>, Http2Error> {
+    let (_, frame_type, flags, stream_id) = header;
+    let invalid = || Http2Error::Protocol(HttpError::InvalidFrame);
+
+    match pending.take() {
+        None => {
+            let mut fragment = payload;
+            if flags & PADDED_FLAG != 0 {
+                let pad_length = *payload.first().ok_or_else(|| invalid())? as usize;
+                if 1 + pad_length > payload.len() {
+                    return Err(invalid());
+                }
+                fragment = &payload[1..payload.len() - pad_length];
+            }
+            *pending = Some(PendingHeaderBlock {
+                stream_id: stream_id,
+                flags: flags & !PADDED_FLAG,
+                payload: fragment.to_vec(),
+            });
+            Ok(None)
+        },
+        Some(mut block) => {
+            if frame_type != CONTINUATION_FRAME_TYPE || stream_id != block.stream_id {
+                debug!("header block interrupted by a frame of type {:x}", frame_type);
+                return Err(invalid());
+            }
+            block.payload.extend_from_slice(payload);
+            if flags & END_HEADERS_FLAG == 0 {
+                *pending = Some(block);
+                Ok(None)
+            } else {
+                Ok(Some(block.into_raw_frame()))
+            }
+        },
+    }
+}
+
+/// Returns the value of the setting with the given identifier from the given SETTINGS frame
+/// payload, if the setting is found in it.
+fn find_setting(payload: &[u8], id: u16) -> Option<u32> {
+    // Each setting is a 16-bit identifier followed by a 32-bit value; the last occurrence wins.
+    payload.chunks(6)
+        .filter(|setting| setting.len() == 6)
+        .filter(|setting| read_u16(setting) == id)
+        .map(|setting| read_u32(&setting[2..]))
+        .last()
+}
+
 /// A struct that represents an HTTP/2 stream.
 /// Each HTTP/2 stream corresponds to a single (Tokio/HTTP) request.
 ///
@@ -127,24 +332,100 @@ struct H2Stream {
     /// The current stream state.
     state: StreamState,
 
-    /// The outgoing data associated to the stream. The `Cursor` points into the `Vec` at the
+    /// The outgoing data associated to the stream. The `Cursor` points into the chunk at the
     /// position where the data has been sent out.
-    out_buf: Option<io::Cursor<Vec<u8>>>,
+    out_buf: Option<io::Cursor<BodyBytes>>,
     /// A queue of data chunks that should be sent after the current out buffer is exhausted.
-    out_queue: VecDeque<Vec<u8>>,
+    out_queue: VecDeque<BodyBytes>,
     /// A boolean indicating whether the stream should be closed (locally) after the out buffer
     /// and queue have been cleared out.
     should_close: bool,
+    /// Set while the request body is held back until the server sends a `100 Continue` interim
+    /// response (as the request carried an `expect: 100-continue` header).
+    awaiting_continue: bool,
+    /// Set if the server sent its final response before agreeing to receive the body, in which
+    /// case the body is never sent and any further body chunks are simply dropped.
+    discard_body: bool,
+    /// The length of the request body, as declared by its `content-length` header, if any.
+    content_length: Option<u64>,
+    /// The number of request body bytes that were handed to the stream so far.
+    body_length: u64,
+    /// The most bytes that the request body may carry, if it's limited.
+    max_body_length: Option<u64>,
+    /// The number of padding bytes that the stream's DATA frames are sent with, for which room
+    /// is left in each of them.
+    data_padding: u8,
+    /// The number of bytes of the stream's receive window that were consumed, but not yet given
+    /// back to the server.
+    unreleased_window: u32,
+    /// Set once the (final) response headers were received. Any header block that comes after
+    /// them carries the trailers.
+    headers_received: bool,
+    /// Set if the response can't have a body, whatever its headers say (i.e. for HEAD requests).
+    response_bodyless: bool,
+    /// The length of the response body, as declared by its `content-length` header, if any.
+    response_content_length: Option<u64>,
+    /// The number of response body bytes received so far.
+    response_body_length: u64,
+    /// The most response header fields that the stream may receive in total, if limited.
+    max_response_headers: Option<usize>,
+    /// The most bytes that the names and values of the stream's response headers may add up to,
+    /// if limited.
+    max_response_header_bytes: Option<usize>,
+    /// The number of response header fields received so far.
+    response_header_count: usize,
+    /// The size of the names and values of the response headers received so far.
+    response_header_size: usize,
+    /// Set once the server sent more response headers than the limits allow, which fails the
+    /// connection.
+    header_limit_exceeded: bool,
+    /// Set if the stream was closed by a RST_STREAM frame.
+    was_reset: bool,
+    /// Set for background streams, which yield to all other streams.
+    background: bool,
+    /// The weight of the stream, which decides which of the streams with request data ready gets
+    /// to send it first.
+    weight: u16,
+    /// Set while the stream has to hold back its body, as streams that take precedence over it
+    /// have data to send.
+    yielding: bool,
+    /// The state that is shared with the client-facing side of the request, if any.
+    request: Option<SharedRequest>,
+    /// Set if the stream is being terminated early (e.g. by the server), in which case the
+    /// request fails with this error instead of having its body end normally.
+    failure: Option<io::Error>,
 
     /// A `ResponseChunkSender` that allows the stream to notify the `H2ClientTokioTransport` when
     /// it has received a relevant part of the response.
     sender: ResponseChunkSender,
+    /// The statistics of the connection that the stream belongs to.
+    stats: Rc<RefCell<H2Stats>>,
+    /// The flow control windows of the connection, into which the sent DATA is recorded.
+    windows: Rc<RefCell<FlowWindows>>,
+    /// The span of the stream's request, which is closed once the stream is dropped.
+    span: Span,
+}
+
+impl Drop for H2Stream {
+    fn drop(&mut self) {
+        // The stream is gone, so the request no longer holds up `H2Client::flush_inflight`.
+        if let Some(ref request) = self.request {
+            request.set_done();
+        }
+    }
 }
 
 impl H2Stream {
     /// Create a new `H2Stream` for a Tokio request with the given ID, which will place all
-    /// `ResponseChunk`s that it generates due to incoming h2 stream events.
-    pub fn new(sender: ResponseChunkSender) -> H2Stream {
+    /// `ResponseChunk`s that it generates due to incoming h2 stream events. Anything done on
+    /// behalf of the stream is done within the given span. The sizes of the received headers are
+    /// recorded into the given connection statistics, and the DATA it sends into the given flow
+    /// control windows.
+    pub fn new(sender: ResponseChunkSender,
+               stats: Rc<RefCell<H2Stats>>,
+               windows: Rc<RefCell<FlowWindows>>,
+               span: Span)
+               -> H2Stream {
         H2Stream {
             stream_id: None,
             state: StreamState::Open,
@@ -152,8 +433,33 @@ impl H2Stream {
             out_buf: None,
             out_queue: VecDeque::new(),
             should_close: false,
+            awaiting_continue: false,
+            discard_body: false,
+            content_length: None,
+            body_length: 0,
+            max_body_length: None,
+            data_padding: 0,
+            unreleased_window: 0,
+            headers_received: false,
+            response_bodyless: false,
+            response_content_length: None,
+            response_body_length: 0,
+            max_response_headers: None,
+            max_response_header_bytes: None,
+            response_header_count: 0,
+            response_header_size: 0,
+            header_limit_exceeded: false,
+            was_reset: false,
+            background: false,
+            weight: DEFAULT_WEIGHT,
+            yielding: false,
+            request: None,
+            failure: None,
 
             sender: sender,
+            stats: stats,
+            windows: windows,
+            span: span,
         }
     }
 
@@ -161,12 +467,32 @@ impl H2Stream {
     /// already been instructed that it should be locally closed (via `set_should_close`) even if
     /// it still hasn't actually become locally closed (i.e. not everything that's been buffered
     /// has been sent out to the server yet).
-    pub fn add_data(&mut self, data: Vec<u8>) -> Result<(), Http2Error> {
+    pub fn add_data(&mut self, data: BodyBytes) -> Result<(), Http2Error> {
+        if self.discard_body {
+            trace!("dropping a body chunk the server did not agree to receive");
+            return Ok(());
+        }
         if self.should_close {
             // Adding data after we already closed the stream is not valid, because we cannot make
             // sure to send it.
             return Err(Http2Error::TokioSync(TokioSyncError::DataChunkAfterEndOfBody));
         }
+        self.body_length += data.as_ref().len() as u64;
+        if self.content_length.map_or(false, |expected| self.body_length > expected) {
+            debug!("request body longer than its content-length");
+            return Err(Http2Error::ContentLengthMismatch);
+        }
+        if self.max_body_length.map_or(false, |max| self.body_length > max) {
+            debug!("request body exceeds the size limit");
+            return Err(Http2Error::RequestBodyLimitExceeded);
+        }
+        if data.as_ref().is_empty() {
+            // An empty chunk carries nothing, so it shouldn't turn into an empty DATA frame of its
+            // own. An empty body simply ends up as a single empty DATA frame with END_STREAM, once
+            // the stream is closed.
+            trace!("skipping an empty body chunk");
+            return Ok(());
+        }
 
         self.out_queue.push_back(data);
 
@@ -175,22 +501,65 @@ impl H2Stream {
 
     /// Places the stream in a state where once the previously buffered chunks have been sent, the
     /// stream will be closed. No more chunks should be queued after this is called.
-    pub fn set_should_close(&mut self) {
+    ///
+    /// Fails if the body turned out shorter than its declared `content-length`.
+    pub fn set_should_close(&mut self) -> Result<(), Http2Error> {
         self.should_close = true;
+        if self.discard_body {
+            return Ok(());
+        }
+        if self.content_length.map_or(false, |expected| self.body_length != expected) {
+            debug!("request body shorter than its content-length");
+            return Err(Http2Error::ContentLengthMismatch);
+        }
+
+        Ok(())
     }
 
     /// Prepare the `out_buf` by placing the next element off the `out_queue` in it, if we have
     /// exhausted the previous buffer. If the buffer hasn't yet been exhausted, it has no effect.
     fn prepare_out_buf(&mut self) {
         if self.out_buf.is_none() {
-            self.out_buf = self.out_queue.pop_front().map(|vec| io::Cursor::new(vec));
+            self.out_buf = self.out_queue.pop_front().map(|chunk| io::Cursor::new(chunk));
         }
     }
+
+    /// Returns whether the stream has any request data that it could send right away.
+    fn has_data_ready(&self) -> bool {
+        !self.is_closed_local() && !self.awaiting_continue
+            && (self.out_buf.is_some() || !self.out_queue.is_empty() || self.should_close)
+    }
+
+    /// Returns the number of body bytes that have been handed to the stream, but not yet sent out.
+    fn buffered_len(&self) -> usize {
+        let in_buf = self.out_buf.as_ref()
+            .map_or(0, |buf| buf.get_ref().as_ref().len() - buf.position() as usize);
+        in_buf + self.out_queue.iter().map(|chunk| chunk.as_ref().len()).sum::<usize>()
+    }
+
+    /// Gives up on sending the request body: anything that's been buffered is dropped and the
+    /// stream will be closed locally without sending any more data.
+    fn abandon_body(&mut self) {
+        self.discard_body = true;
+        self.out_buf = None;
+        self.out_queue.clear();
+        self.should_close = true;
+    }
 }
 
+
 impl SolicitStream for H2Stream {
     fn new_data_chunk(&mut self, data: &[u8]) {
-        let body_chunk = ResponseChunk::Body(HttpResponseBody { body: data.to_vec() });
+        if self.is_closed_remote() {
+            // Nothing may follow the end of the body.
+            debug!("dropping DATA received after the end of the response body");
+            return;
+        }
+        // Each DATA frame becomes exactly one body chunk (even if it's empty), which is what
+        // allows the response body to preserve frame boundaries. Nothing downstream may merge or
+        // split the chunks.
+        self.response_body_length += data.len() as u64;
+        let body_chunk = ResponseChunk::Body(HttpResponseBody::new(data.to_vec()));
         self.sender.send_chunk(body_chunk);
     }
 
@@ -199,20 +568,122 @@ impl SolicitStream for H2Stream {
             let owned: OwnedHeader = h.into();
             owned.into()
         });
-
-        let header_chunk = ResponseChunk::Headers(HttpResponseHeaders {
+        let response_headers = HttpResponseHeaders {
             headers: new_headers.collect(),
-        });
-        self.sender.send_chunk(header_chunk);
+        };
+        let size = header_bytes(&response_headers.headers);
+        self.stats.borrow_mut().response_header_bytes += size as u64;
+
+        self.response_header_count += response_headers.headers.len();
+        self.response_header_size += size;
+        let too_many = self.max_response_headers
+            .map_or(false, |max| self.response_header_count > max);
+        let too_large = self.max_response_header_bytes
+            .map_or(false, |max| self.response_header_size > max);
+        if too_many || too_large || self.header_limit_exceeded {
+            // The transport fails the connection once the frame is handled.
+            debug!("response headers exceed the limits; count={}, size={}",
+                   self.response_header_count, self.response_header_size);
+            self.header_limit_exceeded = true;
+            return;
+        }
+
+        if self.headers_received {
+            trace!("received the response trailers");
+            if let Some(ref request) = self.request {
+                request.set_trailers(response_headers);
+            }
+            return;
+        }
+
+        if let Some(status) = response_headers.status() {
+            if status >= 100 && status < 200 {
+                // An interim response: the final response is still to come.
+                trace!("received an interim response; status={}", status);
+                if status == 100 && self.awaiting_continue {
+                    debug!("server agreed to receive the request body");
+                    self.awaiting_continue = false;
+                }
+                if let Some(ref request) = self.request {
+                    request.push_informational(response_headers);
+                }
+                return;
+            }
+        }
+        if let Some(ref request) = self.request {
+            request.finish_informational();
+        }
+        if self.awaiting_continue {
+            // The final response came in without the server asking for the body, so it's not
+            // going to be sent at all.
+            debug!("final response received before 100 Continue; abandoning the request body");
+            self.awaiting_continue = false;
+            self.abandon_body();
+        }
+
+        self.headers_received = true;
+        // Responses that can't have a body may still declare the length that it would've had.
+        let bodyless = self.response_bodyless
+            || response_headers.status().map_or(false, |status| status == 204 || status == 304);
+        if !bodyless {
+            self.response_content_length = response_headers.content_length();
+        }
+        self.sender.send_chunk(ResponseChunk::Headers(response_headers));
     }
 
     fn set_state(&mut self, state: StreamState) {
+        let was_closed_remote = self.is_closed_remote();
         self.state = state;
 
         // If we've transitioned into a state where the stream is closed on the remote end,
         // it means that there can't be more body chunks incoming...
-        if self.is_closed_remote() {
-            self.sender.send_chunk(ResponseChunk::EndOfBody);
+        //
+        // For a response without a body (e.g. to a HEAD request), the HEADERS frame itself
+        // carries END_STREAM. The connection hands the headers to the stream before closing it,
+        // in the same pass, so the `Headers` chunk is always queued up ahead of the `EndOfBody`
+        // and the response resolves right away, with an empty body.
+        if self.is_closed_remote() && !was_closed_remote {
+            if let Some(ref request) = self.request {
+                request.finish_informational();
+            }
+            // ...either because the response is complete or because the server terminated the
+            // stream early. A stream that ends before the response headers ever came in has no
+            // response to complete, so it always fails.
+            if self.failure.is_none() && !self.headers_received {
+                debug!("stream closed without a response");
+                self.failure = Some(RequestFailure {
+                    reason: RequestFailureReason::NoResponse,
+                    retry_safety: RetrySafety::Unknown,
+                }.into());
+            }
+            // A body that doesn't add up to its declared length (e.g. a truncated download)
+            // must not look like it completed successfully.
+            match self.response_content_length {
+                Some(expected)
+                        if self.failure.is_none() && expected != self.response_body_length => {
+                    debug!("response body length {} doesn't match its content-length {}",
+                           self.response_body_length, expected);
+                    self.failure = Some(RequestFailure {
+                        reason: RequestFailureReason::ResponseLengthMismatch {
+                            expected: expected,
+                            received: self.response_body_length,
+                        },
+                        retry_safety: RetrySafety::Unknown,
+                    }.into());
+                },
+                _ => {},
+            }
+            match self.failure.take() {
+                Some(failure) => self.sender.send_chunk(ResponseChunk::Error(failure)),
+                None => self.sender.send_chunk(ResponseChunk::EndOfBody),
+            }
+        }
+
+        // Once the stream is fully closed (possibly by a reset, before the request body was fully
+        // sent), nothing more can ever be sent on it: any body chunks that are still buffered, or
+        // that are yet to be handed over, are dropped.
+        if self.is_closed() && !self.discard_body {
+            self.abandon_body();
         }
     }
 
@@ -224,6 +695,15 @@ impl SolicitStream for H2Stream {
         if self.is_closed_local() {
             return Err(StreamDataError::Closed);
         }
+        if self.awaiting_continue || self.yielding {
+            return Ok(StreamDataChunk::Unavailable);
+        }
+        // Room is left for the padding (and its length field) that the frame is sent with.
+        let reserved = if self.data_padding > 0 { 1 + self.data_padding as usize } else { 0 };
+        if reserved > 0 && buf.len() <= reserved {
+            return Ok(StreamDataChunk::Unavailable);
+        }
+        let room = buf.len() - reserved;
 
         // First make sure we have something in the out buffer, if at all possible.
         self.prepare_out_buf();
@@ -240,8 +720,8 @@ impl SolicitStream for H2Stream {
                 }
             },
             Some(d) => {
-                let read = d.read(buf)?;
-                out_buf_exhausted = (d.position() as usize) == d.get_ref().len();
+                let read = d.read(&mut buf[..room])?;
+                out_buf_exhausted = (d.position() as usize) == d.get_ref().as_ref().len();
 
                 if self.should_close && out_buf_exhausted && self.out_queue.is_empty() {
                     StreamDataChunk::Last(read)
@@ -255,6 +735,18 @@ impl SolicitStream for H2Stream {
             self.out_buf = None;
         }
 
+        // The padding that the frame is sent with counts against the flow control windows too.
+        let sent = match chunk {
+            StreamDataChunk::Chunk(read) | StreamDataChunk::Last(read) => Some(read + reserved),
+            StreamDataChunk::Unavailable => None,
+        };
+        if let (Some(stream_id), Some(sent)) = (self.stream_id, sent) {
+            self.windows.borrow_mut().data_sent(stream_id, sent as u32);
+        }
+        if let (Some(request), Some(sent)) = (self.request.as_ref(), sent) {
+            request.record_body_sent((sent - reserved) as u64);
+        }
+
         // Transition the stream state to locally closed if we've extracted the final data chunk.
         if let StreamDataChunk::Last(_) = chunk {
             self.close_local()
@@ -313,22 +805,139 @@ pub struct H2ClientTokioTransport<T: Io + 'static> {
     ready_responses: ResponseChunkReceiver,
 
     tokio_request_to_h2stream: HashMap<u64, u32>,
+
+    /// The state shared with the `H2Client`: the connection statistics and health, as well as the
+    /// means for the client-facing side to wake up the transport's task.
+    connection: SharedConnection,
+    /// The options that the connection was set up with.
+    options: ConnectionOptions,
+    /// The number of bytes of the connection's receive window that were consumed, but not yet
+    /// given back to the server.
+    unreleased_window: u32,
+    /// The PINGs that were sent out, but not yet acknowledged, keyed by their opaque data. Each
+    /// one remembers when it was sent, along with where to deliver the round-trip time.
+    in_flight_pings: HashMap<u64, (Instant, oneshot::Sender<Result<Duration, H2Error>>)>,
+    /// The largest header list (as defined by SETTINGS_MAX_HEADER_LIST_SIZE) that the server is
+    /// willing to accept. `None` if the server doesn't impose a limit.
+    max_header_list_size: Option<u32>,
+    /// Set once the server's initial SETTINGS have been received.
+    received_settings: bool,
+    /// Fires if the server doesn't acknowledge the client's SETTINGS in time. Cleared once they
+    /// are acknowledged; never set if no timeout is configured.
+    settings_ack_timer: Option<Timeout>,
+    /// Whether the server allows extended CONNECT requests (SETTINGS_ENABLE_CONNECT_PROTOCOL).
+    connect_protocol_enabled: bool,
+    /// A header block that is split across multiple frames and has not been fully received yet.
+    pending_header_block: Option<PendingHeaderBlock>,
+    /// The error that the connection failed with. It is only returned once all the requests that
+    /// were in flight have been failed.
+    connection_error: Option<Http2Error>,
+    /// The Tokio IDs of requests that failed before their body was fully handed over. Any
+    /// remaining body chunks of these requests are dropped.
+    failed_requests: HashSet<u64>,
+    /// The span of the connection, which everything that the transport does is done within.
+    span: Span,
+    /// Set on a connection that was upgraded from HTTP/1.1, until the request that it was
+    /// upgraded for has been mapped onto stream 1. No frames are read until then, as the response
+    /// to that request comes in on stream 1.
+    awaiting_upgrade_request: bool,
 }
 
 impl<T> H2ClientTokioTransport<T> where T: Io + 'static {
     /// Create a new `H2ClientTokioTransport` that will use the given `Io` for its underlying raw
-    /// IO needs.
-    fn new(io: T) -> H2ClientTokioTransport<T> {
+    /// IO needs. The connection statistics and health are recorded into the given
+    /// `SharedConnection`.
+    ///
+    /// If the connection was `upgraded` from HTTP/1.1, the first request handed to the transport
+    /// needs to be the one that the upgrade was done for. The `settings_ack_timer`, if any, fails
+    /// the connection unless the server acknowledges the client's SETTINGS before it fires.
+    fn new(io: T,
+           connection: SharedConnection,
+           options: ConnectionOptions,
+           span: Span,
+           upgraded: bool,
+           settings_ack_timer: Option<Timeout>)
+           -> H2ClientTokioTransport<T> {
         let (read, write) = io.split();
         H2ClientTokioTransport {
-            sender: FrameSender::new(write),
-            receiver: FrameReceiver::new(read),
+            sender: FrameSender::new(write,
+                                     options.frame_observer.clone(),
+                                     options.data_padding,
+                                     options.max_coalesced_write),
+            receiver: FrameReceiver::new(read, options.read_buffer_size),
             conn: ClientConnection::with_connection(
                 HttpConnection::new(HttpScheme::Http),
                 DefaultSessionState::<ClientMarker, H2Stream>::new()),
             ready_responses: ResponseChunkReceiver::new(),
             tokio_request_to_h2stream: HashMap::new(),
+            connection: connection,
+            options: options,
+            unreleased_window: 0,
+            in_flight_pings: HashMap::new(),
+            max_header_list_size: Some(DEFAULT_MAX_HEADER_LIST_SIZE),
+            received_settings: false,
+            settings_ack_timer: settings_ack_timer,
+            connect_protocol_enabled: false,
+            pending_header_block: None,
+            connection_error: None,
+            failed_requests: HashSet::new(),
+            span: span,
+            awaiting_upgrade_request: upgraded,
+        }
+    }
+
+    /// Maps the request that the connection was upgraded for onto stream 1. The request was
+    /// already sent in HTTP/1.1 (without a body), so the stream starts out half-closed on the
+    /// local end and nothing is sent for it.
+    fn adopt_upgrade_request(&mut self,
+                             request_id: u64,
+                             headers: Vec<StaticHeader>,
+                             shared: Option<SharedRequest>,
+                             span: Span)
+                             -> Result<(), Http2Error> {
+        if !self.awaiting_upgrade_request {
+            return Err(Http2Error::TokioSync(TokioSyncError::UnexpectedUpgradeRequest));
+        }
+        self.awaiting_upgrade_request = false;
+
+        if let Some(ref shared) = shared {
+            shared.set_sent_headers(headers);
+        }
+        let sender = self.ready_responses.get_sender(request_id);
+        let mut stream = H2Stream::new(sender,
+                                    self.connection.stats.clone(),
+                                    self.connection.windows.clone(),
+                                    span.clone());
+        stream.request = shared;
+        stream.max_response_headers = self.options.max_response_headers;
+        stream.max_response_header_bytes = self.options.max_response_header_bytes;
+        stream.close_local();
+        let stream_id = self.conn.state.insert_outgoing(stream);
+        if stream_id != 1 {
+            // Some other stream got opened first, so the upgrade response can't be told apart.
+            return Err(Http2Error::TokioSync(TokioSyncError::UnexpectedUpgradeRequest));
+        }
+
+        {
+            let stream = self.conn.state.get_stream_mut(stream_id).expect("stream _just_ created");
+            stream.stream_id = Some(stream_id);
+            if let Some(ref shared) = stream.request {
+                shared.set_stream_id(stream_id);
+            }
+        }
+        span.record_stream_id(stream_id);
+        self.connection.windows.borrow_mut().open_stream(stream_id);
+        if let Some(ref observer) = self.options.stream_start_observer {
+            observer.observe(request_id, stream_id);
         }
+        debug!("adopted the upgrade request; tokio request={}, h2 stream id=1", request_id);
+        self.tokio_request_to_h2stream.insert(request_id, stream_id);
+
+        let mut stats = self.connection.stats.borrow_mut();
+        stats.streams_opened += 1;
+        stats.active_streams += 1;
+
+        Ok(())
     }
 
     /// Kicks off a new HTTP request.
@@ -338,44 +947,147 @@ impl<T> H2ClientTokioTransport<T> where T: Io + 'static {
     ///
     /// Also starts tracking the mapping between the Tokio request ID (`request_id`) and the HTTP/2
     /// stream ID that it ends up getting assigned to.
+    ///
+    /// Requests are started synchronously, in the order that Tokio hands them to `start_send`:
+    /// stream IDs are assigned in that same order, and the HEADERS frame is serialized into the
+    /// sender's FIFO queue right away. Neither `poll_complete` nor `send_request_data` ever
+    /// reorders that queue (DATA frames only get appended after it), so the HEADERS of consecutive
+    /// requests always reach the socket in submission order, with consecutive odd stream IDs.
     fn start_request(&mut self,
                      request_id: u64,
                      headers: Vec<StaticHeader>,
-                     has_body: bool)
+                     shared: Option<SharedRequest>,
+                     has_body: bool,
+                     raw: bool,
+                     span: Span)
                      -> Result<(), Http2Error> {
-        let request = self.prepare_request(request_id, headers, has_body);
+        // Reusing the ID would orphan the stream that it's currently mapped to.
+        if self.is_request_in_flight(request_id) {
+            debug!("request id={} is already in flight", request_id);
+            return Err(Http2Error::TokioSync(TokioSyncError::DuplicateRequestId));
+        }
+        let uncompressed_len = header_bytes(&headers);
+        let request = self.prepare_request(request_id, headers, shared, has_body, raw, span.clone())?;
 
         // Start the request, obtaining the h2 stream ID.
+        let queued_before = self.sender.bytes_queued();
         let stream_id = self.conn.start_request(request, &mut self.sender)?;
+        {
+            // Nothing but the (compressed) header block got queued up.
+            let mut stats = self.connection.stats.borrow_mut();
+            stats.request_header_bytes += uncompressed_len as u64;
+            stats.request_header_bytes_sent += self.sender.bytes_queued() - queued_before;
+        }
 
         // The ID has been assigned to the stream, so attach it to the stream instance too.
         // TODO(mlalic): The `solicit::Stream` trait should grow an `on_id_assigned` method which
         //               would be called by the session (i.e. the `ClientConnection` in this case).
         //               Indeed, this is slightly awkward...
-        self.conn.state.get_stream_mut(stream_id).expect("stream _just_ created").stream_id = Some(stream_id);
+        let background = {
+            let stream = self.conn.state.get_stream_mut(stream_id).expect("stream _just_ created");
+            stream.stream_id = Some(stream_id);
+            if let Some(ref shared) = stream.request {
+                shared.set_stream_id(stream_id);
+            }
+            stream.request.as_ref().map_or(false, |shared| shared.take_background_request())
+        };
+        let weight = {
+            let stream = self.conn.state.get_stream_mut(stream_id).expect("stream _just_ created");
+            stream.request.as_ref().and_then(|shared| shared.take_priority_request())
+        };
+        span.record_stream_id(stream_id);
+        self.connection.windows.borrow_mut().open_stream(stream_id);
+        if let Some(ref observer) = self.options.stream_start_observer {
+            observer.observe(request_id, stream_id);
+        }
+        if background {
+            self.make_background(stream_id);
+        }
+        if let Some(weight) = weight {
+            self.reprioritize(stream_id, weight);
+        }
 
         // Now that the h2 request has started, we can keep the mapping of the Tokio request ID to
         // the matching h2 stream. This is used once data chunks start coming in, so we can match
         // up the request ID we get there to the h2 stream that the data chunk should be given to.
         debug!("started new request; tokio request={}, h2 stream id={}", request_id, stream_id);
         self.tokio_request_to_h2stream.insert(request_id, stream_id);
+
+        let mut stats = self.connection.stats.borrow_mut();
+        stats.streams_opened += 1;
+        stats.active_streams += 1;
+
         Ok(())
     }
 
     /// Prepares a new RequestStream with the given headers. If the request won't have any body, it
     /// immediately closes the stream on the local end to ensure that the peer doesn't expect any
     /// data to come in on the stream.
-    fn prepare_request(&mut self, request_id: u64, headers: Vec<StaticHeader>, has_body: bool)
-            -> RequestStream<'static, 'static, H2Stream> {
-        let mut stream = H2Stream::new(self.ready_responses.get_sender(request_id));
+    ///
+    /// Fails if the given headers are not a well-formed set of request headers, before anything
+    /// is queued up on the connection. For a `raw` request, none of this is checked: its headers
+    /// go out exactly as given.
+    fn prepare_request(&mut self,
+                       request_id: u64,
+                       headers: Vec<StaticHeader>,
+                       shared: Option<SharedRequest>,
+                       has_body: bool,
+                       raw: bool,
+                       span: Span)
+                       -> Result<RequestStream<'static, 'static, H2Stream>, Http2Error> {
+        if !raw {
+            self.check_request_headers(&headers)?;
+        }
+
+        if let Some(ref shared) = shared {
+            shared.set_sent_headers(headers.clone());
+        }
+        let sender = self.ready_responses.get_sender(request_id);
+        let mut stream = H2Stream::new(sender,
+                                    self.connection.stats.clone(),
+                                    self.connection.windows.clone(),
+                                    span);
+        stream.request = shared;
+        stream.max_response_headers = self.options.max_response_headers;
+        stream.max_response_header_bytes = self.options.max_response_header_bytes;
+        stream.response_bodyless = headers.iter()
+            .any(|header| header.name() == b":method" && header.value() == b"HEAD");
         if !has_body {
             stream.close_local();
+        } else {
+            stream.content_length = headers.iter()
+                .find(|header| header.name() == b"content-length")
+                .and_then(|header| parse_content_length(header.value()));
+            stream.max_body_length = self.options.max_request_body_size;
+            stream.data_padding = self.options.data_padding;
+            // Hold back the body until the server agrees to receive it, if asked to.
+            stream.awaiting_continue = headers.iter().any(|header| {
+                header.name() == b"expect" && header.value().eq_ignore_ascii_case(b"100-continue")
+            });
         }
 
-        RequestStream {
+        Ok(RequestStream {
             stream: stream,
             headers: headers,
+        })
+    }
+
+    /// Checks that the given headers are a well-formed set of request headers, which the server
+    /// is willing to accept.
+    fn check_request_headers(&self, headers: &[StaticHeader]) -> Result<(), Http2Error> {
+        validate_request_headers(headers)?;
+        let is_extended_connect = headers.iter().any(|header| header.name() == b":protocol");
+        if is_extended_connect && !self.connect_protocol_enabled {
+            return Err(Http2Error::ExtendedConnectNotSupported);
+        }
+        if let Some(max_size) = self.max_header_list_size {
+            let size = header_list_size(headers);
+            if size > max_size as usize {
+                debug!("header list size {} exceeds the server's limit {}", size, max_size);
+                return Err(InvalidHeadersError::HeaderListTooLarge.into());
+            }
         }
+        Ok(())
     }
 
     /// Handles all frames currently found in the in buffer. After this completes, the buffer will
@@ -384,37 +1096,690 @@ impl<T> H2ClientTokioTransport<T> where T: Io + 'static {
     fn handle_new_frames(&mut self) -> Result<(), Http2Error> {
         // We have new data. Let's try parsing and handling as many h2
         // frames as we can!
-        while let Some(bytes_to_discard) = self.handle_next_frame()? {
+        loop {
+            let bytes_to_discard = match self.handle_next_frame() {
+                Ok(Some(bytes_to_discard)) => bytes_to_discard,
+                Ok(None) => return Ok(()),
+                Err(Http2Error::Protocol(HttpError::CompressionError(err))) => {
+                    // The HPACK decoder's state is shared by the whole connection and can't be
+                    // trusted after a header block that it failed to decode, so no later header
+                    // block could be decoded either: it's a connection error of type
+                    // COMPRESSION_ERROR.
+                    debug!("failed to decode a header block: {:?}", err);
+                    return self.send_goaway_and_fail(ErrorCode::CompressionError,
+                                                     HttpError::CompressionError(err).into());
+                },
+                Err(Http2Error::ResponseHeadersTooLarge) => {
+                    // The header block was decoded, updating the shared HPACK state, so the
+                    // stream can't simply be reset: the connection is failed as a whole.
+                    return self.send_goaway_and_fail(ErrorCode::EnhanceYourCalm,
+                                                     Http2Error::ResponseHeadersTooLarge);
+                },
+                Err(err) => return Err(err),
+            };
             // So far, the frame wasn't copied out of the original input buffer.
             // Now, we'll simply discard from the input buffer...
             self.receiver.discard_frame(bytes_to_discard);
         }
-
-        Ok(())
     }
 
     /// Handles the next frame in the in buffer (if any) and returns its size in bytes. These bytes
     /// can now safely be discarded from the in buffer, as they have been processed by the h2
     /// connection.
     fn handle_next_frame(&mut self) -> Result<Option<usize>, Http2Error> {
+        let mut released_padding = None;
+        let mut goaway = None;
+        let mut pong = None;
+        let mut reset = None;
+        let mut stray_data = None;
+        let mut early_data = None;
+        let mut orphaned_data = None;
+        let mut acked_settings = false;
+        let mut invalid_window_update = None;
         let res = match self.receiver.get_next_frame() {
             None => None,
             Some(mut frame_container) => {
-                // Give the frame_container to the conn...
-                self.conn.handle_next_frame(&mut frame_container, &mut self.sender)?;
+                let (length, frame_type, flags, stream_id) = frame_container.header();
+                if let Some(ref observer) = self.options.frame_observer {
+                    let header = frame_container.header();
+                    observer.observe(&frame_info(FrameDirection::Received, header));
+                }
+                if !self.received_settings && (frame_type != SETTINGS_FRAME_TYPE
+                                               || flags & ACK_FLAG != 0) {
+                    // The server's preface has to be a SETTINGS frame; whoever sent anything
+                    // else isn't speaking HTTP/2, so nothing more it sends can be trusted.
+                    debug!("expected SETTINGS as the first frame, got type {:x}", frame_type);
+                    return self.send_goaway_and_fail(ErrorCode::ProtocolError,
+                                                     Http2Error::MissingSettings);
+                }
+                if frame_type == HEADERS_FRAME_TYPE || frame_type == CONTINUATION_FRAME_TYPE {
+                    let len = frame_container.len() as u64;
+                    self.connection.stats.borrow_mut().response_header_bytes_received += len;
+                }
+                let continues_block = frame_type == HEADERS_FRAME_TYPE
+                    && flags & END_HEADERS_FLAG == 0;
+                if continues_block || self.pending_header_block.is_some() {
+                    // The connection can only decode complete header blocks, so the fragments
+                    // are collected and the whole block is handed over as a single frame.
+                    let block = buffer_header_fragment(&mut self.pending_header_block,
+                                                       frame_container.header(),
+                                                       frame_container.payload())?;
+                    if let Some(raw) = block {
+                        let frame = RawFrame::parse(&raw).expect("a complete serialized frame");
+                        let mut assembled = FrameContainer::new(frame);
+                        self.conn.handle_next_frame(&mut assembled, &mut self.sender)?;
+                        let exceeded = self.conn.state.get_stream_mut(stream_id)
+                            .map_or(false, |stream| stream.header_limit_exceeded);
+                        if exceeded {
+                            return Err(Http2Error::ResponseHeadersTooLarge);
+                        }
+                    }
 
+                    self.connection.stats.borrow_mut().bytes_received += frame_container.len() as u64;
+                    return Ok(Some(frame_container.len()));
+                }
+                if frame_type == RST_STREAM_FRAME_TYPE && frame_container.payload().len() >= 4 {
+                    // Remember that the stream was reset, so that it can be told apart from
+                    // streams that got closed normally once it gets cleaned up, and so that the
+                    // request fails instead of ending normally once the stream is closed.
+                    let error_code = ErrorCode::from(read_u32(frame_container.payload()));
+                    reset = Some(stream_id);
+                    if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+                        stream.was_reset = true;
+                        // If the full response already came in (e.g. the server resets the
+                        // stream to stop an upload that it no longer needs), the request has
+                        // succeeded.
+                        if !stream.is_closed_remote() {
+                            stream.failure = Some(RequestFailure {
+                                reason: RequestFailureReason::StreamReset(error_code),
+                                // A refused stream is guaranteed not to have been processed.
+                                retry_safety: if error_code == ErrorCode::RefusedStream {
+                                    RetrySafety::Safe
+                                } else {
+                                    RetrySafety::Unknown
+                                },
+                            }.into());
+                        }
+                    }
+                }
+                if frame_type == GOAWAY_FRAME_TYPE && frame_container.payload().len() >= 8 {
+                    let payload = frame_container.payload();
+                    let last_stream_id = read_u32(payload) & 0x7fffffff;
+                    let error_code = ErrorCode::from(read_u32(&payload[4..]));
+                    goaway = Some((last_stream_id, error_code));
+                }
+                if frame_type == SETTINGS_FRAME_TYPE && flags & ACK_FLAG == 0 {
+                    let payload = frame_container.payload();
+                    if payload.len() % 6 != 0 {
+                        debug!("SETTINGS with a payload of {} bytes", payload.len());
+                        return self.send_goaway_and_fail(ErrorCode::FrameSizeError,
+                                                         Http2Error::Protocol(
+                                                             HttpError::InvalidFrame));
+                    }
+                    if stream_id != 0 {
+                        debug!("SETTINGS on stream {}", stream_id);
+                        return self.send_goaway_and_fail(ErrorCode::ProtocolError,
+                                                         Http2Error::Protocol(
+                                                             HttpError::InvalidFrame));
+                    }
+                    // The limit only applies if the server advertises it. Until the first
+                    // SETTINGS come in, a conservative default is applied instead.
+                    match find_setting(payload, SETTINGS_MAX_HEADER_LIST_SIZE) {
+                        Some(max_size) => self.max_header_list_size = Some(max_size),
+                        None if !self.received_settings => self.max_header_list_size = None,
+                        None => {},
+                    }
+                    if let Some(size) = find_setting(payload, SETTINGS_INITIAL_WINDOW_SIZE) {
+                        self.connection.windows.borrow_mut().set_initial_send(size);
+                    }
+                    if let Some(enabled) = find_setting(payload, SETTINGS_ENABLE_CONNECT_PROTOCOL) {
+                        self.connect_protocol_enabled = enabled == 1;
+                    }
+                    self.received_settings = true;
+                    // The SETTINGS are acknowledged here rather than by the `HttpConnection`,
+                    // which never gets to see them, and the ACK is flushed once the frame is
+                    // handled. The `HttpConnection` wouldn't apply any of them either, so the
+                    // ones not handled above are deliberately dropped: HEADER_TABLE_SIZE and
+                    // MAX_FRAME_SIZE (the encoder's table and the frames that are sent stay within
+                    // the protocol's defaults), MAX_CONCURRENT_STREAMS (a server that is over its
+                    // limit refuses the stream) and ENABLE_PUSH (which only clients send).
+                    trace!("acknowledging the server's SETTINGS");
+                    self.sender.send_raw_frame(pack_frame(SETTINGS_FRAME_TYPE, ACK_FLAG, 0, &[]));
+                    acked_settings = true;
+                }
+                if frame_type == SETTINGS_FRAME_TYPE && flags & ACK_FLAG != 0
+                        && self.settings_ack_timer.take().is_some() {
+                    debug!("the server acknowledged our SETTINGS");
+                }
+                if frame_type == PING_FRAME_TYPE && flags & ACK_FLAG != 0
+                        && frame_container.payload().len() == 8 {
+                    let payload = frame_container.payload();
+                    pong = Some(((read_u32(payload) as u64) << 32) | read_u32(&payload[4..]) as u64);
+                }
+                if frame_type == WINDOW_UPDATE_FRAME_TYPE && frame_container.payload().len() == 4 {
+                    let increment = read_u32(frame_container.payload()) & 0x7fffffff;
+                    let error = if increment == 0 {
+                        Some((ErrorCode::ProtocolError, Http2Error::ZeroWindowIncrement))
+                    } else if !self.connection.windows.borrow_mut()
+                            .send_window_update(stream_id, increment) {
+                        Some((ErrorCode::FlowControlError, Http2Error::WindowOverflow))
+                    } else {
+                        None
+                    };
+                    match error {
+                        Some((error_code, err)) if stream_id == 0 => {
+                            // A connection error: the whole connection goes down with it.
+                            debug!("invalid connection WINDOW_UPDATE: {}", err);
+                            return self.send_goaway_and_fail(error_code, err);
+                        },
+                        Some((error_code, err)) => {
+                            invalid_window_update = Some((stream_id, error_code, err));
+                        },
+                        None => {},
+                    }
+                }
+                if frame_type == DATA_FRAME_TYPE {
+                    self.connection.windows.borrow_mut().data_received(stream_id, length);
+                    // Once the server has ended its side of a stream, it can't send any more
+                    // DATA on it. Such a frame never reaches the stream, which already has
+                    // its body complete.
+                    let closed_remote = self.conn.state.get_stream_mut(stream_id)
+                        .map_or(false, |stream| stream.is_closed_remote());
+                    if closed_remote {
+                        stray_data = Some((stream_id, length));
+                    }
+                    // A response can't start with its body, so DATA that comes in before the
+                    // response headers never reaches the stream either.
+                    let before_headers = self.conn.state.get_stream_mut(stream_id)
+                        .map_or(false, |stream| !stream.is_closed_remote()
+                                                && !stream.headers_received);
+                    if before_headers {
+                        early_data = Some((stream_id, length));
+                    }
+                    // DATA can keep coming in for a while on a stream that was reset (and cleaned
+                    // up) in the meantime. The stream is gone, but the frame still counts against
+                    // the connection's flow control window.
+                    if stream_id != 0 && self.conn.state.get_stream_mut(stream_id).is_none() {
+                        orphaned_data = Some(length);
+                    }
+                }
+                if frame_type == DATA_FRAME_TYPE && flags & PADDED_FLAG != 0
+                        && stray_data.is_none() && early_data.is_none() {
+                    // The padding counts against the flow control window, but never makes it
+                    // into a body chunk, so its share of the window can be returned right away.
+                    // The padding is made up of the pad length field and the padding itself.
+                    let pad_length = frame_container.payload().first().map_or(0, |&len| len);
+                    let padding = ::std::cmp::min(1 + pad_length as u32, length);
+                    released_padding = Some((stream_id, padding));
+                }
+
+                // Give the frame_container to the conn, unless the frame was fully handled here
+                // (the `HttpConnection` would acknowledge the server's SETTINGS once more, and
+                // do nothing else with them)...
+                if stray_data.is_none() && early_data.is_none() && !acked_settings {
+                    self.conn.handle_next_frame(&mut frame_container, &mut self.sender)?;
+                }
+                if frame_type == HEADERS_FRAME_TYPE {
+                    let exceeded = self.conn.state.get_stream_mut(stream_id)
+                        .map_or(false, |stream| stream.header_limit_exceeded);
+                    if exceeded {
+                        return Err(Http2Error::ResponseHeadersTooLarge);
+                    }
+                }
+
+                self.connection.stats.borrow_mut().bytes_received += frame_container.len() as u64;
                 Some(frame_container.len())
             },
         };
 
+        if acked_settings {
+            self.flush_sender()?;
+        }
+        if let Some((stream_id, padding)) = released_padding {
+            self.release_recv_window(stream_id, padding)?;
+        }
+        if let Some((last_stream_id, error_code)) = goaway {
+            self.handle_goaway(last_stream_id, error_code);
+        }
+        if let Some(opaque_data) = pong {
+            self.handle_pong(opaque_data);
+        }
+        if let Some(stream_id) = reset {
+            self.handle_stream_reset(stream_id);
+        }
+        if let Some((stream_id, length)) = stray_data {
+            self.handle_data_after_close(stream_id, length)?;
+        }
+        if let Some((stream_id, length)) = early_data {
+            self.handle_data_before_headers(stream_id, length)?;
+        }
+        if let Some(length) = orphaned_data {
+            self.unreleased_window += length;
+            self.release_connection_window()?;
+        }
+        if let Some((stream_id, error_code, err)) = invalid_window_update {
+            self.handle_invalid_window_update(stream_id, error_code, err)?;
+        }
+
         Ok(res)
     }
 
+    /// Handles a WINDOW_UPDATE for the given stream that was invalid (i.e. had a zero increment
+    /// or overflowed the stream's window), which is a stream error: the stream is reset with the
+    /// given error code and its request fails with the given error.
+    fn handle_invalid_window_update(&mut self,
+                                    stream_id: StreamId,
+                                    error_code: ErrorCode,
+                                    err: Http2Error)
+                                    -> Result<(), Http2Error> {
+        debug!("invalid WINDOW_UPDATE on stream {}: {}; resetting it", stream_id, err);
+        self.sender.send_frame(RstStreamFrame::new(stream_id, error_code))?;
+        if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+            stream.was_reset = true;
+            if !stream.is_closed_remote() {
+                stream.failure = Some(err.into());
+            }
+            stream.close();
+        }
+        self.handle_closed_streams();
+
+        Ok(())
+    }
+
+    /// Handles a DATA frame of the given length that came in on a stream that the server had
+    /// already closed from its side, which is a stream error of type STREAM_CLOSED: the stream
+    /// is reset. The response itself was already complete, so it's left alone.
+    fn handle_data_after_close(&mut self, stream_id: StreamId, length: u32)
+            -> Result<(), Http2Error> {
+        debug!("DATA on stream {} after the server closed it; resetting it", stream_id);
+        self.sender.send_frame(RstStreamFrame::new(stream_id, ErrorCode::StreamClosed))?;
+        if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+            stream.was_reset = true;
+            stream.close();
+        }
+        // The frame still counts against the connection's flow control window.
+        self.release_recv_window(stream_id, length)?;
+        self.handle_closed_streams();
+
+        Ok(())
+    }
+
+    /// Handles a DATA frame of the given length that came in on a stream before its response
+    /// headers, which makes the response malformed: the stream is reset with PROTOCOL_ERROR and,
+    /// as no response ever came in, the request fails with `NoResponse` (see `set_state`).
+    fn handle_data_before_headers(&mut self, stream_id: StreamId, length: u32)
+            -> Result<(), Http2Error> {
+        debug!("DATA on stream {} before its response headers; resetting it", stream_id);
+        self.sender.send_frame(RstStreamFrame::new(stream_id, ErrorCode::ProtocolError))?;
+        if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+            stream.was_reset = true;
+            stream.close();
+        }
+        // The frame still counts against the connection's flow control window.
+        self.release_recv_window(stream_id, length)?;
+        self.handle_closed_streams();
+
+        Ok(())
+    }
+
+    /// Makes sure that a stream that the server reset is closed and cleaned up, whatever state it
+    /// was in when the RST_STREAM frame came in: before the response headers, in the middle of
+    /// the response body, or after the request body was fully sent.
+    fn handle_stream_reset(&mut self, stream_id: StreamId) {
+        if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+            if !stream.is_closed() {
+                debug!("closing stream {} after it got reset", stream_id);
+                stream.close();
+            }
+        }
+        self.handle_closed_streams();
+    }
+
+    /// Handles the server going away: all streams above the last stream ID that the server
+    /// reported as processed are failed, as the server will never respond to them. As the server
+    /// did not process these requests, they are safe to retry.
+    ///
+    /// Streams at or below the last stream ID are left to complete normally.
+    fn handle_goaway(&mut self, last_stream_id: StreamId, error_code: ErrorCode) {
+        debug!("received GOAWAY; last stream id={}, error code={:?}", last_stream_id, error_code);
+        self.connection.set_goaway_received(error_code);
+
+        let unprocessed: Vec<StreamId> = self.conn.state.iter()
+            .map(|(id, _stream)| *id)
+            .filter(|id| *id > last_stream_id)
+            .collect();
+        for stream_id in unprocessed {
+            if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+                stream.failure = Some(RequestFailure {
+                    reason: RequestFailureReason::GoAway(error_code),
+                    retry_safety: RetrySafety::Safe,
+                }.into());
+                stream.close();
+            }
+        }
+
+        self.handle_closed_streams();
+    }
+
+    /// Resolves the PING that the received PING ACK, carrying the given opaque data, belongs to.
+    /// ACKs that don't match any PING that was sent out are ignored.
+    fn handle_pong(&mut self, opaque_data: u64) {
+        match self.in_flight_pings.remove(&opaque_data) {
+            Some((sent_at, complete)) => {
+                let rtt = Instant::now() - sent_at;
+                debug!("received PING ACK; opaque data={}, rtt={:?}", opaque_data, rtt);
+                let _ = complete.send(Ok(rtt));
+            },
+            None => debug!("ignoring unexpected PING ACK; opaque data={}", opaque_data),
+        }
+    }
+
+    /// Sends out all the PINGs that the client-facing side has asked for.
+    fn handle_ping_requests(&mut self) -> Result<(), Http2Error> {
+        let requests = self.connection.take_ping_requests();
+        if requests.is_empty() {
+            return Ok(());
+        }
+
+        for request in requests {
+            debug!("sending PING; opaque data={}", request.opaque_data);
+            self.sender.send_frame(PingFrame::with_data(request.opaque_data))?;
+            self.in_flight_pings.insert(request.opaque_data, (Instant::now(), request.complete));
+        }
+        self.flush_sender()?;
+
+        Ok(())
+    }
+
+    /// Fails all the PINGs that are still waiting for an ACK (or still need to be sent out), as
+    /// the connection was lost.
+    fn fail_pings(&mut self) {
+        let queued = self.connection.take_ping_requests().into_iter().map(|request| request.complete);
+        let in_flight = self.in_flight_pings.drain().map(|(_, (_, complete))| complete);
+        for complete in queued.chain(in_flight) {
+            let _ = complete.send(Err(H2Error::ConnectionLost));
+        }
+    }
+
+    /// Fails all the streams that are still in flight, as the connection was lost. Each of their
+    /// requests fails with a connection-level error, instead of just being dropped along with the
+    /// connection.
+    fn fail_all_streams(&mut self, reason: RequestFailureReason) {
+        let in_flight: Vec<StreamId> = self.conn.state.iter().map(|(id, _stream)| *id).collect();
+        debug!("connection lost; failing {} in-flight streams", in_flight.len());
+        for stream_id in in_flight {
+            if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+                stream.failure = Some(RequestFailure {
+                    reason: reason,
+                    retry_safety: RetrySafety::Unknown,
+                }.into());
+                stream.close();
+            }
+        }
+
+        self.handle_closed_streams();
+    }
+
+    /// Marks the connection as failed with the given error: all in-flight requests and PINGs
+    /// fail, and the error itself is handed to Tokio once the failed requests have been handed
+    /// back (from `poll`).
+    ///
+    /// Used both when reading and when writing to the socket fails.
+    fn fail_connection(&mut self, err: Http2Error) {
+        if self.connection_error.is_some() {
+            // Already failed; the first error is the one that gets reported.
+            return;
+        }
+        debug!("connection failed: {}", err);
+        let reason = match err {
+            Http2Error::IoError(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                ClosedReason::Eof
+            },
+            Http2Error::Aborted => ClosedReason::Aborted,
+            ref err => ClosedReason::Error(err.to_string()),
+        };
+        self.connection.set_closed(reason);
+        self.fail_all_streams(match err {
+            Http2Error::Aborted => RequestFailureReason::Aborted,
+            _ => RequestFailureReason::ConnectionLost,
+        });
+        self.fail_pings();
+        self.connection_error = Some(err);
+    }
+
     /// Cleans up all closed streams.
     fn handle_closed_streams(&mut self) {
-        // Simply let them get dropped.
+        // Simply let them get dropped, after recording how they ended up closed.
         let done = self.conn.state.get_closed();
         debug!("Number of streams that got closed = {}", done.len());
+
+        let mut stats = self.connection.stats.borrow_mut();
+        let mut windows = self.connection.windows.borrow_mut();
+        for stream in done.iter() {
+            // Dropping the stream closes its span, after this final entry.
+            let _entered = stream.span.enter();
+            debug!("stream {:?} closed; reset={}", stream.stream_id, stream.was_reset);
+            if let Some(stream_id) = stream.stream_id {
+                windows.close_stream(stream_id);
+            }
+            if stream.was_reset {
+                stats.streams_reset += 1;
+            } else {
+                stats.streams_closed += 1;
+            }
+        }
+        stats.active_streams -= done.len();
+    }
+
+    /// Resets all streams for which the client-facing side has asked for it, by sending out a
+    /// RST_STREAM frame and closing the stream.
+    fn handle_reset_requests(&mut self) -> Result<(), Http2Error> {
+        let to_reset: Vec<(StreamId, ErrorCode)> = self.conn.state.iter()
+            .filter_map(|(id, stream)| {
+                let code = stream.request.as_ref().and_then(|shared| shared.take_reset_request());
+                code.map(|code| (*id, code))
+            })
+            .collect();
+        if to_reset.is_empty() {
+            return Ok(());
+        }
+
+        for (stream_id, error_code) in to_reset {
+            debug!("resetting stream {} on request; error code={:?}", stream_id, error_code);
+            self.sender.send_frame(RstStreamFrame::new(stream_id, error_code))?;
+            if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+                stream.was_reset = true;
+                // Whatever is left of the response is never going to arrive.
+                if !stream.is_closed_remote() {
+                    stream.failure = Some(RequestFailure {
+                        reason: RequestFailureReason::Canceled,
+                        retry_safety: RetrySafety::Unknown,
+                    }.into());
+                }
+                stream.close();
+            }
+        }
+
+        self.handle_closed_streams();
+        self.flush_sender()?;
+
+        Ok(())
+    }
+
+    /// Turns all streams for which the client-facing side has asked for it into background
+    /// streams.
+    fn handle_background_requests(&mut self) -> Result<(), Http2Error> {
+        let to_background: Vec<StreamId> = self.conn.state.iter()
+            .filter(|&(_id, ref stream)| {
+                stream.request.as_ref().map_or(false, |shared| shared.take_background_request())
+            })
+            .map(|(id, _stream)| *id)
+            .collect();
+        if to_background.is_empty() {
+            return Ok(());
+        }
+
+        for stream_id in to_background {
+            self.make_background(stream_id);
+        }
+        self.flush_sender()?;
+
+        Ok(())
+    }
+
+    /// Turns the given stream into a background stream, asking the server to give it the lowest
+    /// possible priority by sending out a PRIORITY frame.
+    fn make_background(&mut self, stream_id: StreamId) {
+        debug!("turning stream {} into a background stream", stream_id);
+        if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+            stream.background = true;
+        }
+        // A non-exclusive dependency on the root (stream 0) with a weight of 1, which is encoded
+        // as 0.
+        let payload = [0, 0, 0, 0, 0];
+        self.sender.send_raw_frame(pack_frame(PRIORITY_FRAME_TYPE, 0, stream_id, &payload));
+    }
+
+    /// Re-prioritizes all streams for which the client-facing side has asked for a new weight.
+    fn handle_priority_requests(&mut self) -> Result<(), Http2Error> {
+        let to_reprioritize: Vec<(StreamId, u16)> = self.conn.state.iter()
+            .filter_map(|(id, stream)| {
+                stream.request.as_ref()
+                    .and_then(|shared| shared.take_priority_request())
+                    .map(|weight| (*id, weight))
+            })
+            .collect();
+        if to_reprioritize.is_empty() {
+            return Ok(());
+        }
+
+        for (stream_id, weight) in to_reprioritize {
+            self.reprioritize(stream_id, weight);
+        }
+        self.flush_sender()?;
+
+        Ok(())
+    }
+
+    /// Gives the given stream a new weight, both for scheduling its request data and on the
+    /// server's end, by sending out a PRIORITY frame. A stream that is already closed is left
+    /// alone, as there's nothing left to prioritize.
+    fn reprioritize(&mut self, stream_id: StreamId, weight: u16) {
+        match self.conn.state.get_stream_mut(stream_id) {
+            Some(ref mut stream) if !stream.is_closed() => stream.weight = weight,
+            _ => {
+                debug!("not re-prioritizing stream {}, as it is already closed", stream_id);
+                return;
+            },
+        }
+        debug!("re-prioritizing stream {}; weight={}", stream_id, weight);
+        // A non-exclusive dependency on the root (stream 0), with the weight encoded as one less
+        // than its actual value.
+        let payload = [0, 0, 0, 0, (weight - 1) as u8];
+        self.sender.send_raw_frame(pack_frame(PRIORITY_FRAME_TYPE, 0, stream_id, &payload));
+    }
+
+    /// Returns whether any foreground stream is still receiving its response.
+    fn has_foreground_responses(&mut self) -> bool {
+        self.conn.state.iter()
+            .any(|(_id, stream)| !stream.background && !stream.is_closed_remote())
+    }
+
+    /// Makes the streams hold back their request data while a stream that takes precedence over
+    /// them has some of its own ready to be sent: background streams yield to any foreground
+    /// stream, while foreground streams yield to the ones with a higher weight.
+    fn update_yielding(&mut self) {
+        let top_weight = self.conn.state.iter()
+            .filter(|&(_id, ref stream)| !stream.background && stream.has_data_ready())
+            .map(|(_id, stream)| stream.weight)
+            .max();
+        let stream_ids: Vec<StreamId> = self.conn.state.iter()
+            .map(|(id, _stream)| *id)
+            .collect();
+        for stream_id in stream_ids {
+            if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+                let yielding = top_weight.map_or(false, |top| {
+                    stream.background || stream.weight < top
+                });
+                stream.yielding = yielding;
+            }
+        }
+    }
+
+    /// Gives back the flow control windows that were held back for background streams, once no
+    /// foreground response is being received anymore.
+    fn release_background_windows(&mut self) -> Result<(), Http2Error> {
+        if self.has_foreground_responses() {
+            return Ok(());
+        }
+
+        let held_back: Vec<StreamId> = self.conn.state.iter()
+            .filter(|&(_id, ref stream)| {
+                stream.background && !stream.is_closed_remote() && stream.unreleased_window > 0
+            })
+            .map(|(id, _stream)| *id)
+            .collect();
+        for stream_id in held_back {
+            let increment = match self.conn.state.get_stream_mut(stream_id) {
+                Some(stream) => mem::replace(&mut stream.unreleased_window, 0),
+                None => continue,
+            };
+            self.sender.send_frame(WindowUpdateFrame::for_stream(stream_id, increment))?;
+            self.connection.windows.borrow_mut().recv_window_update(stream_id, increment);
+        }
+
+        Ok(())
+    }
+
+    /// Aborts the connection: all in-flight requests fail right away, and the server is told that
+    /// the connection is going away, if the socket still takes it. Has no effect on a connection
+    /// that already failed.
+    fn abort(&mut self) {
+        if self.connection_error.is_some() {
+            return;
+        }
+        debug!("aborting the connection");
+        // Best effort: the connection is aborted whether the GOAWAY makes it or not.
+        if self.sender.send_frame(GoawayFrame::new(0, ErrorCode::Cancel)).is_ok() {
+            let _ = self.flush_sender();
+        }
+        self.fail_connection(Http2Error::Aborted);
+    }
+
+    /// Checks whether the server failed to acknowledge the client's SETTINGS in time, in which
+    /// case the connection is torn down with a SETTINGS_TIMEOUT error.
+    fn check_settings_ack(&mut self) -> Result<(), Http2Error> {
+        let expired = match self.settings_ack_timer {
+            Some(ref mut timer) => timer.poll()?.is_ready(),
+            None => false,
+        };
+        if !expired {
+            return Ok(());
+        }
+
+        debug!("the server did not acknowledge our SETTINGS in time");
+        self.settings_ack_timer = None;
+        self.send_goaway_and_fail(ErrorCode::SettingsTimeout, Http2Error::SettingsTimeout)
+    }
+
+    /// Tells the server that the connection is going away with the given error code, returning
+    /// the given error, which the connection then fails with.
+    fn send_goaway_and_fail<T>(&mut self, error_code: ErrorCode, err: Http2Error)
+            -> Result<T, Http2Error> {
+        self.sender.send_frame(GoawayFrame::new(0, error_code))?;
+        // Best effort: the connection is failed whether the GOAWAY makes it or not.
+        let _ = self.flush_sender();
+        Err(err)
+    }
+
+    /// Try to write out everything that is currently buffered in the `sender`, keeping the
+    /// connection statistics up to date with the number of bytes written.
+    ///
+    /// Returns `true` if everything was written without blocking.
+    fn flush_sender(&mut self) -> io::Result<bool> {
+        let res = self.sender.try_write();
+        self.connection.stats.borrow_mut().bytes_sent = self.sender.bytes_written();
+        res
     }
 
     /// Try to read more data off the socket and handle any HTTP/2 frames that we might
@@ -428,10 +1793,67 @@ impl<T> H2ClientTokioTransport<T> where T: Io + 'static {
             // After processing frames, let's see if there are any streams that have been completed
             // as a result...
             self.handle_closed_streams();
+            // ...which might have been what held back the background streams.
+            self.release_background_windows()?;
 
             // Make sure to issue a write for anything that might have been queued up
             // during the processing of the frames...
-            self.sender.try_write()?;
+            self.flush_sender()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the given amount of the flow control window back to the server, for both the
+    /// connection and the given stream.
+    ///
+    /// To avoid flooding the connection with tiny WINDOW_UPDATE frames, the released bytes are
+    /// accumulated and only sent out once they exceed the configured threshold. No stream-level
+    /// update is sent for streams that can't receive any more data anyway.
+    fn release_recv_window(&mut self, stream_id: StreamId, size: u32) -> Result<(), Http2Error> {
+        if size == 0 {
+            return Ok(());
+        }
+        let threshold = self.options.window_update_threshold;
+        let foreground_active = self.has_foreground_responses();
+
+        let stream_update = match self.conn.state.get_stream_mut(stream_id) {
+            Some(stream) => {
+                if stream.is_closed_remote() {
+                    None
+                } else {
+                    stream.unreleased_window += size;
+                    // A background stream's window is held back while any foreground response
+                    // is still being received, so that it doesn't compete with it.
+                    let held_back = stream.background && foreground_active;
+                    if stream.unreleased_window > threshold && !held_back {
+                        Some(mem::replace(&mut stream.unreleased_window, 0))
+                    } else {
+                        None
+                    }
+                }
+            },
+            None => None,
+        };
+        if let Some(increment) = stream_update {
+            self.sender.send_frame(WindowUpdateFrame::for_stream(stream_id, increment))?;
+            self.connection.windows.borrow_mut().recv_window_update(stream_id, increment);
+        }
+
+        self.unreleased_window += size;
+        self.release_connection_window()
+    }
+
+    /// Gives the consumed part of the connection's flow control window back to the server, once
+    /// it exceeds the configured threshold. It's held back for as long as the application has
+    /// more response bytes buffered than allowed, though.
+    fn release_connection_window(&mut self) -> Result<(), Http2Error> {
+        let over_budget = self.options.max_buffered_response_bytes
+            .map_or(false, |max| self.connection.buffered_response_bytes() >= max);
+        if self.unreleased_window > self.options.window_update_threshold && !over_budget {
+            let increment = mem::replace(&mut self.unreleased_window, 0);
+            self.sender.send_frame(WindowUpdateFrame::for_connection(increment))?;
+            self.connection.windows.borrow_mut().recv_window_update(0, increment);
         }
 
         Ok(())
@@ -440,34 +1862,126 @@ impl<T> H2ClientTokioTransport<T> where T: Io + 'static {
     /// Dequeue the next response frame off the `ready_responses` queue. As a `Stream` can only
     /// yield a frame at a time, while we can resolve multiple streams (i.e. requests) in the same
     /// stream poll, we need to keep a queue of frames that the `Stream` can yield.
-    fn get_next_response_frame(&mut self) -> Option<TokioResponseFrame> {
-        self.ready_responses.get_next_chunk().map(|(request_id, response)| {
-            match response {
-                ResponseChunk::Headers(headers) => {
-                    trace!("Yielding a headers frame for request {}", request_id);
-                    Frame::Message {
-                        id: request_id,
-                        message: headers,
-                        body: true,
-                        solo: false,
-                    }
-                },
-                ResponseChunk::Body(body) => {
-                    trace!("Yielding a body chunk for request {}", request_id);
-                    Frame::Body {
-                        id: request_id,
-                        chunk: Some(body),
-                    }
-                },
-                ResponseChunk::EndOfBody => {
-                    trace!("Yielding an 'end of body' chunk for request {}", request_id);
-                    Frame::Body {
-                        id: request_id,
-                        chunk: None,
-                    }
-                },
+    ///
+    /// The flow control window taken up by a body chunk is only returned to the server once the
+    /// chunk is dequeued (i.e. handed off to Tokio). This means that the response data that is
+    /// buffered for a stream is bounded by the stream's flow control window: a consumer that is
+    /// slow to read the body also slows down the server.
+    fn get_next_response_frame(&mut self) -> Result<Option<TokioResponseFrame>, Http2Error> {
+        let (request_id, response) = match self.ready_responses.get_next_chunk() {
+            None => return Ok(None),
+            Some(next) => next,
+        };
+        let span = self.request_span(request_id);
+        let _entered = span.as_ref().map(|span| span.enter());
+
+        if let ResponseChunk::Body(ref body) = response {
+            if let Some(&stream_id) = self.tokio_request_to_h2stream.get(&request_id) {
+                self.release_recv_window(stream_id, body.body.len() as u32)?;
             }
-        })
+        }
+
+        let frame = match response {
+            ResponseChunk::Body(mut body) if self.options.max_buffered_response_bytes.is_some() => {
+                trace!("Yielding a tracked body chunk for request {}", request_id);
+                body.buffered = Some(self.connection.track_buffered_response(body.body.len()));
+                Frame::Body {
+                    id: request_id,
+                    chunk: Some(body),
+                }
+            },
+            ResponseChunk::Headers(headers) => {
+                trace!("Yielding a headers frame for request {}", request_id);
+                Frame::Message {
+                    id: request_id,
+                    message: headers,
+                    body: true,
+                    solo: false,
+                }
+            },
+            ResponseChunk::Body(body) => {
+                trace!("Yielding a body chunk for request {}", request_id);
+                Frame::Body {
+                    id: request_id,
+                    chunk: Some(body),
+                }
+            },
+            ResponseChunk::EndOfBody => {
+                trace!("Yielding an 'end of body' chunk for request {}", request_id);
+                Frame::Body {
+                    id: request_id,
+                    chunk: None,
+                }
+            },
+            ResponseChunk::Error(error) => {
+                trace!("Yielding an error for request {}: {:?}", request_id, error);
+                Frame::Error {
+                    id: request_id,
+                    error: error,
+                }
+            },
+        };
+
+        Ok(Some(frame))
+    }
+
+    /// Returns the span of the request with the given Tokio ID, as long as its stream is open.
+    fn request_span(&self, id: u64) -> Option<Span> {
+        self.tokio_request_to_h2stream.get(&id)
+            .and_then(|stream_id| self.conn.state.get_stream_ref(*stream_id))
+            .map(|stream| stream.span.clone())
+    }
+
+    /// Checks whether the request with the given Tokio ID is mapped to an h2 stream that is still
+    /// open.
+    fn is_request_in_flight(&self, id: u64) -> bool {
+        self.tokio_request_to_h2stream.get(&id)
+            .map_or(false, |stream_id| self.conn.state.get_stream_ref(*stream_id).is_some())
+    }
+
+    /// Checks whether the request with the given Tokio ID already has as much body data buffered
+    /// as it's allowed to.
+    fn is_body_backlogged(&self, id: u64) -> bool {
+        self.tokio_request_to_h2stream.get(&id)
+            .and_then(|stream_id| self.conn.state.get_stream_ref(*stream_id))
+            .map_or(false, |stream| stream.buffered_len() >= MAX_BUFFERED_BODY_LEN)
+    }
+
+    /// Fails a single request that could not be started, without affecting the rest of the
+    /// connection: the error is handed back to Tokio as the request's response.
+    fn fail_request(&mut self, request_id: u64, has_body: bool, err: Http2Error) {
+        debug!("failed to start request {}: {:?}", request_id, err);
+        // The body chunks of an in-flight request with the same ID (see `DuplicateRequestId`)
+        // must still reach their stream.
+        if has_body && !self.is_request_in_flight(request_id) {
+            self.failed_requests.insert(request_id);
+        }
+        let error = H2Error::InvalidRequest(err).into();
+        self.ready_responses.get_sender(request_id).send_chunk(ResponseChunk::Error(error));
+    }
+
+    /// Aborts a single request whose body turned out to be invalid: its stream is reset and the
+    /// request fails with the given error, while the rest of the connection is left unaffected.
+    fn abort_request(&mut self, request_id: u64, err: Http2Error) -> Result<(), Http2Error> {
+        debug!("aborting request {}: {:?}", request_id, err);
+        self.failed_requests.insert(request_id);
+
+        let stream_id = match self.tokio_request_to_h2stream.get(&request_id) {
+            Some(&stream_id) => stream_id,
+            None => return Ok(()),
+        };
+        if let Some(stream) = self.conn.state.get_stream_mut(stream_id) {
+            stream.failure = Some(H2Error::InvalidRequest(err).into());
+            stream.was_reset = true;
+            stream.close();
+        } else {
+            // The stream is already gone, so the request has already completed.
+            return Ok(());
+        }
+        self.sender.send_frame(RstStreamFrame::new(stream_id, ErrorCode::Cancel))?;
+        self.handle_closed_streams();
+
+        Ok(())
     }
 
     /// Add a body chunk to the request with the given Tokio ID.
@@ -491,7 +2005,7 @@ impl<T> H2ClientTokioTransport<T> where T: Io + 'static {
                     },
                     None => {
                         trace!("no more data for stream {}", *stream_id);
-                        stream.set_should_close();
+                        stream.set_should_close()?;
                     },
                 };
             },
@@ -519,11 +2033,13 @@ impl<T> H2ClientTokioTransport<T> where T: Io + 'static {
         }
 
         trace!("preparing a data frame");
+        self.update_yielding();
         let has_data = self.try_write_next_data()?;
         if has_data {
             debug!("queued up a new data frame");
+            self.connection.stats.borrow_mut().data_frames_sent += 1;
 
-            if self.sender.try_write()? {
+            if self.flush_sender()? {
                 trace!("wrote a full data frame without blocking");
                 // HACK!? Yield to the executor, but make sure we're called back asap...
                 // Ensures that we don't simply end up writing a whole lot of request
@@ -538,6 +2054,12 @@ impl<T> H2ClientTokioTransport<T> where T: Io + 'static {
                 // We'll get rescheduled when the socket unblocks.
                 Ok(Async::NotReady)
             }
+        } else if self.has_buffered_request_data() {
+            // Some stream has body data that it's holding back for now (e.g. until the server
+            // sends a `100 Continue`), so not everything is sent yet. Whatever holds it back gets
+            // resolved by an event on the connection, which wakes the task up to try again.
+            trace!("request data buffered, but held back");
+            Ok(Async::NotReady)
         } else {
             trace!("no stream data ready");
             // If we didn't manage to prepare a data frame, while there were still open
@@ -559,6 +2081,16 @@ impl<T> H2ClientTokioTransport<T> where T: Io + 'static {
             !stream.is_closed_local()
         })
     }
+
+    /// Checks whether any active h2 stream has request body data that was handed to it, but not
+    /// yet sent out, or still has to send the end of its body. Unlike with streams that merely
+    /// wait for more of their body to come in through `start_send`, the transport has to send
+    /// that data out on its own.
+    fn has_buffered_request_data(&mut self) -> bool {
+        self.conn.state.iter().any(|(_id, stream)| {
+            !stream.is_closed_local() && (stream.buffered_len() > 0 || stream.should_close)
+        })
+    }
 }
 
 impl<T> Stream for H2ClientTokioTransport<T> where T: Io + 'static {
@@ -566,19 +2098,72 @@ impl<T> Stream for H2ClientTokioTransport<T> where T: Io + 'static {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let span = self.span.clone();
+        let _entered = span.enter();
         trace!("polling read");
+        self.connection.waker.register();
 
-        // First, try to see if there's anything more that we can read off the socket already...
-        self.try_read_more()?;
+        // Tear the connection down right away if the client-facing side asked for it...
+        if self.connection.take_abort_request() {
+            self.abort();
+        }
+        // ...reset any streams that it gave up on...
+        self.handle_reset_requests()?;
+        // ...and send out any PINGs that it asked for, as well as any priority changes.
+        if self.connection_error.is_none() {
+            self.handle_ping_requests()?;
+            self.handle_background_requests()?;
+            self.handle_priority_requests()?;
+            // The application may have consumed enough of the buffered response bytes for the
+            // connection's flow control window to be given back.
+            self.release_connection_window()?;
+        }
+
+        // Then, try to see if there's anything more that we can read off the socket already...
+        // NOTE: The read is attempted on every poll, without first waiting for the socket to
+        //       become readable. Whatever the server sent while the connection was still being
+        //       negotiated (e.g. its SETTINGS, right after the TLS handshake) may already sit in
+        //       the TLS layer's buffer, where it wouldn't make the socket readable again; as the
+        //       transport is polled as soon as it's bound, those bytes are picked up right away.
+        if self.connection_error.is_none() && !self.awaiting_upgrade_request {
+            if let Err(err) = self.try_read_more() {
+                self.fail_connection(err);
+            }
+        }
+        // ...and whether the server took too long to acknowledge our SETTINGS.
+        if self.connection_error.is_none() {
+            if let Err(err) = self.check_settings_ack() {
+                self.fail_connection(err);
+            }
+        }
 
         // Now return the first response that we have ready, if any.
-        match self.get_next_response_frame() {
-            None => Ok(Async::NotReady),
-            Some(tokio_frame) => Ok(Async::Ready(Some(tokio_frame))),
+        match self.get_next_response_frame()? {
+            None => {
+                // Only once all the failed requests have been handed back, is the connection
+                // itself torn down.
+                match self.connection_error.take() {
+                    Some(err) => Err(err.into()),
+                    None => Ok(Async::NotReady),
+                }
+            },
+            Some(tokio_frame) => {
+                // Push out any WINDOW_UPDATEs that handing off the frame caused.
+                self.flush_sender()?;
+                Ok(Async::Ready(Some(tokio_frame)))
+            },
         }
     }
 }
 
+impl<T> Drop for H2ClientTokioTransport<T> where T: Io + 'static {
+    fn drop(&mut self) {
+        // However the transport ends up being torn down, the connection is gone along with it.
+        self.connection.set_closed(ClosedReason::Dropped);
+        self.fail_pings();
+    }
+}
+
 impl<T> Sink for H2ClientTokioTransport<T> where T: Io + 'static {
     type SinkItem = Frame<HttpRequestHeaders, HttpRequestBody, io::Error>;
     // NOTE: For some reason, Tokio requires that the Transport uses io::Error for its error type,
@@ -589,16 +2174,56 @@ impl<T> Sink for H2ClientTokioTransport<T> where T: Io + 'static {
     fn start_send(&mut self,
                   item: Self::SinkItem)
                   -> StartSend<Self::SinkItem, Self::SinkError> {
+        let span = self.span.clone();
+        let _entered = span.enter();
         match item {
-            Frame::Message { id, body: has_body, message: HttpRequestHeaders { headers }, .. } => {
+            Frame::Message { id, body: has_body, message, .. } => {
+                let span = Span::request(id);
+                let _entered = span.enter();
+                let HttpRequestHeaders { headers, shared, upgrade, raw } = message;
                 debug!("start new request id={}, body={}", id, has_body);
                 trace!("  headers={:?}", headers);
 
-                self.start_request(id, headers, has_body)?;
+                // Kept to tell the request that it's done, should it fail to start.
+                let request = shared.clone();
+                let res = if upgrade {
+                    let res = self.adopt_upgrade_request(id, headers, shared, span.clone());
+                    // Frames can be read now that the upgrade response has somewhere to go.
+                    task::park().unpark();
+                    res
+                } else {
+                    self.start_request(id, headers, shared, has_body, raw, span.clone())
+                };
+                if let Err(err) = res {
+                    if let Some(request) = request {
+                        request.set_done();
+                    }
+                    self.fail_request(id, has_body, err);
+                }
             },
             Frame::Body { id, chunk } => {
+                let span = self.request_span(id);
+                let _entered = span.as_ref().map(|span| span.enter());
+                if self.failed_requests.contains(&id) {
+                    trace!("dropping a body chunk of failed request id={}", id);
+                    if chunk.is_none() {
+                        self.failed_requests.remove(&id);
+                    }
+                    return Ok(AsyncSink::Ready);
+                }
+                if chunk.is_some() && self.is_body_backlogged(id) {
+                    // Apply backpressure: Tokio will try to hand the chunk over again later.
+                    trace!("too much buffered body data for request id={}", id);
+                    return Ok(AsyncSink::NotReady(Frame::Body { id: id, chunk: chunk }));
+                }
                 debug!("add body chunk for request id={}", id);
-                self.add_body_chunk(id, chunk)?;
+                let is_last = chunk.is_none();
+                if let Err(err) = self.add_body_chunk(id, chunk) {
+                    self.abort_request(id, err)?;
+                    if is_last {
+                        self.failed_requests.remove(&id);
+                    }
+                }
             },
             _ => {},
         }
@@ -607,17 +2232,36 @@ impl<T> Sink for H2ClientTokioTransport<T> where T: Io + 'static {
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        let span = self.span.clone();
+        let _entered = span.enter();
         trace!("poll all requests sent?");
 
+        if self.connection_error.is_some() {
+            // Nothing more can be written; the failure is reported from the read side.
+            return Ok(Async::NotReady);
+        }
+
         // Make sure to trigger a frame flush ...
-        if self.sender.try_write()? {
+        let res = match self.flush_sender() {
             // If sending everything that was queued so far worked, let's see if we can queue up
             // some data frames, if there are streams that still need to send some.
-            self.send_request_data()
-        } else {
+            Ok(true) => self.send_request_data(),
             // We didn't manage to write everything from our out buffer without blocking.
             // We'll get woken up when writing to the socket is possible again.
-            Ok(Async::NotReady)
+            Ok(false) => Ok(Async::NotReady),
+            Err(err) => Err(err),
+        };
+
+        match res {
+            Err(err) => {
+                // A broken socket fails the whole connection, rather than only whatever happens
+                // to be driving the `Sink`: every in-flight request gets resolved with the error.
+                self.fail_connection(err.into());
+                // Make sure that `poll` gets a chance to hand back the failed requests.
+                task::park().unpark();
+                Ok(Async::NotReady)
+            },
+            res => res,
         }
     }
 }
@@ -645,6 +2289,12 @@ pub struct H2ClientTokioProto<Connector>
 {
     pub connector: Connector,
     pub authority: String,
+    pub connection: SharedConnection,
+    pub options: ConnectionOptions,
+    /// Set if the connector upgrades the connection from HTTP/1.1 (see `UpgradeConnector`).
+    pub upgrade: bool,
+    /// The event loop that the connection runs on, for setting up its timers.
+    pub handle: Handle,
 }
 
 impl<T, Connector> ClientProto<T> for H2ClientTokioProto<Connector>
@@ -660,25 +2310,120 @@ impl<T, Connector> ClientProto<T> for H2ClientTokioProto<Connector>
     type BindTransport = Box<Future<Item=Self::Transport, Error=io::Error>>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        let params = H2ConnectorParams::new(self.authority.clone(), io);
+        let params =
+            H2ConnectorParams::new(self.authority.clone(), io, self.connection.clone());
+        let connection = self.connection.clone();
+        let options = self.options.clone();
+        let upgrade = self.upgrade;
+        let handle = self.handle.clone();
+        let span = Span::connection(&self.authority);
 
         let transport = self.connector.call(params)
-            .and_then(|io| {
-                // Prepare the preface into an in-memory buffer...
-                let mut buf = io::Cursor::new(vec![]);
-                let preface_buf_future = future::result(
-                    client::write_preface(&mut buf).map(move |_| buf.into_inner()));
-                preface_buf_future
-                    .and_then(|buf| {
-                        trace!("Kicking off a client preface write");
-                        tokio_io::write_all(io, buf)
-                    })
-                    .map(|(io, _buf)| {
+            .and_then(move |io| {
+                // Prepare the preface, along with the initial SETTINGS, into an in-memory buffer...
+                let buf = serialize_preface(&options);
+                trace!("Kicking off a client preface write");
+                tokio_io::write_all(io, buf)
+                    .and_then(move |(io, _buf)| {
                         debug!("client preface write complete");
-                        H2ClientTokioTransport::new(io)
+                        let settings_ack_timer = match options.settings_ack_timeout {
+                            Some(timeout) => Some(Timeout::new(timeout, &handle)?),
+                            None => None,
+                        };
+                        Ok(H2ClientTokioTransport::new(
+                            io, connection, options, span, upgrade, settings_ack_timer))
                     })
             });
 
         Box::new(transport)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use futures::{future, Future};
+    use futures::sink::Sink;
+    use tokio_core::reactor::Core;
+    use tokio_proto::streaming::multiplex::Frame;
+
+    use solicit::http::Header;
+
+    use client::{HttpRequestHeaders, HttpRequestBody};
+    use client::options::ConnectionOptions;
+    use client::shared::SharedConnection;
+    use client::spans::Span;
+    use mock::{self, MockH2Server, MockAction, MockResponse};
+    use super::{H2ClientTokioTransport, serialize_preface, DATA_FRAME_TYPE};
+
+    const END_STREAM_FLAG: u8 = 0x1;
+
+    #[test]
+    fn poll_complete_is_ready_only_once_all_bodies_are_sent() {
+        let mut core = Core::new().expect("failed to create an event loop");
+        let (mut client_io, server_io) = mock::pipe();
+        let server = (0..3).fold(MockH2Server::new(server_io), |server, _| {
+            server.then(MockAction::Respond(MockResponse::new(b"204")))
+        });
+        let log = server.frame_log();
+        core.handle().spawn(server.map_err(|err| panic!("the mock server failed: {}", err)));
+
+        // Just like `bind_transport`, the preface is written before the transport takes over.
+        let options = ConnectionOptions::default();
+        client_io.write_all(&serialize_preface(&options)).unwrap();
+        let mut transport = H2ClientTokioTransport::new(client_io,
+                                                        SharedConnection::new(),
+                                                        options,
+                                                        Span::connection("example.com"),
+                                                        false,
+                                                        None);
+
+        let chunk = vec![0x2a; 4000];
+        core.run(future::lazy(|| {
+            for id in 0..3 {
+                let headers = HttpRequestHeaders::with_headers(vec![
+                    Header::new(b":method", b"POST"),
+                    Header::new(b":path", b"/"),
+                    Header::new(b":authority", b"example.com"),
+                    Header::new(b":scheme", b"http"),
+                ]);
+                let request = Frame::Message { id: id, message: headers, body: true, solo: false };
+                assert!(transport.start_send(request).unwrap().is_ready());
+            }
+            // The chunks of the streams are interleaved, and each stream ends its body.
+            for _ in 0..3 {
+                for id in 0..3 {
+                    let chunk = Some(HttpRequestBody::new(chunk.clone()));
+                    assert!(transport.start_send(Frame::Body { id: id, chunk: chunk })
+                            .unwrap().is_ready());
+                }
+            }
+            for id in 0..3 {
+                let end = Frame::Body { id: id, chunk: None };
+                assert!(transport.start_send(end).unwrap().is_ready());
+            }
+
+            // It may take several polls to drain the bodies.
+            let mut polls = 0;
+            while !transport.poll_complete().unwrap().is_ready() {
+                polls += 1;
+                assert!(polls < 100, "the request bodies were never drained");
+            }
+            assert!(!transport.has_pending_request_data());
+            Ok::<_, ()>(())
+        })).unwrap();
+
+        mock::settle(&mut core);
+        let frames = log.frames();
+        for &stream_id in &[1, 3, 5] {
+            let data: Vec<_> = frames.iter()
+                .filter(|frame| frame.frame_type == DATA_FRAME_TYPE && frame.stream_id == stream_id)
+                .collect();
+            let sent: usize = data.iter().map(|frame| frame.payload.len()).sum();
+            assert_eq!(sent, 3 * chunk.len());
+            assert_eq!(data.last().expect("no DATA frames").flags & END_STREAM_FLAG,
+                       END_STREAM_FLAG);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Contains an implementation of the HTTP/1.1 Upgrade mechanism for cleartext HTTP/2 (`h2c`).
+//!
+//! `upgrade_async` - a function that sends the HTTP/1.1 upgrade request on a fresh socket and
+//! waits for the server to switch protocols.
+//!
+//! `UpgradedStream` - a struct that wraps the socket after the server has switched to HTTP/2.
+//! Any HTTP/2 bytes that the server sent along with its `101 Switching Protocols` response are
+//! handed out first, before reading from the socket itself. Implements `tokio_core::io::Io`.
+use std::io::{self, Read};
+
+use tokio_core::io::{Io, self as tokio_io};
+use futures::{Async, Future, Poll};
+
+/// The most bytes that the HTTP/1.1 response to the upgrade request may take up, before the
+/// server is considered to be misbehaving.
+const MAX_RESPONSE_HEAD_LEN: usize = 16 * 1024;
+
+/// The alphabet of the URL-safe base64 encoding.
+const BASE64_URL_ALPHABET: &'static [u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Encodes the given bytes using the URL-safe base64 encoding, without any padding, as required
+/// for the `HTTP2-Settings` header.
+fn base64_url_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate()
+            .fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        // Each input byte yields at least one full output character.
+        for i in 0..chunk.len() + 1 {
+            let index = (bits >> (18 - 6 * i)) & 0x3f;
+            encoded.push(BASE64_URL_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Serializes the HTTP/1.1 request that asks the server to upgrade the connection to `h2c`. The
+/// `settings` are the payload of the SETTINGS frame that the client would send.
+fn serialize_upgrade_request(authority: &str, settings: &[u8]) -> Vec<u8> {
+    format!("GET / HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Connection: Upgrade, HTTP2-Settings\r\n\
+             Upgrade: h2c\r\n\
+             HTTP2-Settings: {}\r\n\
+             \r\n",
+            authority,
+            base64_url_encode(settings)).into_bytes()
+}
+
+/// Returns the position right after the end of the head of an HTTP/1.1 response (i.e. the empty
+/// line that ends its headers), if the given buffer contains all of it.
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|window| window == b"\r\n\r\n").map(|pos| pos + 4)
+}
+
+/// Sends the HTTP/1.1 upgrade request on the given socket, assuming it's freshly connected.
+/// Returns a (boxed) future that resolves to an `UpgradedStream` once the server responds with
+/// `101 Switching Protocols`, from which point on HTTP/2 is spoken on the socket.
+///
+/// If the server responds with anything else (i.e. it doesn't agree to the upgrade), the future
+/// resolves to an error that carries the status line of the response.
+pub fn upgrade_async<I>(authority: &str,
+                        settings: &[u8],
+                        io: I)
+                        -> Box<Future<Item=UpgradedStream<I>, Error=io::Error>>
+                        where I: 'static + Io {
+    let request = serialize_upgrade_request(authority, settings);
+    trace!("sending the h2c upgrade request");
+    let upgrade = tokio_io::write_all(io, request)
+        .and_then(|(io, _request)| {
+            ReadUpgradeResponse {
+                io: Some(io),
+                buf: Vec::new(),
+            }
+        });
+
+    Box::new(upgrade)
+}
+
+/// A `Future` that reads the HTTP/1.1 response to the upgrade request.
+struct ReadUpgradeResponse<I> where I: Io {
+    io: Option<I>,
+    /// The bytes read so far.
+    buf: Vec<u8>,
+}
+
+impl<I> Future for ReadUpgradeResponse<I> where I: Io {
+    type Item = UpgradedStream<I>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let head_len = loop {
+            if let Some(head_len) = find_head_end(&self.buf) {
+                break head_len;
+            }
+            if self.buf.len() > MAX_RESPONSE_HEAD_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "the response to the upgrade request is too large"));
+            }
+
+            let mut chunk = [0; 1024];
+            let io = self.io.as_mut().expect("ReadUpgradeResponse polled after completion");
+            match io.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(count) => self.buf.extend_from_slice(&chunk[..count]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        };
+
+        let accepted = {
+            let status_line = self.buf.split(|&c| c == b'\r').next().unwrap_or(&[]);
+            trace!("upgrade response: {}", String::from_utf8_lossy(status_line));
+            if status_line.split(|&c| c == b' ').nth(1) == Some(&b"101"[..]) {
+                Ok(())
+            } else {
+                Err(format!("the server refused to upgrade to h2c: {}",
+                            String::from_utf8_lossy(status_line)))
+            }
+        };
+        if let Err(msg) = accepted {
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+
+        debug!("server switched to h2c");
+        // Whatever follows the response head is already HTTP/2.
+        let leftover = self.buf.split_off(head_len);
+        Ok(Async::Ready(UpgradedStream {
+            leftover: io::Cursor::new(leftover),
+            inner: self.io.take().expect("ReadUpgradeResponse polled after completion"),
+        }))
+    }
+}
+
+/// A socket after the server agreed to switch to HTTP/2 over the HTTP/1.1 Upgrade mechanism.
+pub struct UpgradedStream<I: Io> {
+    /// The bytes that were read along with the upgrade response, but are already part of the
+    /// HTTP/2 connection.
+    leftover: io::Cursor<Vec<u8>>,
+    inner: I,
+}
+
+impl<I> UpgradedStream<I> where I: Io {
+    /// Returns whether all of the leftover bytes have been read out.
+    fn leftover_exhausted(&self) -> bool {
+        self.leftover.position() as usize == self.leftover.get_ref().len()
+    }
+}
+
+impl<I> io::Read for UpgradedStream<I> where I: Io {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.leftover_exhausted() {
+            self.inner.read(buf)
+        } else {
+            self.leftover.read(buf)
+        }
+    }
+}
+
+impl<I> io::Write for UpgradedStream<I> where I: Io {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<I> Io for UpgradedStream<I> where I: Io {
+    #[inline]
+    fn poll_read(&mut self) -> Async<()> {
+        if self.leftover_exhausted() {
+            self.inner.poll_read()
+        } else {
+            Async::Ready(())
+        }
+    }
+
+    #[inline]
+    fn poll_write(&mut self) -> Async<()> {
+        self.inner.poll_write()
+    }
+}
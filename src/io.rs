@@ -13,15 +13,47 @@
 //! the underlying IO, or serializing a frame into bytes prior to writing them out.
 
 use std::io::{self, Read, Write};
+use std::iter;
 use std::collections::VecDeque;
 
 use futures::{Async};
 use tokio_core::io::{Io, ReadHalf, WriteHalf};
 
-use solicit::http::{HttpResult};
-use solicit::http::frame::{RawFrame, FrameIR};
+use solicit::http::{HttpResult, StreamId};
+use solicit::http::frame::{RawFrame, FrameIR, FrameHeader, unpack_header};
 use solicit::http::connection::{SendFrame, ReceiveFrame, HttpFrame};
 
+use client::{FrameObserver, FrameInfo, FrameDirection};
+
+/// The frame type identifier of DATA frames.
+const DATA_FRAME_TYPE: u8 = 0x0;
+/// The flag that marks a frame as padded.
+const PADDED_FLAG: u8 = 0x8;
+/// The length of the header that every frame starts with.
+const FRAME_HEADER_LEN: usize = 9;
+/// The largest frame payload that the server may send. The client never announces a
+/// `SETTINGS_MAX_FRAME_SIZE`, so this is the protocol's initial value.
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+
+/// Serializes a frame with the given type, flags, stream ID and payload into its wire format. The
+/// reserved bit of the stream ID is always left unset.
+pub fn pack_frame(frame_type: u8, flags: u8, stream_id: StreamId, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len();
+    let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + len);
+    buf.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    buf.push(frame_type);
+    buf.push(flags);
+    buf.extend_from_slice(&[
+        (stream_id >> 24) as u8 & 0x7f,
+        (stream_id >> 16) as u8,
+        (stream_id >> 8) as u8,
+        stream_id as u8,
+    ]);
+    buf.extend_from_slice(payload);
+
+    buf
+}
+
 /// The struct that implements the `SendFrame` trait.
 pub struct FrameSender<T: Io + 'static> {
     /// The write end of a `tokio_core::io::Io` that the sender will attempt to write the raw
@@ -31,25 +63,72 @@ pub struct FrameSender<T: Io + 'static> {
     out_buf: Option<io::Cursor<Vec<u8>>>,
     /// Pending serialized frames
     out_frames: VecDeque<Vec<u8>>,
+    /// The total number of bytes that were written to the `io` so far.
+    bytes_written: u64,
+    /// The total number of bytes that were queued up to be written so far.
+    bytes_queued: u64,
+    /// Told about each frame as it's queued up, if set.
+    observer: Option<FrameObserver>,
+    /// The number of padding bytes that each DATA frame is sent with.
+    data_padding: u8,
+    /// The most bytes of pending frames that are merged into a single buffer, so that they go
+    /// out in a single write.
+    max_coalesced_write: usize,
 }
 
 impl<T: Io + 'static> FrameSender<T> {
     /// Creates a new `FrameSender` that will write onto the given `WriteHalf` of a socket
-    /// (or rather `tokio_core::io::Io`).
-    pub fn new(io: WriteHalf<T>) -> FrameSender<T> {
+    /// (or rather `tokio_core::io::Io`). The `observer`, if any, is told about every frame that
+    /// is sent. Every DATA frame is sent with `data_padding` bytes of padding. Consecutive
+    /// pending frames are merged into writes of up to `max_coalesced_write` bytes.
+    pub fn new(io: WriteHalf<T>,
+               observer: Option<FrameObserver>,
+               data_padding: u8,
+               max_coalesced_write: usize)
+               -> FrameSender<T> {
         FrameSender {
             io: io,
             out_buf: None,
             out_frames: VecDeque::new(),
+            bytes_written: 0,
+            bytes_queued: 0,
+            observer: observer,
+            data_padding: data_padding,
+            max_coalesced_write: max_coalesced_write,
         }
     }
 
+    /// Returns the total number of bytes that the sender has written to the underlying `Io`.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Returns the total number of bytes that have been queued up for writing so far, whether
+    /// they have already been written or not.
+    pub fn bytes_queued(&self) -> u64 {
+        self.bytes_queued
+    }
+
     /// Adds a serialized frame to the pending frame buffer. It does not attempt writing
     /// anything to the underlying socket.
     fn append(&mut self, b: Vec<u8>) {
+        if let Some(ref observer) = self.observer {
+            if b.len() >= 9 {
+                let mut header = [0; 9];
+                header.copy_from_slice(&b[..9]);
+                observer.observe(&frame_info(FrameDirection::Sent, unpack_header(&header)));
+            }
+        }
+        self.bytes_queued += b.len() as u64;
         self.out_frames.push_back(b);
     }
 
+    /// Queues up an already serialized frame, for frames that `solicit` has no representation
+    /// of. Just like `send_frame`, it does not attempt writing anything yet.
+    pub fn send_raw_frame(&mut self, frame: Vec<u8>) {
+        self.append(frame);
+    }
+
     /// Attempts to perform a write of all remaining buffered data. This includes the current
     /// `out_buf`, as well as any pending serialized frames in `out_frames`.
     ///
@@ -97,11 +176,15 @@ impl<T: Io + 'static> FrameSender<T> {
                 match self.io.write(&out_buf.get_ref()[out_buf.position() as usize..]) {
                     Ok(count) => {
                         debug!("wrote {} bytes", count);
+                        self.bytes_written += count as u64;
 
                         let total_written = (out_buf.position() as usize) + count;
                         if total_written == out_buf.get_ref().len() {
                             // All done with the buffer
                             done = true;
+                        } else {
+                            // Only part of it made it out; pick up where we left off next time.
+                            out_buf.set_position(total_written as u64);
                         }
                     },
                     Err(e) => {
@@ -124,8 +207,10 @@ impl<T: Io + 'static> FrameSender<T> {
     }
 
     /// Prepares the `out_buf` for an upcoming write. If the buffer is empty, it attempts to
-    /// take the next serialized frame from the pending frames buffer and write that. If there
-    /// are still unwritten bytes in the `out_buf`, it immediately returns.
+    /// take the next serialized frame from the pending frames buffer and write that, along with
+    /// as many of the frames that follow it as fit within `max_coalesced_write` bytes, so that
+    /// bursts of small frames don't take a syscall each. If there are still unwritten bytes in
+    /// the `out_buf`, it immediately returns.
     ///
     /// Returns `true` if there are more bytes to send, which are now ready in `out_buf`.
     /// Returns `false` if there are no more bytes to send.
@@ -136,7 +221,13 @@ impl<T: Io + 'static> FrameSender<T> {
                 None => false,
 
                 // Get the next frame and make it the out buffer.
-                Some(buf) => {
+                Some(mut buf) => {
+                    while self.out_frames.front().map_or(false, |next| {
+                        buf.len() + next.len() <= self.max_coalesced_write
+                    }) {
+                        let next = self.out_frames.pop_front().expect("a pending frame");
+                        buf.extend_from_slice(&next);
+                    }
                     self.out_buf = Some(io::Cursor::new(buf));
                     true
                 }
@@ -155,13 +246,55 @@ impl<T: Io + 'static> SendFrame for FrameSender<T> {
         let mut buf = io::Cursor::new(Vec::with_capacity(1024));
         frame.serialize_into(&mut buf)?;
 
+        // ...padding it, if it's a DATA frame and padding is asked for...
+        let mut frame = buf.into_inner();
+        if self.data_padding > 0 {
+            frame = pad_data_frame(frame, self.data_padding);
+        }
+
         // ...and then simply queue that up for the actual wire IO later on.
-        self.append(buf.into_inner());
+        self.append(frame);
 
         Ok(())
     }
 }
 
+/// Adds the given number of padding bytes to the given serialized frame, setting its PADDED flag,
+/// as long as it's a DATA frame that isn't padded already. Any other frame is returned as is.
+fn pad_data_frame(frame: Vec<u8>, padding: u8) -> Vec<u8> {
+    if frame.len() < 9 || frame[3] != DATA_FRAME_TYPE || frame[4] & PADDED_FLAG != 0 {
+        return frame;
+    }
+
+    // The payload grows by the pad length field and the padding itself.
+    let length = frame.len() - 9 + 1 + padding as usize;
+    let mut padded = Vec::with_capacity(9 + length);
+    padded.extend_from_slice(&[
+        (length >> 16) as u8,
+        (length >> 8) as u8,
+        length as u8,
+        DATA_FRAME_TYPE,
+        frame[4] | PADDED_FLAG,
+    ]);
+    padded.extend_from_slice(&frame[5..9]);
+    padded.push(padding);
+    padded.extend_from_slice(&frame[9..]);
+    padded.extend(iter::repeat(0).take(padding as usize));
+    padded
+}
+
+/// Builds the `FrameInfo` that a `FrameObserver` is told about from the given frame header.
+pub fn frame_info(direction: FrameDirection, header: FrameHeader) -> FrameInfo {
+    let (length, frame_type, flags, stream_id) = header;
+    FrameInfo {
+        direction: direction,
+        frame_type: frame_type,
+        flags: flags,
+        stream_id: stream_id,
+        length: length,
+    }
+}
+
 /// A simple wrapper around a parsed `RawFrame`.
 ///
 /// Implements the `ReceiveFrame` trait by trivially yielding the wrapped frame.
@@ -177,7 +310,7 @@ pub struct FrameContainer<'a> {
 impl<'a> FrameContainer<'a> {
     /// Creates a new `FrameContainer` that wraps the given `RawFrame`. This frame will be
     /// returned when the `recv_frame` call comes in.
-    fn new(frame: RawFrame) -> FrameContainer {
+    pub fn new(frame: RawFrame) -> FrameContainer {
         let size = frame.len();
 
         FrameContainer {
@@ -190,6 +323,16 @@ impl<'a> FrameContainer<'a> {
     pub fn len(&self) -> usize {
         self.size
     }
+
+    /// Returns the header of the frame that it wraps.
+    pub fn header(&self) -> FrameHeader {
+        self.frame.header()
+    }
+
+    /// Returns the payload of the frame that it wraps.
+    pub fn payload(&self) -> &[u8] {
+        self.frame.payload()
+    }
 }
 
 impl<'a> ReceiveFrame for FrameContainer<'a> {
@@ -204,15 +347,28 @@ pub struct FrameReceiver<T: Io + 'static> {
     /// The ReadHalf of the Tokio `Io` that this receiver will attempt to read from.
     io: ReadHalf<T>,
     /// A buffer of data that has been read so far, but represents an incomplete HTTP/2 frame.
+    ///
+    /// The buffer can hold any prefix of the byte stream: a partial frame header, a full header
+    /// with a partial payload, or several full frames followed by a partial one. Only complete
+    /// frames are ever yielded by `get_next_frame`; the rest stays buffered until more bytes
+    /// are read.
     in_buf: Vec<u8>,
+    /// The buffer that each read from the `io` goes into, before being appended to `in_buf`. Its
+    /// size bounds the number of bytes that a single read syscall can return.
+    read_buf: Vec<u8>,
+    /// Set once the underlying `Io` has signaled an EOF.
+    eof: bool,
 }
 
 impl<T: Io + 'static> FrameReceiver<T> {
-    /// Create a new `FrameReceiver` that will use the given `ReadHalf` to read from.
-    pub fn new(io: ReadHalf<T>) -> FrameReceiver<T> {
+    /// Create a new `FrameReceiver` that will use the given `ReadHalf` to read from, reading at
+    /// most `read_buffer_size` bytes at a time.
+    pub fn new(io: ReadHalf<T>, read_buffer_size: usize) -> FrameReceiver<T> {
         FrameReceiver {
             io: io,
             in_buf: Vec::new(),
+            read_buf: vec![0; ::std::cmp::max(read_buffer_size, 1)],
+            eof: false,
         }
     }
 
@@ -222,15 +378,32 @@ impl<T: Io + 'static> FrameReceiver<T> {
     /// blocking, immediately returns with 0. If it hits an EOF, it returns with an error, as the
     /// fact that this was called indicates that we were expecting more data on the wire and
     /// therefore we've hit an unexpected eof.
+    ///
+    /// If the EOF comes right after some more data, the data is returned first (so that any
+    /// frames that it contains can still be handled) and the error is returned on the next call.
     pub fn try_read(&mut self) -> io::Result<usize> {
+        if self.eof {
+            trace!("unexpected eof");
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
         let initial_size = self.in_buf.len();
         loop {
-            match self.io.read_to_end(&mut self.in_buf) {
+            match self.io.read(&mut self.read_buf) {
                 Ok(0) => {
-                    trace!("unexpected eof");
-                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                    self.eof = true;
+                    if self.in_buf.len() == initial_size {
+                        trace!("unexpected eof");
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                    }
+                    break;
                 },
-                Ok(_) => {},
+                Ok(count) => {
+                    trace!("read {} bytes", count);
+                    self.in_buf.extend_from_slice(&self.read_buf[..count]);
+                    self.reserve_pending_frame();
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
                 Err(e) => {
                     if e.kind() == io::ErrorKind::WouldBlock {
                         trace!("read - would block");
@@ -250,6 +423,26 @@ impl<T: Io + 'static> FrameReceiver<T> {
         Ok(total_read)
     }
 
+    /// Makes room in the input buffer for the rest of the frame that it starts with, once that
+    /// frame's header is in. A frame larger than a single read then assembles in place, rather
+    /// than the buffer being reallocated again and again as its pieces come in.
+    ///
+    /// The room is capped at the largest frame that the server may send, so that a bogus length
+    /// can't make the client allocate arbitrary amounts of memory up front.
+    fn reserve_pending_frame(&mut self) {
+        if self.in_buf.len() < FRAME_HEADER_LEN {
+            return;
+        }
+        let mut header = [0; FRAME_HEADER_LEN];
+        header.copy_from_slice(&self.in_buf[..FRAME_HEADER_LEN]);
+        let payload_len = unpack_header(&header).0 as usize;
+        let frame_len = FRAME_HEADER_LEN + ::std::cmp::min(payload_len, MAX_FRAME_SIZE);
+        if frame_len > self.in_buf.len() {
+            let missing = frame_len - self.in_buf.len();
+            self.in_buf.reserve(missing);
+        }
+    }
+
     /// Attempts to parse the current contents of the input buffer for an h2 frame.
     /// If successful returns a FrameContainer wrapping the frame. The returned `FrameContainer`
     /// will be borrowing the content of the internal buffer, i.e. parsing the frame does not
@@ -259,9 +452,188 @@ impl<T: Io + 'static> FrameReceiver<T> {
         raw_frame.map(|raw_frame| FrameContainer::new(raw_frame))
     }
 
-    /// Discards the current frame from the buffered input.
+    /// Discards the current frame from the buffered input. The `size` needs to be the full size
+    /// of the frame (as reported by `FrameContainer::len`), so that the buffer is left starting
+    /// at the first byte of the next frame.
     pub fn discard_frame(&mut self, size: usize) {
         self.in_buf.drain(..size);
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use futures::Future;
+    use futures::future;
+    use tokio_core::io::Io;
+
+    use solicit::http::StreamId;
+
+    use mock::{self, MockIo};
+    use super::{FrameReceiver, pack_frame, FRAME_HEADER_LEN, MAX_FRAME_SIZE};
+
+    /// A frame as the `FrameReceiver` assembled it: its type, stream ID and payload.
+    type Received = (u8, StreamId, Vec<u8>);
+
+    /// Runs the given function within a task, which reading from a `MockIo` requires.
+    fn in_task<F>(f: F) where F: FnOnce() {
+        future::lazy(move || {
+            f();
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    /// Creates a `FrameReceiver` reading from one end of a new pipe, along with the other end.
+    fn receiver(read_buffer_size: usize) -> (FrameReceiver<MockIo>, MockIo) {
+        let (client, server) = mock::pipe();
+        let (read, _write) = client.split();
+        (FrameReceiver::new(read, read_buffer_size), server)
+    }
+
+    /// Takes all the complete frames out of the receiver's buffer.
+    fn take_frames(receiver: &mut FrameReceiver<MockIo>) -> Vec<Received> {
+        let mut frames = Vec::new();
+        loop {
+            let (frame, len) = match receiver.get_next_frame() {
+                None => break,
+                Some(container) => {
+                    let (_, frame_type, _, stream_id) = container.header();
+                    ((frame_type, stream_id, container.payload().to_vec()), container.len())
+                },
+            };
+            receiver.discard_frame(len);
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// The frames that the tests send: a SETTINGS, a DATA and a HEADERS frame.
+    fn test_frames() -> Vec<Received> {
+        vec![
+            (0x4, 0, vec![0, 0x2, 0, 0, 0, 0]),
+            (0x0, 1, b"hello world".to_vec()),
+            (0x1, 3, vec![0x82; 20]),
+        ]
+    }
+
+    fn serialize(frames: &[Received]) -> Vec<u8> {
+        frames.iter()
+            .flat_map(|&(frame_type, stream_id, ref payload)| {
+                pack_frame(frame_type, 0, stream_id, payload)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frames_fed_byte_by_byte_are_yielded_once_complete() {
+        in_task(|| {
+            let (mut receiver, mut server) = receiver(16 * 1024);
+            let frames = test_frames();
+            let mut frame_ends = Vec::new();
+            let mut end = 0;
+            for &(_, _, ref payload) in &frames {
+                end += 9 + payload.len();
+                frame_ends.push(end);
+            }
+
+            let mut received = Vec::new();
+            for (i, byte) in serialize(&frames).into_iter().enumerate() {
+                server.write_all(&[byte]).unwrap();
+                assert_eq!(receiver.try_read().unwrap(), 1);
+                let new = take_frames(&mut receiver);
+                let expected = if frame_ends.contains(&(i + 1)) { 1 } else { 0 };
+                assert_eq!(new.len(), expected, "frames yielded after {} bytes", i + 1);
+                received.extend(new);
+            }
+            assert_eq!(received, frames);
+        });
+    }
+
+    #[test]
+    fn frames_split_at_adversarial_boundaries_are_reassembled() {
+        in_task(|| {
+            let (mut receiver, mut server) = receiver(16 * 1024);
+            let frames = test_frames();
+            let wire = serialize(&frames);
+            let second_end = 9 + frames[0].2.len() + 9 + frames[1].2.len();
+            // Half of the first header, the rest of it, part of the first payload, the rest of the
+            // first frame along with the whole second one and part of the third one's header, and
+            // finally the rest of the third frame.
+            let chunks = [(4, 0), (9, 0), (12, 0), (second_end + 3, 2), (wire.len(), 1)];
+
+            let mut received = Vec::new();
+            let mut start = 0;
+            for &(end, expected) in &chunks {
+                server.write_all(&wire[start..end]).unwrap();
+                assert_eq!(receiver.try_read().unwrap(), end - start);
+                let new = take_frames(&mut receiver);
+                assert_eq!(new.len(), expected, "frames yielded after {} bytes", end);
+                received.extend(new);
+                start = end;
+            }
+            assert_eq!(received, frames);
+        });
+    }
+
+    #[test]
+    fn frames_split_anywhere_in_two_are_reassembled() {
+        let frames = test_frames();
+        let wire = serialize(&frames);
+        for split in 1..wire.len() {
+            let (frames, wire) = (frames.clone(), wire.clone());
+            in_task(move || {
+                let (mut receiver, mut server) = receiver(16 * 1024);
+                server.write_all(&wire[..split]).unwrap();
+                receiver.try_read().unwrap();
+                let mut received = take_frames(&mut receiver);
+                server.write_all(&wire[split..]).unwrap();
+                receiver.try_read().unwrap();
+                received.extend(take_frames(&mut receiver));
+                assert_eq!(received, frames, "split after {} bytes", split);
+            });
+        }
+    }
+
+    #[test]
+    fn frames_right_before_eof_are_kept() {
+        in_task(|| {
+            let (mut receiver, mut server) = receiver(16 * 1024);
+            let frames = test_frames();
+            server.write_all(&serialize(&frames)).unwrap();
+            drop(server);
+
+            assert!(receiver.try_read().unwrap() > 0);
+            assert_eq!(take_frames(&mut receiver), frames);
+            let err = receiver.try_read().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+
+    #[test]
+    fn frame_much_larger_than_the_read_buffer_is_assembled() {
+        in_task(|| {
+            let (mut receiver, mut server) = receiver(100);
+            let payload: Vec<u8> = (0..MAX_FRAME_SIZE - 10).map(|i| i as u8).collect();
+            let frames = vec![(0x0, 1, payload), (0x0, 1, b"after".to_vec())];
+            let wire = serialize(&frames);
+            let first_len = FRAME_HEADER_LEN + frames[0].2.len();
+
+            let mut received = Vec::new();
+            for (i, piece) in wire.chunks(1000).enumerate() {
+                server.write_all(piece).unwrap();
+                assert_eq!(receiver.try_read().unwrap(), piece.len());
+                if i == 0 {
+                    // Once the header is in, room for the whole frame is made up front.
+                    assert!(receiver.in_buf.capacity() >= first_len);
+                }
+                received.extend(take_frames(&mut receiver));
+                if (i + 1) * 1000 < first_len {
+                    assert!(received.is_empty(), "a frame was yielded before it was complete");
+                }
+            }
+            assert_eq!(received, frames);
+        });
+    }
+}
@@ -11,5 +11,16 @@ extern crate tokio_openssl;
 
 extern crate solicit;
 
+#[cfg(any(test, feature = "mock"))]
+extern crate hpack;
+#[cfg(feature = "compression")]
+extern crate flate2;
+// Only the span macros are used (by path), as the log macros would clash with the `log` ones.
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
 mod io;
 pub mod client;
+pub mod blocking;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
@@ -0,0 +1,549 @@
+//! Exposes an in-memory `Io` pipe and a scriptable in-process HTTP/2 server that can be used to
+//! exercise the client end-to-end without any real sockets.
+//!
+//! `pipe` creates a pair of connected `MockIo` instances. One end is given to the client (e.g.
+//! via `H2Client::from_io`), while the other is driven by a `MockH2Server`, which needs to be
+//! spawned onto the same event loop.
+//!
+//! The `MockH2Server` answers each request (once the client has half-closed its stream) with the
+//! next `MockAction` in its script. It records every frame that it receives from the client in a
+//! `FrameLog`, which can be inspected after the fact.
+//!
+//! The module is only available when the `mock` feature is enabled, as well as to the crate's own
+//! tests.
+
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use tokio_core::io::Io;
+use tokio_core::reactor::Core;
+
+use hpack;
+
+use solicit::http::{ErrorCode, StreamId};
+use solicit::http::frame::RawFrame;
+
+/// The client connection preface that precedes all frames sent by the client.
+const CLIENT_PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA_FRAME_TYPE: u8 = 0x0;
+const HEADERS_FRAME_TYPE: u8 = 0x1;
+const RST_STREAM_FRAME_TYPE: u8 = 0x3;
+const SETTINGS_FRAME_TYPE: u8 = 0x4;
+const PING_FRAME_TYPE: u8 = 0x6;
+const GOAWAY_FRAME_TYPE: u8 = 0x7;
+const CONTINUATION_FRAME_TYPE: u8 = 0x9;
+
+const END_STREAM_FLAG: u8 = 0x1;
+const ACK_FLAG: u8 = 0x1;
+const END_HEADERS_FLAG: u8 = 0x4;
+
+/// The identifier of the SETTINGS_INITIAL_WINDOW_SIZE setting.
+const INITIAL_WINDOW_SIZE_SETTING: u16 = 0x4;
+
+pub use io::pack_frame;
+
+/// One direction of an in-memory pipe.
+struct PipeBuffer {
+    /// The bytes that were written, but not yet read.
+    buf: VecDeque<u8>,
+    /// Set once the writing end is dropped, after which the reader will see an EOF once it
+    /// drains the buffer.
+    closed: bool,
+    /// The task that is waiting for more data to become available.
+    reader: Option<Task>,
+    /// The number of `write` calls that were performed on the writing end.
+    writes: usize,
+}
+
+impl PipeBuffer {
+    fn new() -> PipeBuffer {
+        PipeBuffer {
+            buf: VecDeque::new(),
+            closed: false,
+            reader: None,
+            writes: 0,
+        }
+    }
+
+    /// Notifies the reader (if any) that the state of the buffer has changed.
+    fn notify_reader(&mut self) {
+        if let Some(task) = self.reader.take() {
+            task.unpark();
+        }
+    }
+}
+
+/// One end of an in-memory, full-duplex pipe. Implements `tokio_core::io::Io`, so it can be used
+/// in place of a socket.
+///
+/// Writes never block. Reads that find no data register the current task to be notified once
+/// the other end writes more data.
+pub struct MockIo {
+    read_buf: Rc<RefCell<PipeBuffer>>,
+    write_buf: Rc<RefCell<PipeBuffer>>,
+}
+
+impl MockIo {
+    /// Returns the number of `write` calls that were performed on this end of the pipe.
+    pub fn write_count(&self) -> usize {
+        self.write_buf.borrow().writes
+    }
+}
+
+/// Creates a new pair of connected `MockIo` instances; whatever is written to one can be read
+/// from the other.
+pub fn pipe() -> (MockIo, MockIo) {
+    let a = Rc::new(RefCell::new(PipeBuffer::new()));
+    let b = Rc::new(RefCell::new(PipeBuffer::new()));
+
+    let first = MockIo {
+        read_buf: a.clone(),
+        write_buf: b.clone(),
+    };
+    let second = MockIo {
+        read_buf: b,
+        write_buf: a,
+    };
+
+    (first, second)
+}
+
+/// Runs the given event loop for a little while, so that a client and the `MockH2Server` that it
+/// talks to can handle whatever the other one sent last.
+pub fn settle(core: &mut Core) {
+    for _ in 0..16 {
+        core.turn(Some(Duration::from_millis(1)));
+    }
+}
+
+impl Read for MockIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.read_buf.borrow_mut();
+        if pipe.buf.is_empty() {
+            if pipe.closed {
+                return Ok(0);
+            }
+            pipe.reader = Some(task::park());
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+
+        let count = ::std::cmp::min(buf.len(), pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..count)) {
+            *dst = src;
+        }
+
+        Ok(count)
+    }
+}
+
+impl Write for MockIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.write_buf.borrow_mut();
+        if pipe.closed {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        pipe.buf.extend(buf.iter().cloned());
+        pipe.writes += 1;
+        pipe.notify_reader();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Io for MockIo {
+    fn poll_read(&mut self) -> Async<()> {
+        let mut pipe = self.read_buf.borrow_mut();
+        if pipe.buf.is_empty() && !pipe.closed {
+            pipe.reader = Some(task::park());
+            Async::NotReady
+        } else {
+            Async::Ready(())
+        }
+    }
+}
+
+impl Drop for MockIo {
+    fn drop(&mut self) {
+        let mut pipe = self.write_buf.borrow_mut();
+        pipe.closed = true;
+        pipe.notify_reader();
+    }
+}
+
+/// A canned response that the `MockH2Server` sends for a request.
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    headers: Vec<(Vec<u8>, Vec<u8>)>,
+    body: Vec<Vec<u8>>,
+    trailers: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    header_fragment_size: Option<usize>,
+}
+
+impl MockResponse {
+    /// Creates a new `MockResponse` with the given `:status` and no body.
+    pub fn new(status: &[u8]) -> MockResponse {
+        MockResponse {
+            headers: vec![(b":status".to_vec(), status.to_vec())],
+            body: Vec::new(),
+            trailers: None,
+            header_fragment_size: None,
+        }
+    }
+
+    /// Adds a header to the response.
+    pub fn header(mut self, name: &[u8], value: &[u8]) -> MockResponse {
+        self.headers.push((name.to_vec(), value.to_vec()));
+        self
+    }
+
+    /// Adds a body chunk to the response. Each chunk is sent out in its own DATA frame.
+    pub fn body_chunk(mut self, chunk: &[u8]) -> MockResponse {
+        self.body.push(chunk.to_vec());
+        self
+    }
+
+    /// Sets the trailers that are sent in a final HEADERS frame after the body.
+    pub fn trailers(mut self, trailers: Vec<(Vec<u8>, Vec<u8>)>) -> MockResponse {
+        self.trailers = Some(trailers);
+        self
+    }
+
+    /// Splits the header blocks of the response into fragments of at most the given size: the
+    /// first one is sent in the HEADERS frame, and the rest in CONTINUATION frames.
+    pub fn header_fragment_size(mut self, size: usize) -> MockResponse {
+        self.header_fragment_size = Some(size);
+        self
+    }
+}
+
+/// An action that the `MockH2Server` takes in response to a request.
+#[derive(Clone, Debug)]
+pub enum MockAction {
+    /// Responds with the given canned response.
+    Respond(MockResponse),
+    /// Resets the request's stream with the given error code.
+    Reset(ErrorCode),
+    /// Sends the headers and body chunks of the given response without ending the stream (its
+    /// trailers, if any, are never sent), then resets the stream with the given error code.
+    RespondAndReset(MockResponse, ErrorCode),
+    /// Sends a GOAWAY with the given error code, where the last processed stream is the one
+    /// preceding the request's stream.
+    GoAway(ErrorCode),
+    /// Sends the given raw bytes verbatim. They are expected to be fully serialized frames.
+    Raw(Vec<u8>),
+}
+
+/// A frame that the `MockH2Server` received from the client.
+#[derive(Clone, Debug)]
+pub struct ReceivedFrame {
+    pub frame_type: u8,
+    pub flags: u8,
+    pub stream_id: StreamId,
+    pub payload: Vec<u8>,
+}
+
+/// A handle to the log of all frames that a `MockH2Server` received, as well as the decoded
+/// request headers.
+#[derive(Clone)]
+pub struct FrameLog {
+    frames: Rc<RefCell<Vec<ReceivedFrame>>>,
+    requests: Rc<RefCell<Vec<(StreamId, Vec<(Vec<u8>, Vec<u8>)>)>>>,
+    /// The direction of the pipe that the client writes to.
+    client_pipe: Rc<RefCell<PipeBuffer>>,
+}
+
+impl FrameLog {
+    fn new(client_pipe: Rc<RefCell<PipeBuffer>>) -> FrameLog {
+        FrameLog {
+            frames: Rc::new(RefCell::new(Vec::new())),
+            requests: Rc::new(RefCell::new(Vec::new())),
+            client_pipe: client_pipe,
+        }
+    }
+
+    /// Returns all frames received so far, in the order in which they were received.
+    pub fn frames(&self) -> Vec<ReceivedFrame> {
+        self.frames.borrow().clone()
+    }
+
+    /// Returns the decoded headers of all requests received so far, along with the ID of the
+    /// stream that they were received on.
+    pub fn requests(&self) -> Vec<(StreamId, Vec<(Vec<u8>, Vec<u8>)>)> {
+        self.requests.borrow().clone()
+    }
+
+    /// Returns the number of `write` calls that the client performed on its end of the pipe so
+    /// far (see `MockIo::write_count`).
+    pub fn client_write_count(&self) -> usize {
+        self.client_pipe.borrow().writes
+    }
+}
+
+/// A scriptable HTTP/2 server that runs in-process over a `MockIo`.
+///
+/// It's a `Future` that needs to be spawned onto the event loop that also drives the client. It
+/// resolves once the client closes its end of the pipe.
+pub struct MockH2Server {
+    io: MockIo,
+    in_buf: Vec<u8>,
+    preface_received: bool,
+    settings_sent: bool,
+    initial_window_size: Option<u32>,
+    script: VecDeque<MockAction>,
+    encoder: hpack::Encoder<'static>,
+    decoder: hpack::Decoder<'static>,
+    log: FrameLog,
+}
+
+impl MockH2Server {
+    /// Creates a new `MockH2Server` that serves the client on the other end of the given `io`.
+    pub fn new(io: MockIo) -> MockH2Server {
+        let log = FrameLog::new(io.read_buf.clone());
+        MockH2Server {
+            io: io,
+            in_buf: Vec::new(),
+            preface_received: false,
+            settings_sent: false,
+            initial_window_size: None,
+            script: VecDeque::new(),
+            encoder: hpack::Encoder::new(),
+            decoder: hpack::Decoder::new(),
+            log: log,
+        }
+    }
+
+    /// Makes the server advertise the given SETTINGS_INITIAL_WINDOW_SIZE.
+    pub fn with_initial_window_size(mut self, size: u32) -> MockH2Server {
+        self.initial_window_size = Some(size);
+        self
+    }
+
+    /// Appends an action to the script. Actions are taken in order, one per request.
+    pub fn then(mut self, action: MockAction) -> MockH2Server {
+        self.script.push_back(action);
+        self
+    }
+
+    /// Returns a handle to the log of frames received by the server.
+    pub fn frame_log(&self) -> FrameLog {
+        self.log.clone()
+    }
+
+    /// Writes out the given bytes. As the `MockIo` never blocks on writes, this always writes
+    /// everything.
+    fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.io.write_all(buf)
+    }
+
+    fn send_settings(&mut self) -> io::Result<()> {
+        let mut payload = Vec::new();
+        if let Some(size) = self.initial_window_size {
+            payload.extend_from_slice(&[
+                (INITIAL_WINDOW_SIZE_SETTING >> 8) as u8,
+                INITIAL_WINDOW_SIZE_SETTING as u8,
+                (size >> 24) as u8,
+                (size >> 16) as u8,
+                (size >> 8) as u8,
+                size as u8,
+            ]);
+        }
+        let frame = pack_frame(SETTINGS_FRAME_TYPE, 0, 0, &payload);
+        self.send(&frame)
+    }
+
+    fn send_headers(&mut self,
+                    stream_id: StreamId,
+                    headers: &[(Vec<u8>, Vec<u8>)],
+                    end_stream: bool,
+                    fragment_size: Option<usize>)
+                    -> io::Result<()> {
+        let block = self.encoder.encode(
+            headers.iter().map(|&(ref n, ref v)| (&n[..], &v[..])));
+        let fragment_size = fragment_size.unwrap_or(block.len());
+        let mut fragments = block.chunks(::std::cmp::max(fragment_size, 1)).peekable();
+        let mut frame_type = HEADERS_FRAME_TYPE;
+        let mut flags = if end_stream { END_STREAM_FLAG } else { 0 };
+        loop {
+            let fragment = fragments.next().unwrap_or(&[]);
+            let last = fragments.peek().is_none();
+            if last {
+                flags |= END_HEADERS_FLAG;
+            }
+            let frame = pack_frame(frame_type, flags, stream_id, fragment);
+            self.send(&frame)?;
+            if last {
+                return Ok(());
+            }
+            frame_type = CONTINUATION_FRAME_TYPE;
+            flags = 0;
+        }
+    }
+
+    /// Sends the given response, ending the stream with it if `end_stream` is set.
+    fn respond(&mut self, stream_id: StreamId, response: MockResponse, end_stream: bool)
+            -> io::Result<()> {
+        let no_body = response.body.is_empty() && response.trailers.is_none();
+        let fragment_size = response.header_fragment_size;
+        self.send_headers(stream_id, &response.headers, end_stream && no_body, fragment_size)?;
+
+        let chunk_count = response.body.len();
+        for (i, chunk) in response.body.iter().enumerate() {
+            let last = end_stream && i + 1 == chunk_count && response.trailers.is_none();
+            let flags = if last { END_STREAM_FLAG } else { 0 };
+            let frame = pack_frame(DATA_FRAME_TYPE, flags, stream_id, chunk);
+            self.send(&frame)?;
+        }
+
+        match response.trailers {
+            Some(trailers) if end_stream => {
+                self.send_headers(stream_id, &trailers, true, fragment_size)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&mut self, stream_id: StreamId, code: ErrorCode) -> io::Result<()> {
+        let code = code as u32;
+        let payload = [(code >> 24) as u8, (code >> 16) as u8, (code >> 8) as u8, code as u8];
+        let frame = pack_frame(RST_STREAM_FRAME_TYPE, 0, stream_id, &payload);
+        self.send(&frame)
+    }
+
+    /// Takes the next scripted action for the request on the given stream.
+    fn handle_request(&mut self, stream_id: StreamId) -> io::Result<()> {
+        match self.script.pop_front() {
+            Some(MockAction::Respond(response)) => self.respond(stream_id, response, true),
+            Some(MockAction::Reset(code)) => self.reset(stream_id, code),
+            Some(MockAction::RespondAndReset(response, code)) => {
+                self.respond(stream_id, response, false)?;
+                self.reset(stream_id, code)
+            },
+            Some(MockAction::GoAway(code)) => {
+                let last = stream_id.saturating_sub(2);
+                let code = code as u32;
+                let payload = [
+                    (last >> 24) as u8, (last >> 16) as u8, (last >> 8) as u8, last as u8,
+                    (code >> 24) as u8, (code >> 16) as u8, (code >> 8) as u8, code as u8,
+                ];
+                let frame = pack_frame(GOAWAY_FRAME_TYPE, 0, 0, &payload);
+                self.send(&frame)
+            },
+            Some(MockAction::Raw(bytes)) => self.send(&bytes),
+            None => {
+                debug!("mock server: no scripted action for stream {}", stream_id);
+                Ok(())
+            },
+        }
+    }
+
+    /// Handles a single frame received from the client.
+    fn handle_frame(&mut self, frame: ReceivedFrame) -> io::Result<()> {
+        trace!("mock server: received frame type={} stream={}", frame.frame_type, frame.stream_id);
+        self.log.frames.borrow_mut().push(frame.clone());
+
+        let end_stream = frame.flags & END_STREAM_FLAG != 0;
+        match frame.frame_type {
+            HEADERS_FRAME_TYPE => {
+                if let Ok(headers) = self.decoder.decode(&frame.payload) {
+                    self.log.requests.borrow_mut().push((frame.stream_id, headers));
+                }
+                if end_stream {
+                    self.handle_request(frame.stream_id)?;
+                }
+            },
+            DATA_FRAME_TYPE => {
+                if end_stream {
+                    self.handle_request(frame.stream_id)?;
+                }
+            },
+            SETTINGS_FRAME_TYPE => {
+                if frame.flags & ACK_FLAG == 0 {
+                    self.send(&pack_frame(SETTINGS_FRAME_TYPE, ACK_FLAG, 0, &[]))?;
+                }
+            },
+            PING_FRAME_TYPE => {
+                if frame.flags & ACK_FLAG == 0 {
+                    self.send(&pack_frame(PING_FRAME_TYPE, ACK_FLAG, 0, &frame.payload))?;
+                }
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    /// Handles all complete frames currently found in the in buffer.
+    fn handle_frames(&mut self) -> io::Result<()> {
+        if !self.preface_received {
+            if self.in_buf.len() < CLIENT_PREFACE.len() {
+                return Ok(());
+            }
+            if &self.in_buf[..CLIENT_PREFACE.len()] != CLIENT_PREFACE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid client preface"));
+            }
+            self.in_buf.drain(..CLIENT_PREFACE.len());
+            self.preface_received = true;
+        }
+
+        loop {
+            let (frame, len) = match RawFrame::parse(&self.in_buf) {
+                None => break,
+                Some(raw) => {
+                    let (_, frame_type, flags, stream_id) = raw.header();
+                    let frame = ReceivedFrame {
+                        frame_type: frame_type,
+                        flags: flags,
+                        stream_id: stream_id,
+                        payload: raw.payload().to_vec(),
+                    };
+                    (frame, raw.len())
+                },
+            };
+
+            self.in_buf.drain(..len);
+            self.handle_frame(frame)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Future for MockH2Server {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        if !self.settings_sent {
+            self.send_settings()?;
+            self.settings_sent = true;
+        }
+
+        let mut buf = [0; 4096];
+        loop {
+            match self.io.read(&mut buf) {
+                Ok(0) => {
+                    debug!("mock server: client closed the connection");
+                    return Ok(Async::Ready(()));
+                },
+                Ok(count) => {
+                    self.in_buf.extend_from_slice(&buf[..count]);
+                    self.handle_frames()?;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}